@@ -0,0 +1,548 @@
+//! Backends performing hypervisor operations for axcli.
+//!
+//! * `IoctlBackend`: the real backend, through ioctls on the ArceOS driver.
+//! * `MockBackend`: simulates hypervisor in memory, so that axcli could run without the driver.
+//!
+//! The backend is selected by `AXCLI_BACKEND` (`ioctl` by default, or `mock`).
+//! Device node of `IoctlBackend` is resolved by `resolve_device`.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use rustix::fd::OwnedFd;
+use rustix::fs::{open, Mode, OFlags};
+use rustix::io::Errno;
+use rustix::ioctl;
+use serde::{Deserialize, Serialize};
+
+use axerrno::{ax_err, ax_err_type, AxError, AxResult};
+
+use crate::cfg::{AxcliHostCfg, VmCreateCliArg, VmMemCfg};
+use crate::ioctl_arg::{
+    HvDisableIoctlArg, HvEnableIoctlArg, VmBootIoctlArg, VmCreateIoctlArg, VmDestroyIoctlArg,
+    VmInfoEntry, VmListIoctlArg, VmLoadSegmentIoctlArg, VmShutdownIoctlArg, VM_NAME_MAX_LEN,
+};
+
+/// Max number of VMs queried from hypervisor at once.
+const VM_LIST_CAPACITY: usize = 64;
+
+/// A part of kernel image loaded at `gpa`, zeroed after `data` up to `mem_size` Bytes.
+#[derive(Debug)]
+pub struct LoadSegment<'a> {
+    pub gpa: usize,
+    pub data: &'a [u8],
+    pub mem_size: usize,
+}
+
+/// Images and config of a VM to be created.
+#[derive(Debug)]
+pub struct VmCreateParams<'a> {
+    /// Expected VM id, it could be modified by hypervisor.
+    pub id: usize,
+    pub cpu_set: usize,
+    pub bios_img: &'a [u8],
+    /// Sorted by address, the first one is at `kernel_load_addr` of `raw_cfg`.
+    pub kernel_segments: &'a [LoadSegment<'a>],
+    pub ramdisk_img: Option<&'a [u8]>,
+    /// Raw content of VM config file, parsed by hypervisor itself.
+    pub raw_cfg: &'a str,
+}
+
+/// Operations on hypervisor required by axcli.
+pub trait HypervisorBackend {
+    /// Enable hypervisor with its image and raw config file.
+    fn enable(&mut self, hv_img: &[u8], raw_cfg: &str) -> AxResult;
+
+    fn disable(&mut self) -> AxResult;
+
+    /// Create a VM with all kernel segments loaded, return the VM id assigned by hypervisor.
+    fn create_vm(&mut self, params: VmCreateParams) -> AxResult<usize>;
+
+    fn boot_vm(&mut self, vmid: usize) -> AxResult;
+
+    fn shutdown_vm(&mut self, vmid: usize) -> AxResult;
+
+    fn destroy_vm(&mut self, vmid: usize) -> AxResult;
+
+    /// Query info of all VMs.
+    fn list_vm(&mut self) -> AxResult<Vec<VmInfoEntry>>;
+
+    /// Memory regions of VM, `None` if the backend is not able to report them.
+    fn vm_memory_regions(&mut self, _vmid: usize) -> Option<Vec<VmMemCfg>> {
+        None
+    }
+}
+
+/// Host-level axcli config file, see `AxcliHostCfg`.
+const AXCLI_HOST_CONFIG_PATH: &str = "/etc/axcli.toml";
+
+/// Device nodes of known hypervisor drivers, detected in order if no device is configured.
+const KNOWN_DEVICES: &[&str] = &["/dev/jailhouse", "/dev/arceos_vdev"];
+
+/// Open the backend selected by `AXCLI_BACKEND`.
+///
+/// `device` from command line overrides other device configs of `IoctlBackend`.
+pub fn open_backend(device: Option<PathBuf>) -> AxResult<Box<dyn HypervisorBackend>> {
+    match std::env::var("AXCLI_BACKEND").as_deref() {
+        Ok("ioctl") | Err(_) => Ok(Box::new(IoctlBackend::new(resolve_device(device)?))),
+        Ok("mock") => Ok(Box::new(MockBackend::open()?)),
+        Ok(other) => ax_err!(
+            InvalidInput,
+            format!("unknown AXCLI_BACKEND {other:?}, expect `ioctl` or `mock`")
+        ),
+    }
+}
+
+/// Find device node of the hypervisor driver, in order of:
+/// 1. `device` from command line.
+/// 2. `AXCLI_DEVICE` environment variable.
+/// 3. `device` in `AXCLI_HOST_CONFIG_PATH`.
+/// 4. The first existing one in `KNOWN_DEVICES`.
+fn resolve_device(device: Option<PathBuf>) -> AxResult<PathBuf> {
+    let configured = match device {
+        Some(device) => Some((device, "--device")),
+        None => match std::env::var_os("AXCLI_DEVICE") {
+            Some(device) => Some((PathBuf::from(device), "AXCLI_DEVICE")),
+            None => load_host_config()?
+                .device
+                .map(|device| (device, AXCLI_HOST_CONFIG_PATH)),
+        },
+    };
+    if let Some((device, source)) = configured {
+        if !device.exists() {
+            return ax_err!(
+                NotFound,
+                format!("device {device:?} from {source} does not exist")
+            );
+        }
+        debug!("use device {device:?} from {source}");
+        return Ok(device);
+    }
+
+    KNOWN_DEVICES
+        .iter()
+        .map(PathBuf::from)
+        .find(|device| device.exists())
+        .inspect(|device| debug!("detected device {device:?}"))
+        .ok_or_else(|| {
+            ax_err_type!(
+                NotFound,
+                format!(
+                    "no hypervisor device found, tried --device, AXCLI_DEVICE, {} and {}, \
+                     please make sure the driver is loaded",
+                    AXCLI_HOST_CONFIG_PATH,
+                    KNOWN_DEVICES.join(", ")
+                )
+            )
+        })
+}
+
+fn load_host_config() -> AxResult<AxcliHostCfg> {
+    let content = match std::fs::read_to_string(AXCLI_HOST_CONFIG_PATH) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(AxcliHostCfg::default())
+        }
+        Err(err) => {
+            return ax_err!(
+                Io,
+                format!("failed to read {AXCLI_HOST_CONFIG_PATH}, {err:?}")
+            )
+        }
+    };
+    toml::from_str(&content).map_err(|err| {
+        ax_err_type!(
+            InvalidInput,
+            format!("failed to parse {AXCLI_HOST_CONFIG_PATH}, {err}")
+        )
+    })
+}
+
+/// Backend performing ioctls on the ArceOS driver.
+#[derive(Debug)]
+pub struct IoctlBackend {
+    device: PathBuf,
+}
+
+impl IoctlBackend {
+    pub fn new(device: PathBuf) -> Self {
+        Self { device }
+    }
+
+    fn open_driver(&self) -> AxResult<OwnedFd> {
+        open(&self.device, OFlags::RDWR, Mode::RWXO).map_err(|err| {
+            warn!("Failed to open ArceOS driver {:?}, {err}", self.device);
+            errno_to_ax_err(err)
+        })
+    }
+
+    fn perform_ioctl<I: ioctl::Ioctl>(&self, ioctl: I) -> AxResult<I::Output> {
+        let fd = self.open_driver()?;
+        unsafe { ioctl::ioctl(fd, ioctl) }.map_err(|err| {
+            warn!("Failed to perform ioctl {:#x}, {err}", I::OPCODE.raw());
+            errno_to_ax_err(err)
+        })
+    }
+}
+
+impl HypervisorBackend for IoctlBackend {
+    fn enable(&mut self, hv_img: &[u8], raw_cfg: &str) -> AxResult {
+        self.perform_ioctl(HvEnableIoctlArg {
+            hv_img_ptr: hv_img.as_ptr() as usize,
+            hv_img_size: hv_img.len(),
+            raw_cfg_file_ptr: raw_cfg.as_ptr() as usize,
+            raw_cfg_file_size: raw_cfg.len(),
+        })
+    }
+
+    fn disable(&mut self) -> AxResult {
+        self.perform_ioctl(HvDisableIoctlArg)
+    }
+
+    fn create_vm(&mut self, params: VmCreateParams) -> AxResult<usize> {
+        let (first, rest) = params
+            .kernel_segments
+            .split_first()
+            .ok_or_else(|| ax_err_type!(InvalidInput, "kernel has no segment"))?;
+        // VM id could be modified by hypervisor.
+        let mut vmid = params.id;
+        let (ramdisk_img_ptr, ramdisk_img_size) = params
+            .ramdisk_img
+            .map_or((0, 0), |img| (img.as_ptr() as usize, img.len()));
+
+        self.perform_ioctl(VmCreateIoctlArg {
+            id_ptr: &mut vmid as *mut _ as usize,
+            cpu_set: params.cpu_set,
+            bios_img_ptr: params.bios_img.as_ptr() as usize,
+            bios_img_size: params.bios_img.len(),
+            kernel_img_ptr: first.data.as_ptr() as usize,
+            kernel_img_size: first.data.len(),
+            ramdisk_img_ptr,
+            ramdisk_img_size,
+            raw_cfg_file_ptr: params.raw_cfg.as_ptr() as usize,
+            raw_cfg_file_size: params.raw_cfg.len(),
+        })?;
+
+        // Only the content of the first segment is taken by VM creation.
+        let bss = LoadSegment {
+            gpa: first.gpa + first.data.len(),
+            data: &[],
+            mem_size: first.mem_size - first.data.len(),
+        };
+        for segment in core::iter::once(&bss).chain(rest) {
+            if segment.mem_size == 0 {
+                continue;
+            }
+            if let Err(err) = self.perform_ioctl(VmLoadSegmentIoctlArg {
+                id: vmid,
+                gpa: segment.gpa,
+                img_ptr: segment.data.as_ptr() as usize,
+                img_size: segment.data.len(),
+                mem_size: segment.mem_size,
+            }) {
+                warn!(
+                    "Failed to load kernel segment at {:#x} into VM [{vmid}], \
+                    the driver may not support kernels of several segments",
+                    segment.gpa
+                );
+                if let Err(destroy_err) = self.perform_ioctl(VmDestroyIoctlArg { id: vmid }) {
+                    warn!("Failed to destroy VM [{vmid}], {destroy_err:?}");
+                }
+                return Err(err);
+            }
+        }
+        Ok(vmid)
+    }
+
+    fn boot_vm(&mut self, vmid: usize) -> AxResult {
+        self.perform_ioctl(VmBootIoctlArg { id: vmid })
+    }
+
+    fn shutdown_vm(&mut self, vmid: usize) -> AxResult {
+        self.perform_ioctl(VmShutdownIoctlArg { id: vmid })
+    }
+
+    fn destroy_vm(&mut self, vmid: usize) -> AxResult {
+        self.perform_ioctl(VmDestroyIoctlArg { id: vmid })
+    }
+
+    fn list_vm(&mut self) -> AxResult<Vec<VmInfoEntry>> {
+        let mut vm_infos = vec![VmInfoEntry::default(); VM_LIST_CAPACITY];
+        // VM number would be written by hypervisor.
+        let mut vm_num: usize = 0;
+
+        self.perform_ioctl(VmListIoctlArg {
+            vm_info_ptr: vm_infos.as_mut_ptr() as usize,
+            vm_info_capacity: VM_LIST_CAPACITY,
+            vm_num_ptr: &mut vm_num as *mut _ as usize,
+        })?;
+
+        vm_infos.truncate(vm_num.min(VM_LIST_CAPACITY));
+        Ok(vm_infos)
+    }
+}
+
+/// Convert errno returned by ArceOS driver into `AxError`.
+fn errno_to_ax_err(err: Errno) -> AxError {
+    match err {
+        Errno::NOENT | Errno::NODEV => AxError::NotFound,
+        Errno::PERM | Errno::ACCESS => AxError::PermissionDenied,
+        Errno::BUSY => AxError::ResourceBusy,
+        Errno::EXIST => AxError::AlreadyExists,
+        Errno::INVAL => AxError::InvalidInput,
+        Errno::NOMEM => AxError::NoMemory,
+        Errno::FAULT => AxError::BadAddress,
+        // The device is not an ArceOS driver.
+        Errno::NOTTY => AxError::Unsupported,
+        _ => AxError::BadState,
+    }
+}
+
+/// A VM simulated by `MockBackend`, `state` uses the same encoding as `VmInfoEntry`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MockVm {
+    pub name: String,
+    pub vm_type: usize,
+    pub cpu_set: usize,
+    pub state: usize,
+    pub kernel_img_size: usize,
+    #[serde(default)]
+    pub memory_regions: Vec<VmMemCfg>,
+}
+
+/// State of `MockBackend`, persisted in `AXCLI_MOCK_STATE` across axcli invocations if set.
+///
+/// A fresh mock starts with hypervisor enabled and no VM.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MockState {
+    pub enabled: bool,
+    pub vms: BTreeMap<usize, MockVm>,
+    /// Every operation performed, e.g. `boot_vm(1)`, including failed ones.
+    pub calls: Vec<String>,
+}
+
+impl Default for MockState {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            vms: BTreeMap::new(),
+            calls: Vec::new(),
+        }
+    }
+}
+
+/// Backend simulating hypervisor, it behaves like the driver on state errors.
+#[derive(Debug, Default)]
+pub struct MockBackend {
+    pub state: MockState,
+    state_path: Option<PathBuf>,
+}
+
+impl MockBackend {
+    const VM_CREATED: usize = 0;
+    const VM_BOOTED: usize = 1;
+    const VM_SHUT_DOWN: usize = 2;
+
+    /// Open mock backend, loading state from `AXCLI_MOCK_STATE` if it exists.
+    pub fn open() -> AxResult<Self> {
+        let Some(state_path) = std::env::var_os("AXCLI_MOCK_STATE").map(PathBuf::from) else {
+            return Ok(Self::default());
+        };
+        let state = match std::fs::read_to_string(&state_path) {
+            Ok(content) => serde_json::from_str(&content).map_err(|err| {
+                ax_err_type!(
+                    InvalidData,
+                    format!("failed to parse mock state {state_path:?}, {err:?}")
+                )
+            })?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => MockState::default(),
+            Err(err) => {
+                return ax_err!(
+                    Io,
+                    format!("failed to read mock state {state_path:?}, {err:?}")
+                )
+            }
+        };
+        Ok(Self {
+            state,
+            state_path: Some(state_path),
+        })
+    }
+
+    fn record(&mut self, call: String) {
+        info!("[mock] {call}");
+        self.state.calls.push(call);
+    }
+
+    fn check_enabled(&self) -> AxResult {
+        if !self.state.enabled {
+            return ax_err!(BadState, "[mock] hypervisor is not enabled");
+        }
+        Ok(())
+    }
+
+    /// Move VM from `from` state to `to` state.
+    fn transit(&mut self, vmid: usize, from: usize, to: usize) -> AxResult {
+        self.check_enabled()?;
+        let vm = self
+            .state
+            .vms
+            .get_mut(&vmid)
+            .ok_or_else(|| ax_err_type!(NotFound, format!("[mock] VM [{vmid}] not found")))?;
+        if vm.state != from {
+            return ax_err!(
+                BadState,
+                format!("[mock] VM [{vmid}] is in state {}, expect {from}", vm.state)
+            );
+        }
+        vm.state = to;
+        Ok(())
+    }
+}
+
+impl Drop for MockBackend {
+    fn drop(&mut self) {
+        let Some(state_path) = &self.state_path else {
+            return;
+        };
+        match serde_json::to_string_pretty(&self.state) {
+            Ok(content) => {
+                if let Err(err) = std::fs::write(state_path, content) {
+                    warn!("Failed to save mock state {state_path:?}, {err:?}");
+                }
+            }
+            Err(err) => warn!("Failed to serialize mock state {err:?}"),
+        }
+    }
+}
+
+impl HypervisorBackend for MockBackend {
+    fn enable(&mut self, hv_img: &[u8], _raw_cfg: &str) -> AxResult {
+        self.record(format!("enable({} Bytes)", hv_img.len()));
+        if self.state.enabled {
+            return ax_err!(ResourceBusy, "[mock] hypervisor is already enabled");
+        }
+        self.state.enabled = true;
+        Ok(())
+    }
+
+    fn disable(&mut self) -> AxResult {
+        self.record(String::from("disable()"));
+        self.check_enabled()?;
+        self.state.enabled = false;
+        self.state.vms.clear();
+        Ok(())
+    }
+
+    fn create_vm(&mut self, params: VmCreateParams) -> AxResult<usize> {
+        self.record(format!(
+            "create_vm(id {}, cpu_set {:#x}, bios {} Bytes, kernel {}, ramdisk {:?} Bytes)",
+            params.id,
+            params.cpu_set,
+            params.bios_img.len(),
+            params
+                .kernel_segments
+                .iter()
+                .map(|seg| format!("{:#x}+{:#x}/{:#x}", seg.gpa, seg.data.len(), seg.mem_size))
+                .collect::<Vec<_>>()
+                .join(" "),
+            params.ramdisk_img.map(|img| img.len())
+        ));
+        self.check_enabled()?;
+
+        let cfg: VmCreateCliArg = toml::from_str(params.raw_cfg)
+            .map_err(|err| ax_err_type!(InvalidInput, format!("[mock] bad VM config {err}")))?;
+        let kernel_img_size = params
+            .kernel_segments
+            .iter()
+            .map(|seg| seg.data.len())
+            .sum();
+        if kernel_img_size == 0 {
+            return ax_err!(InvalidInput, "[mock] kernel image is empty");
+        }
+
+        // Like hypervisor, allocate another id if the expected one is taken.
+        let mut vmid = params.id;
+        while self.state.vms.contains_key(&vmid) {
+            vmid += 1;
+        }
+        let mut name = cfg.name;
+        while name.len() >= VM_NAME_MAX_LEN {
+            name.pop();
+        }
+        self.state.vms.insert(
+            vmid,
+            MockVm {
+                name,
+                vm_type: cfg.vm_type,
+                cpu_set: params.cpu_set,
+                state: Self::VM_CREATED,
+                kernel_img_size,
+                memory_regions: cfg.memory_regions,
+            },
+        );
+        Ok(vmid)
+    }
+
+    fn boot_vm(&mut self, vmid: usize) -> AxResult {
+        self.record(format!("boot_vm({vmid})"));
+        let from = match self.state.vms.get(&vmid) {
+            Some(vm) if vm.state == Self::VM_SHUT_DOWN => Self::VM_SHUT_DOWN,
+            _ => Self::VM_CREATED,
+        };
+        self.transit(vmid, from, Self::VM_BOOTED)
+    }
+
+    /// VM created but never booted could be shut down as well.
+    fn shutdown_vm(&mut self, vmid: usize) -> AxResult {
+        self.record(format!("shutdown_vm({vmid})"));
+        let from = match self.state.vms.get(&vmid) {
+            Some(vm) if vm.state == Self::VM_CREATED => Self::VM_CREATED,
+            _ => Self::VM_BOOTED,
+        };
+        self.transit(vmid, from, Self::VM_SHUT_DOWN)
+    }
+
+    fn destroy_vm(&mut self, vmid: usize) -> AxResult {
+        self.record(format!("destroy_vm({vmid})"));
+        self.check_enabled()?;
+        match self.state.vms.get(&vmid) {
+            None => ax_err!(NotFound, format!("[mock] VM [{vmid}] not found")),
+            Some(vm) if vm.state == Self::VM_BOOTED => {
+                ax_err!(ResourceBusy, format!("[mock] VM [{vmid}] is running"))
+            }
+            Some(_) => {
+                self.state.vms.remove(&vmid);
+                Ok(())
+            }
+        }
+    }
+
+    fn list_vm(&mut self) -> AxResult<Vec<VmInfoEntry>> {
+        self.record(String::from("list_vm()"));
+        self.check_enabled()?;
+        Ok(self
+            .state
+            .vms
+            .iter()
+            .map(|(&id, vm)| {
+                let mut info = VmInfoEntry {
+                    id,
+                    vm_type: vm.vm_type,
+                    cpu_set: vm.cpu_set,
+                    state: vm.state,
+                    ..Default::default()
+                };
+                info.name[..vm.name.len()].copy_from_slice(vm.name.as_bytes());
+                info
+            })
+            .collect())
+    }
+
+    fn vm_memory_regions(&mut self, vmid: usize) -> Option<Vec<VmMemCfg>> {
+        self.state
+            .vms
+            .get(&vmid)
+            .map(|vm| vm.memory_regions.clone())
+    }
+}
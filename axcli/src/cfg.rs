@@ -1,5 +1,31 @@
+use std::path::{Path, PathBuf};
+
 use serde::{Deserialize, Serialize};
 
+use axdaemon_request::{DiskCacheMode, DiskCfg, DiskFormat, DISK_SERIAL_MAX_LEN};
+
+use crate::digest;
+use crate::image::{KernelFormat, KernelImage};
+use crate::linux::ZERO_PAGE_SIZE;
+use crate::mapped::{ImageData, MappedFile};
+
+/// `vm_type` of Linux guest, see `VMType` of hypervisor.
+pub const VM_TYPE_LINUX: usize = 2;
+
+/// Host-level axcli config, loaded from `AXCLI_HOST_CONFIG_PATH` if it exists.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AxcliHostCfg {
+    /// Device node of the hypervisor driver.
+    pub device: Option<PathBuf>,
+}
+
+/// Hypervisor config, other fields in the TOML file are parsed by hypervisor itself.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HvEnableCliArg {
+    /// Path of hypervisor image file.
+    pub image_path: String,
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct VmCreateCliArg {
     // Basic Information
@@ -11,6 +37,8 @@ pub struct VmCreateCliArg {
 
     pub entry_point: usize,
 
+    /// Not used by bzImage kernel, whose zero page is loaded at `bios_load_addr` instead.
+    #[serde(default)]
     pub bios_path: String,
     pub bios_load_addr: usize,
 
@@ -20,15 +48,920 @@ pub struct VmCreateCliArg {
     pub ramdisk_path: Option<String>,
     pub ramdisk_load_addr: Option<usize>,
 
+    /// Kernel command line, only for bzImage kernel.
+    pub cmdline: Option<String>,
+
+    /// Shorthand of a writable disk with default options, placed before `disks`.
     pub disk_path: Option<String>,
+    /// Disks of VM, indexed by their positions after `disk_path`.
+    #[serde(default)]
+    pub disks: Vec<VmDiskCfg>,
+
+    /// SHA-256 digests of image files as stored, verified before VM is created.
+    ///
+    /// Filled in by `axcli vm hash`. Disk of `disk_path` is writable, so `disk_sha256` is
+    /// rejected, see `VmDiskCfg::sha256`.
+    pub bios_sha256: Option<String>,
+    pub kernel_sha256: Option<String>,
+    pub ramdisk_sha256: Option<String>,
+    pub disk_sha256: Option<String>,
 
     /// Memory Information
-    memory_regions: Vec<VmMemCfg>,
+    #[serde(default)]
+    pub memory_regions: Vec<VmMemCfg>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
-struct VmMemCfg {
+/// Disk of VM, emulated by axdaemon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VmDiskCfg {
+    /// Not set for RAM disks.
+    #[serde(default)]
+    pub path: String,
+    /// One of "auto", "raw", "qcow2", "overlay" or "ram", "auto" detects qcow2 images and
+    /// overlays by their magic.
+    #[serde(default)]
+    pub format: DiskFormat,
+    /// Size of RAM disk in Bytes, a multiple of 512.
+    pub size: Option<u64>,
+    /// Guest writes fail with I/O error.
+    #[serde(default, alias = "readonly")]
+    pub read_only: bool,
+    /// Guest writes are discarded when VM shuts down, so that several VMs can share one
+    /// disk image.
+    #[serde(default)]
+    pub snapshot: bool,
+    /// Open disk image with `O_DIRECT`, enabled by default.
+    #[serde(default = "default_disk_direct")]
+    pub direct: bool,
+    /// Serial reported to guest, at most 20 Bytes.
+    pub serial: Option<String>,
+    /// One of "writeback", "writethrough" or "unsafe".
+    #[serde(default)]
+    pub cache: DiskCacheMode,
+    /// Free space of raw disk image when guest discards sectors.
+    #[serde(default)]
+    pub discard: bool,
+    /// SHA-256 digest of disk image, only for read-only or snapshot disks, as guest writes
+    /// change the others.
+    pub sha256: Option<String>,
+}
+
+fn default_disk_direct() -> bool {
+    true
+}
+
+fn validate_ram_disk(key: &str, disk: &VmDiskCfg, problems: &mut Vec<CfgProblem>) {
+    if !disk.path.is_empty() {
+        problems.push(CfgProblem::new(
+            key,
+            "RAM disk has no disk image, remove `path`",
+        ));
+    }
+    if disk.sha256.is_some() {
+        problems.push(CfgProblem::new(
+            key.replace("path", "sha256"),
+            "RAM disk has no disk image to verify",
+        ));
+    }
+    match disk.size {
+        None => problems.push(CfgProblem::new(
+            key.replace("path", "size"),
+            "size of RAM disk is not set",
+        )),
+        Some(size) if size == 0 || !size.is_multiple_of(512) => problems.push(CfgProblem::new(
+            key.replace("path", "size"),
+            format!("{size:#x} is not a positive multiple of 512"),
+        )),
+        Some(_) => {}
+    }
+}
+
+/// Format of disk image detected by magic, same as axdaemon, raw if it is unknown.
+fn image_format(path: &str) -> DiskFormat {
+    let mut magic = [0; 8];
+    match std::fs::File::open(path)
+        .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut magic))
+    {
+        Ok(()) => DiskFormat::detect(&magic),
+        Err(_) => DiskFormat::Raw,
+    }
+}
+
+impl VmDiskCfg {
+    /// Whether guest writes reach the disk image.
+    fn is_writable(&self) -> bool {
+        !self.read_only && !self.snapshot
+    }
+
+    fn new(path: String) -> Self {
+        Self {
+            path,
+            format: DiskFormat::default(),
+            size: None,
+            read_only: false,
+            snapshot: false,
+            direct: default_disk_direct(),
+            serial: None,
+            cache: DiskCacheMode::default(),
+            discard: false,
+            sha256: None,
+        }
+    }
+}
+
+/// Memory region of VM, written as a table or an array of `[gpa, size, flags, name]`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct VmMemCfg {
     pub gpa: usize,
     pub size: usize,
-    pub flags: usize,
+    pub flags: MemRegionFlags,
+    /// Optional name for humans, e.g. "low-ram".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl core::fmt::Display for VmMemCfg {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{name:?} ")?;
+        }
+        write!(f, "[{:#x}, {:#x}) {}", self.gpa, self.end(), self.flags)
+    }
+}
+
+/// Mapping flags of memory region, same bits as `MappingFlags` of hypervisor.
+///
+/// Written as a list of names, e.g. `["read", "write", "device"]`, or as a raw number.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemRegionFlags(pub usize);
+
+impl MemRegionFlags {
+    /// Names of known flag bits.
+    const NAMES: &'static [(&'static str, usize)] = &[
+        ("read", 1 << 0),
+        ("write", 1 << 1),
+        ("exec", 1 << 2),
+        ("user", 1 << 3),
+        ("device", 1 << 4),
+        ("uncached", 1 << 5),
+    ];
+
+    const DEVICE: usize = 1 << 4;
+
+    pub fn is_device(&self) -> bool {
+        self.0 & Self::DEVICE != 0
+    }
+
+    fn from_name(name: &str) -> Option<usize> {
+        Self::NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, bit)| *bit)
+    }
+
+    /// Names of set bits, unknown bits are rendered as a hex number.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Self::NAMES
+            .iter()
+            .filter(|(_, bit)| self.0 & bit != 0)
+            .map(|(name, _)| name.to_string())
+            .collect();
+        let known = Self::NAMES.iter().fold(0, |acc, (_, bit)| acc | bit);
+        if self.0 & !known != 0 {
+            names.push(format!("{:#x}", self.0 & !known));
+        }
+        names
+    }
+}
+
+impl core::fmt::Display for MemRegionFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let names = self.names();
+        if names.is_empty() {
+            f.pad("none")
+        } else {
+            f.pad(&names.join("|"))
+        }
+    }
+}
+
+impl Serialize for MemRegionFlags {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.names().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MemRegionFlags {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FlagsVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for FlagsVisitor {
+            type Value = MemRegionFlags;
+
+            fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                write!(
+                    f,
+                    "a number or a list of flag names like [\"read\", \"write\"]"
+                )
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                Ok(MemRegionFlags(v as usize))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                usize::try_from(v)
+                    .map(MemRegionFlags)
+                    .map_err(|_| E::custom(format!("negative flags {v}")))
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Self::Value, A::Error> {
+                let mut flags = 0;
+                while let Some(name) = seq.next_element::<String>()? {
+                    flags |= MemRegionFlags::from_name(&name).ok_or_else(|| {
+                        let known: Vec<_> = MemRegionFlags::NAMES.iter().map(|(n, _)| *n).collect();
+                        serde::de::Error::custom(format!(
+                            "unknown flag {name:?}, expect one of {}",
+                            known.join(", ")
+                        ))
+                    })?;
+                }
+                Ok(MemRegionFlags(flags))
+            }
+        }
+
+        deserializer.deserialize_any(FlagsVisitor)
+    }
+}
+
+/// A problem found in VM config file, reported with the TOML key it comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CfgProblem {
+    pub key: String,
+    pub message: String,
+}
+
+impl CfgProblem {
+    fn new(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl core::fmt::Display for CfgProblem {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "`{}`: {}", self.key, self.message)
+    }
+}
+
+/// Images loaded by `VmCreateCliArg::validate`, so that each one is read and
+/// decompressed once.
+#[derive(Debug)]
+pub struct VmImages {
+    pub kernel: KernelImage,
+    /// `None` for bzImage kernel, whose zero page takes the place of bios.
+    pub bios: Option<ImageData>,
+    pub ramdisk: Option<ImageData>,
+}
+
+impl VmCreateCliArg {
+    /// Expand `~` and `${VAR}` in image paths, then resolve relative paths against
+    /// `config_dir`, which is the directory containing config file.
+    pub fn resolve_paths(&mut self, config_dir: &Path) -> Result<(), CfgProblem> {
+        let resolve = |key: &str, path: &mut String| {
+            let expanded = expand_path(path).map_err(|err| CfgProblem::new(key, err))?;
+            let resolved = config_dir.join(expanded);
+            let resolved = resolved
+                .to_str()
+                .ok_or_else(|| CfgProblem::new(key, format!("{resolved:?} is not valid UTF-8")))?;
+            debug!("resolved `{key}` {path:?} to {resolved:?}");
+            *path = resolved.to_string();
+            Ok(())
+        };
+
+        if !self.bios_path.is_empty() {
+            resolve("bios_path", &mut self.bios_path)?;
+        }
+        resolve("kernel_path", &mut self.kernel_path)?;
+        if let Some(path) = &mut self.ramdisk_path {
+            resolve("ramdisk_path", path)?;
+        }
+        if let Some(path) = &mut self.disk_path {
+            // Keep empty path to be reported by `validate`.
+            if !path.trim().is_empty() {
+                resolve("disk_path", path)?;
+            }
+        }
+        for (i, disk) in self.disks.iter_mut().enumerate() {
+            if !disk.path.trim().is_empty() {
+                resolve(&format!("disks[{i}].path"), &mut disk.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// All disks with `disk_path` first, index of each disk is its device index in VM.
+    pub fn all_disks(&self) -> Vec<(String, VmDiskCfg)> {
+        let mut disks = Vec::new();
+        if let Some(path) = &self.disk_path {
+            let mut disk = VmDiskCfg::new(path.clone());
+            disk.sha256 = self.disk_sha256.clone();
+            disks.push((String::from("disk_path"), disk));
+        }
+        disks.extend(
+            self.disks
+                .iter()
+                .enumerate()
+                .map(|(i, disk)| (format!("disks[{i}].path"), disk.clone())),
+        );
+        disks
+    }
+
+    /// Disks to be registered to axdaemon.
+    pub fn disk_cfgs(&self) -> Vec<DiskCfg> {
+        self.all_disks()
+            .into_iter()
+            .map(|(_, disk)| DiskCfg {
+                path: PathBuf::from(disk.path),
+                format: disk.format,
+                read_only: disk.read_only,
+                snapshot: disk.snapshot,
+                direct: disk.direct,
+                serial: disk.serial,
+                cache: disk.cache,
+                discard: disk.discard,
+                size: disk.size,
+            })
+            .collect()
+    }
+
+    /// Rewrite raw config for hypervisor, which only accepts `memory_regions` in the form
+    /// of `[gpa, size, flags]` with numeric flags, and takes addresses as they are.
+    ///
+    /// `boot_params_addr` is the zero page of bzImage kernel, see `crate::linux`.
+    pub fn raw_config_for_hypervisor(
+        &self,
+        raw_cfg: &str,
+        boot_params_addr: Option<usize>,
+    ) -> Result<String, String> {
+        let mut table: toml::Table = toml::from_str(raw_cfg).map_err(|err| err.to_string())?;
+        // Addresses may be derived from kernel image, see `axvmm_create_vm`.
+        table.insert(
+            String::from("kernel_load_addr"),
+            toml::Value::Integer(self.kernel_load_addr as i64),
+        );
+        table.insert(
+            String::from("entry_point"),
+            toml::Value::Integer(self.entry_point as i64),
+        );
+        if let Some(addr) = boot_params_addr {
+            table.insert(
+                String::from("boot_params_addr"),
+                toml::Value::Integer(addr as i64),
+            );
+        }
+        if table.contains_key("memory_regions") {
+            let regions = self
+                .memory_regions
+                .iter()
+                .map(|region| {
+                    toml::Value::Array(vec![
+                        toml::Value::Integer(region.gpa as i64),
+                        toml::Value::Integer(region.size as i64),
+                        toml::Value::Integer(region.flags.0 as i64),
+                    ])
+                })
+                .collect();
+            table.insert(String::from("memory_regions"), toml::Value::Array(regions));
+        }
+        toml::to_string(&table).map_err(|err| err.to_string())
+    }
+
+    /// Check the config before it is sent to hypervisor, return every problem found, or
+    /// the images loaded if there is none.
+    ///
+    /// Images are verified against their digests, paths should have been resolved by
+    /// `resolve_paths`.
+    pub fn validate(&self) -> Result<VmImages, Vec<CfgProblem>> {
+        let mut problems = Vec::new();
+
+        if self.cpu_set == 0 {
+            problems.push(CfgProblem::new("cpu_set", "no CPU is assigned to VM"));
+        }
+
+        self.validate_disks(&mut problems);
+
+        if self.ramdisk_path.is_some() && self.ramdisk_load_addr.is_none() {
+            problems.push(CfgProblem::new(
+                "ramdisk_load_addr",
+                "required since `ramdisk_path` is set",
+            ));
+        }
+        if self.ramdisk_path.is_none() && self.ramdisk_load_addr.is_some() {
+            problems.push(CfgProblem::new(
+                "ramdisk_path",
+                "required since `ramdisk_load_addr` is set",
+            ));
+        }
+
+        self.validate_memory_regions(&mut problems);
+
+        for (key, _, digest) in self.image_digests() {
+            if let Some(digest) = digest {
+                if !is_sha256_hex(digest) {
+                    problems.push(CfgProblem::new(
+                        key.as_str(),
+                        "expect 64 hex digits of SHA-256 digest",
+                    ));
+                }
+            }
+        }
+
+        let kernel = self
+            .load_image(
+                "kernel",
+                &self.kernel_path,
+                self.kernel_sha256.as_deref(),
+                &mut problems,
+            )
+            .and_then(|raw| match KernelImage::parse(raw) {
+                Ok(image) => {
+                    // Load address of ELF or multiboot kernel comes from the image itself.
+                    self.check_image_fits(
+                        "kernel_load_addr",
+                        image.load_addr.unwrap_or(self.kernel_load_addr),
+                        image.mem_size,
+                        &mut problems,
+                    );
+                    Some(image)
+                }
+                Err(err) => {
+                    problems.push(CfgProblem::new(
+                        "kernel_path",
+                        format!("bad kernel image {:?}, {err}", self.kernel_path),
+                    ));
+                    None
+                }
+            });
+        let is_bzimage = kernel
+            .as_ref()
+            .is_some_and(|image| image.format == KernelFormat::BzImage);
+
+        let mut bios = None;
+        if is_bzimage {
+            if self.vm_type != VM_TYPE_LINUX {
+                problems.push(CfgProblem::new(
+                    "vm_type",
+                    format!("bzImage kernel requires Linux VM type {VM_TYPE_LINUX}"),
+                ));
+            }
+            if !self.bios_path.is_empty() {
+                problems.push(CfgProblem::new(
+                    "bios_path",
+                    "not used by bzImage kernel, remove the key",
+                ));
+            }
+            let cmdline_len = self.cmdline.as_ref().map_or(0, |cmdline| cmdline.len());
+            self.check_image_fits(
+                "bios_load_addr",
+                self.bios_load_addr,
+                ZERO_PAGE_SIZE + cmdline_len + 1,
+                &mut problems,
+            );
+        } else {
+            if self.cmdline.is_some() {
+                problems.push(CfgProblem::new(
+                    "cmdline",
+                    "only supported by bzImage kernel",
+                ));
+            }
+            if self.bios_path.is_empty() {
+                problems.push(CfgProblem::new(
+                    "bios_path",
+                    "required by non-bzImage kernel",
+                ));
+            } else {
+                bios = self.load_image(
+                    "bios",
+                    &self.bios_path,
+                    self.bios_sha256.as_deref(),
+                    &mut problems,
+                );
+                if let Some(bios) = &bios {
+                    self.check_image_fits(
+                        "bios_load_addr",
+                        self.bios_load_addr,
+                        bios.len(),
+                        &mut problems,
+                    );
+                }
+            }
+        }
+        let mut ramdisk = None;
+        if let (Some(path), Some(addr)) = (&self.ramdisk_path, self.ramdisk_load_addr) {
+            ramdisk = self.load_image(
+                "ramdisk",
+                path,
+                self.ramdisk_sha256.as_deref(),
+                &mut problems,
+            );
+            if let Some(ramdisk) = &ramdisk {
+                self.check_image_fits("ramdisk_load_addr", addr, ramdisk.len(), &mut problems);
+            }
+        }
+
+        match kernel {
+            Some(kernel) if problems.is_empty() => Ok(VmImages {
+                kernel,
+                bios,
+                ramdisk,
+            }),
+            _ => Err(problems),
+        }
+    }
+
+    /// Map image file at `path` of `{kind}_path`, verify it against `expected` digest of
+    /// `{kind}_sha256` if it is set, then decompress it up to the size of the largest
+    /// memory region.
+    ///
+    fn load_image(
+        &self,
+        kind: &str,
+        path: &str,
+        expected: Option<&str>,
+        problems: &mut Vec<CfgProblem>,
+    ) -> Option<ImageData> {
+        let path_key = format!("{kind}_path");
+        match std::fs::metadata(path) {
+            Ok(metadata) if !metadata.is_file() => {
+                problems.push(CfgProblem::new(path_key, format!("{path:?} is not a file")));
+                return None;
+            }
+            Ok(metadata) if metadata.len() == 0 => {
+                problems.push(CfgProblem::new(path_key, format!("{path:?} is empty")));
+                return None;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                problems.push(CfgProblem::new(
+                    path_key,
+                    format!("failed to access {path:?}, {err}"),
+                ));
+                return None;
+            }
+        }
+        let file = match MappedFile::open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                problems.push(CfgProblem::new(
+                    path_key,
+                    format!("failed to load {path:?}, {err}"),
+                ));
+                return None;
+            }
+        };
+        // Malformed digest is reported by `validate`.
+        if let Some(expected) = expected.filter(|expected| is_sha256_hex(expected)) {
+            if let Err(err) = digest::check_image_data(path, &file, expected) {
+                problems.push(CfgProblem::new(format!("{kind}_sha256"), err));
+                return None;
+            }
+            debug!("{path:?} matches `{kind}_sha256`");
+        }
+        match ImageData::from_file(Path::new(path), file, self.max_image_size()) {
+            Ok(image) => Some(image),
+            Err(err) => {
+                problems.push(CfgProblem::new(
+                    path_key,
+                    format!("failed to load {path:?}, {err}"),
+                ));
+                None
+            }
+        }
+    }
+
+    fn validate_disks(&self, problems: &mut Vec<CfgProblem>) {
+        let disks = self.all_disks();
+        for (i, (key, disk)) in disks.iter().enumerate() {
+            if disk.format == DiskFormat::Ram {
+                validate_ram_disk(key, disk, problems);
+                continue;
+            }
+            if disk.sha256.is_some() && disk.is_writable() {
+                problems.push(CfgProblem::new(
+                    key.replace("path", "sha256"),
+                    "digest of writable disk changes once guest writes it, \
+                    set `read_only` or `snapshot` of the disk",
+                ));
+            }
+            if disk.size.is_some() {
+                problems.push(CfgProblem::new(
+                    key.replace("path", "size"),
+                    "only RAM disks have size, disk images take the size of file",
+                ));
+            }
+            if disk.path.trim().is_empty() {
+                problems.push(CfgProblem::new(
+                    key.as_str(),
+                    "empty path, remove the disk if VM has no disk",
+                ));
+                continue;
+            }
+            // Disk images written in place must not be shared.
+            if let Some((other, _)) = disks[..i]
+                .iter()
+                .find(|(_, d)| d.path == disk.path && (d.is_writable() || disk.is_writable()))
+            {
+                problems.push(CfgProblem::new(
+                    key.as_str(),
+                    format!("{:?} is already used by `{other}`", disk.path),
+                ));
+            }
+            match std::fs::metadata(&disk.path) {
+                Ok(metadata) if metadata.is_dir() => problems.push(CfgProblem::new(
+                    key.as_str(),
+                    format!("{:?} is a directory", disk.path),
+                )),
+                Ok(_)
+                    if matches!(disk.format, DiskFormat::Qcow2 | DiskFormat::Overlay)
+                        && image_format(&disk.path) != disk.format =>
+                {
+                    problems.push(CfgProblem::new(
+                        key.as_str(),
+                        format!("{:?} is not a {} image", disk.path, disk.format),
+                    ))
+                }
+                Ok(_)
+                    if disk.discard
+                        && disk.format != DiskFormat::Raw
+                        && image_format(&disk.path) != DiskFormat::Raw =>
+                {
+                    problems.push(CfgProblem::new(
+                        key.replace("path", "discard"),
+                        "discard is only supported for raw disk images",
+                    ))
+                }
+                Ok(_) => {}
+                Err(err) => problems.push(CfgProblem::new(
+                    key.as_str(),
+                    format!("failed to access {:?}, {err}", disk.path),
+                )),
+            }
+        }
+        for (i, disk) in self.disks.iter().enumerate() {
+            if disk
+                .serial
+                .as_ref()
+                .is_some_and(|serial| serial.len() > DISK_SERIAL_MAX_LEN)
+            {
+                problems.push(CfgProblem::new(
+                    format!("disks[{i}].serial"),
+                    format!("exceeds {DISK_SERIAL_MAX_LEN} Bytes"),
+                ));
+            }
+        }
+    }
+
+    fn validate_memory_regions(&self, problems: &mut Vec<CfgProblem>) {
+        if self.memory_regions.is_empty() {
+            problems.push(CfgProblem::new(
+                "memory_regions",
+                "no memory region is configured",
+            ));
+        }
+
+        for (i, region) in self.memory_regions.iter().enumerate() {
+            if region.size == 0 {
+                problems.push(CfgProblem::new(
+                    format!("memory_regions[{i}]"),
+                    "size is zero",
+                ));
+            } else if region.gpa.checked_add(region.size).is_none() {
+                problems.push(CfgProblem::new(
+                    format!("memory_regions[{i}]"),
+                    format!("{region} overflows address space"),
+                ));
+            }
+        }
+
+        for (i, a) in self.memory_regions.iter().enumerate() {
+            for (j, b) in self.memory_regions.iter().enumerate().skip(i + 1) {
+                if a.overlaps(b) {
+                    problems.push(CfgProblem::new(
+                        format!("memory_regions[{j}]"),
+                        format!("{b} overlaps with memory_regions[{i}] {a}"),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Images mapped and loaded by axcli, with their digest keys and expected digests.
+    ///
+    /// They are verified as they are mapped, see `crate::digest::check_image_data`.
+    pub fn loaded_image_digests(&self) -> Vec<(String, &str, Option<&str>)> {
+        let mut images = Vec::new();
+        if !self.bios_path.is_empty() {
+            images.push((
+                String::from("bios_sha256"),
+                self.bios_path.as_str(),
+                self.bios_sha256.as_deref(),
+            ));
+        }
+        images.push((
+            String::from("kernel_sha256"),
+            self.kernel_path.as_str(),
+            self.kernel_sha256.as_deref(),
+        ));
+        if let Some(path) = &self.ramdisk_path {
+            images.push((
+                String::from("ramdisk_sha256"),
+                path.as_str(),
+                self.ramdisk_sha256.as_deref(),
+            ));
+        }
+        images
+    }
+
+    /// Disk images opened by axdaemon, with their digest keys and expected digests.
+    ///
+    /// Only read-only and snapshot disks are included, as guest writes change the others.
+    pub fn disk_image_digests(&self) -> Vec<(String, &str, Option<&str>)> {
+        self.disks
+            .iter()
+            .enumerate()
+            .filter(|(_, disk)| disk.format != DiskFormat::Ram && !disk.is_writable())
+            .map(|(i, disk)| {
+                (
+                    format!("disks[{i}].sha256"),
+                    disk.path.as_str(),
+                    disk.sha256.as_deref(),
+                )
+            })
+            .collect()
+    }
+
+    /// All images to be hashed, digest keys are dotted paths, e.g. `disks[1].sha256`.
+    pub fn image_digests(&self) -> Vec<(String, &str, Option<&str>)> {
+        let mut images = self.loaded_image_digests();
+        images.extend(self.disk_image_digests());
+        images
+    }
+
+    /// Max size of an image, which must fit in one memory region.
+    pub fn max_image_size(&self) -> usize {
+        self.memory_regions
+            .iter()
+            .map(|region| region.size)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Check that image of `size` Bytes at `load_addr` fits in one memory region.
+    pub fn image_fits(&self, load_addr: usize, size: usize) -> Result<(), String> {
+        let end = load_addr.saturating_add(size);
+        if self
+            .memory_regions
+            .iter()
+            .any(|region| region.gpa <= load_addr && end <= region.end())
+        {
+            Ok(())
+        } else {
+            Err(format!(
+                "image [{load_addr:#x}, {end:#x}) of {size:#x} Bytes does not fit in any memory region"
+            ))
+        }
+    }
+
+    fn check_image_fits(
+        &self,
+        addr_key: &str,
+        load_addr: usize,
+        size: usize,
+        problems: &mut Vec<CfgProblem>,
+    ) {
+        if let Err(err) = self.image_fits(load_addr, size) {
+            problems.push(CfgProblem::new(addr_key, err));
+        }
+    }
+}
+
+/// Expand leading `~` to `$HOME`, and `${VAR}` to value of environment variable `VAR`.
+fn expand_path(path: &str) -> Result<String, String> {
+    let home = || std::env::var("HOME").map_err(|_| String::from("`~` used but $HOME is not set"));
+    let mut expanded = match path.strip_prefix('~') {
+        Some("") => home()?,
+        Some(rest) if rest.starts_with('/') => home()? + rest,
+        _ => String::from(path),
+    };
+
+    // Values are not expanded again.
+    let mut cursor = 0;
+    while let Some(start) = expanded[cursor..].find("${").map(|i| cursor + i) {
+        let len = expanded[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed `${{` in {path:?}"))?;
+        let name = &expanded[start + 2..start + len];
+        let value = std::env::var(name)
+            .map_err(|_| format!("environment variable {name:?} in {path:?} is not set"))?;
+        expanded.replace_range(start..start + len + 1, &value);
+        cursor = start + value.len();
+    }
+    Ok(expanded)
+}
+
+fn is_sha256_hex(digest: &str) -> bool {
+    digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+impl VmMemCfg {
+    fn end(&self) -> usize {
+        self.gpa.saturating_add(self.size)
+    }
+
+    fn overlaps(&self, other: &Self) -> bool {
+        self.size != 0 && other.size != 0 && self.gpa < other.end() && other.gpa < self.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW_CFG: &str = r#"
+id = 1
+name = "linux"
+vm_type = 2
+cpu_set = 1
+entry_point = 0x7000
+bios_load_addr = 0x7000
+kernel_path = "bzImage"
+kernel_load_addr = 0x7000
+memory_regions = [[0, 0x8000_0000, ["read", "write", "exec"], "ram"]]
+"#;
+
+    fn raw_config(boot_params_addr: Option<usize>) -> toml::Table {
+        let mut vm_arg: VmCreateCliArg = toml::from_str(RAW_CFG).unwrap();
+        vm_arg.kernel_load_addr = 0x100_0000;
+        vm_arg.entry_point = 0x100_0200;
+        let raw_cfg = vm_arg
+            .raw_config_for_hypervisor(RAW_CFG, boot_params_addr)
+            .unwrap();
+        toml::from_str(&raw_cfg).unwrap()
+    }
+
+    #[test]
+    fn raw_config_has_derived_addresses() {
+        let table = raw_config(Some(0x7000));
+        assert_eq!(table["kernel_load_addr"].as_integer(), Some(0x100_0000));
+        assert_eq!(table["entry_point"].as_integer(), Some(0x100_0200));
+        assert_eq!(table["boot_params_addr"].as_integer(), Some(0x7000));
+        assert_eq!(
+            table["memory_regions"],
+            toml::Value::Array(vec![toml::Value::Array(vec![
+                toml::Value::Integer(0),
+                toml::Value::Integer(0x8000_0000),
+                toml::Value::Integer(0b111),
+            ])])
+        );
+    }
+
+    #[test]
+    fn writable_disks_are_not_hashed() {
+        let mut vm_arg: VmCreateCliArg = toml::from_str(RAW_CFG).unwrap();
+        vm_arg.disk_path = Some(String::from("/dev/null"));
+        vm_arg.disk_sha256 = Some("0".repeat(64));
+        for (read_only, snapshot) in [(false, false), (true, false), (false, true)] {
+            let mut disk = VmDiskCfg::new(String::from("/dev/null"));
+            (disk.read_only, disk.snapshot) = (read_only, snapshot);
+            disk.sha256 = Some("0".repeat(64));
+            vm_arg.disks.push(disk);
+        }
+
+        let keys: Vec<String> = vm_arg
+            .disk_image_digests()
+            .into_iter()
+            .map(|(key, _, _)| key)
+            .collect();
+        assert_eq!(keys, ["disks[1].sha256", "disks[2].sha256"]);
+
+        let mut problems = Vec::new();
+        vm_arg.validate_disks(&mut problems);
+        let keys: Vec<&str> = problems
+            .iter()
+            .filter(|problem| problem.key.ends_with("sha256"))
+            .map(|problem| problem.key.as_str())
+            .collect();
+        assert_eq!(keys, ["disk_sha256", "disks[0].sha256"]);
+    }
+
+    #[test]
+    fn raw_config_without_zero_page() {
+        assert!(!raw_config(None).contains_key("boot_params_addr"));
+    }
 }
@@ -1,3 +1,4 @@
+use axdaemon_request::DiskFormat;
 use clap::{Args, Parser, Subcommand};
 
 #[derive(Parser)]
@@ -5,6 +6,13 @@ use clap::{Args, Parser, Subcommand};
 #[command(about = "CommandLine Interface for ArceOS Hypervisor", long_about = None)]
 #[command(args_conflicts_with_subcommands = true)]
 pub struct CLI {
+    /// Device node of the hypervisor driver, e.g. `/dev/jailhouse`.
+    ///
+    /// Also configured by `AXCLI_DEVICE` or `device` in host config file `/etc/axcli.toml`,
+    /// known device nodes are detected if none is configured. Given after the subcommand,
+    /// e.g. `axcli vm boot 1 --device /dev/jailhouse`.
+    #[arg(long, global = true, value_name = "DEVICE", value_hint = clap::ValueHint::FilePath)]
+    pub device: Option<std::path::PathBuf>,
     #[command(subcommand)]
     pub subcmd: CLISubCmd,
 }
@@ -23,16 +31,22 @@ pub enum CLISubCmd {
         #[command(subcommand)]
         subcmd: VmSubCmd,
     },
+    /// Subcommands related to copy-on-write disk overlays, run with permissions of the user.
+    Disk {
+        #[command(subcommand)]
+        subcmd: DiskSubCmd,
+    },
 }
 
 #[derive(Subcommand)]
 #[command(args_conflicts_with_subcommands = true)]
 #[command(flatten_help = true)]
 pub enum HvSubCmd {
-    /// Enable arceos-hypervisor type1.5.
-    Enable,
+    /// Enable arceos-hypervisor type1.5 according to config file.
+    #[command(arg_required_else_help = true)]
+    Enable(HvEnableArgs),
     /// Disable arceos-hypervisor type1.5.
-    Disable,
+    Disable(HvDisableArgs),
 }
 
 #[derive(Subcommand)]
@@ -40,16 +54,55 @@ pub enum HvSubCmd {
 #[command(flatten_help = true)]
 pub enum VmSubCmd {
     /// list the info of the vm
-    List,
+    List(VmListArgs),
     /// Create guest VM according to config file.
     #[command(arg_required_else_help = true)]
     Create(VmCreateArgs),
+    /// Check VM config file without creating the VM.
+    #[command(arg_required_else_help = true)]
+    Validate(VmCreateArgs),
+    /// Write SHA-256 digests of images into VM config file.
+    #[command(arg_required_else_help = true)]
+    Hash(VmCreateArgs),
     /// Boot guest VM according to VM id.
     #[command(arg_required_else_help = true)]
     Boot(VmIdArgs),
     /// Shutdown guest VM according to VM id.
     #[command(arg_required_else_help = true)]
     Shutdown(VmIdArgs),
+    /// Destroy a shut down guest VM according to VM id.
+    #[command(arg_required_else_help = true)]
+    Destroy(VmIdArgs),
+}
+
+#[derive(Subcommand)]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(flatten_help = true)]
+pub enum DiskSubCmd {
+    /// Create an empty overlay on top of a base disk image.
+    #[command(arg_required_else_help = true)]
+    Create(DiskCreateArgs),
+    /// Merge an overlay into its base image, then empty the overlay.
+    ///
+    /// Other overlays on the same base image see the merged data.
+    #[command(arg_required_else_help = true)]
+    Commit(DiskCommitArgs),
+    /// Switch base image of an overlay, keeping data seen by guest.
+    #[command(arg_required_else_help = true)]
+    Rebase(DiskRebaseArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct HvEnableArgs {
+    #[arg(value_name = "HV_CONFIG", value_hint = clap::ValueHint::FilePath)]
+    pub config_path: std::path::PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct HvDisableArgs {
+    /// Disable hypervisor even if there are still VMs registered.
+    #[arg(long)]
+    pub force: bool,
 }
 
 #[derive(Debug, Args)]
@@ -58,8 +111,90 @@ pub struct VmCreateArgs {
     pub config_path: std::path::PathBuf,
 }
 
+#[derive(Debug, Args)]
+pub struct VmListArgs {
+    /// Print VM info in JSON format.
+    #[arg(long)]
+    pub json: bool,
+}
+
 #[derive(Debug, Args)]
 pub struct VmIdArgs {
     #[arg(value_name = "VMID")]
     pub vmid: u64,
 }
+
+#[derive(Debug, Args)]
+pub struct DiskCreateArgs {
+    #[arg(value_name = "OVERLAY", value_hint = clap::ValueHint::FilePath)]
+    pub path: std::path::PathBuf,
+    /// Base disk image, raw, qcow2 or another overlay.
+    #[arg(long, value_name = "BASE", value_hint = clap::ValueHint::FilePath)]
+    pub base: std::path::PathBuf,
+    /// Format of base image, it is never detected.
+    #[arg(long, value_name = "FORMAT", default_value = "raw", value_parser = parse_base_format)]
+    pub base_format: DiskFormat,
+}
+
+#[derive(Debug, Args)]
+pub struct DiskCommitArgs {
+    #[arg(value_name = "OVERLAY", value_hint = clap::ValueHint::FilePath)]
+    pub path: std::path::PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct DiskRebaseArgs {
+    #[arg(value_name = "OVERLAY", value_hint = clap::ValueHint::FilePath)]
+    pub path: std::path::PathBuf,
+    /// New base disk image.
+    #[arg(long, value_name = "BASE", value_hint = clap::ValueHint::FilePath)]
+    pub base: std::path::PathBuf,
+    /// Format of new base image, it is never detected.
+    #[arg(long, value_name = "FORMAT", default_value = "raw", value_parser = parse_base_format)]
+    pub base_format: DiskFormat,
+    /// Only switch the base without comparing it with the old one, for a base image
+    /// which is moved or copied.
+    #[arg(long = "unsafe")]
+    pub unsafe_: bool,
+}
+
+fn parse_base_format(format: &str) -> Result<DiskFormat, String> {
+    match format {
+        "raw" => Ok(DiskFormat::Raw),
+        "qcow2" => Ok(DiskFormat::Qcow2),
+        "overlay" => Ok(DiskFormat::Overlay),
+        _ => Err("expect raw, qcow2 or overlay".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(args: &[&str]) -> Option<std::path::PathBuf> {
+        CLI::try_parse_from(args).unwrap().device
+    }
+
+    #[test]
+    fn device_follows_subcommand() {
+        assert_eq!(
+            device(&["axcli", "vm", "boot", "1", "--device", "/dev/jailhouse"]),
+            Some("/dev/jailhouse".into())
+        );
+        assert_eq!(
+            device(&["axcli", "hv", "disable", "--device", "/dev/arceos_vdev"]),
+            Some("/dev/arceos_vdev".into())
+        );
+        assert_eq!(device(&["axcli", "vm", "list"]), None);
+    }
+
+    #[test]
+    fn args_conflict_with_subcommands() {
+        assert!(
+            CLI::try_parse_from(["axcli", "--device", "/dev/jailhouse", "vm", "list"]).is_err()
+        );
+        assert!(
+            CLI::try_parse_from(["axcli", "vm", "--device", "/dev/jailhouse", "list"]).is_err()
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! Compressed image files, detected by their magic bytes.
+
+use core::fmt;
+use std::io::Read;
+
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+const XZ_MAGIC: &[u8] = b"\xfd7zXZ\x00";
+const ZSTD_MAGIC: &[u8] = b"\x28\xb5\x2f\xfd";
+
+/// Deflate is the only compression method of gzip.
+const GZIP_METHOD_DEFLATE: u8 = 8;
+const GZIP_FLAG_HCRC: u8 = 1 << 1;
+const GZIP_FLAG_EXTRA: u8 = 1 << 2;
+const GZIP_FLAG_NAME: u8 = 1 << 3;
+const GZIP_FLAG_COMMENT: u8 = 1 << 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Gzip => "gzip",
+            Self::Xz => "xz",
+            Self::Zstd => "zstd",
+        };
+        f.pad(name)
+    }
+}
+
+impl Compression {
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(GZIP_MAGIC) {
+            Some(Self::Gzip)
+        } else if data.starts_with(XZ_MAGIC) {
+            Some(Self::Xz)
+        } else if data.starts_with(ZSTD_MAGIC) {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Decompress `data`, fail if the result exceeds `limit` Bytes.
+    pub fn decompress(&self, data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Gzip => gunzip(data, limit),
+            Self::Xz => read_limited(xz2::read::XzDecoder::new(data), limit),
+            Self::Zstd => {
+                let decoder = ruzstd::decoding::StreamingDecoder::new(data)
+                    .map_err(|err| format!("bad zstd frame, {err}"))?;
+                read_limited(decoder, limit)
+            }
+        }
+        .map_err(|err| format!("failed to decompress {self} image, {err}"))
+    }
+}
+
+fn read_limited(decoder: impl Read, limit: usize) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    decoder
+        .take(limit as u64 + 1)
+        .read_to_end(&mut data)
+        .map_err(|err| err.to_string())?;
+    if data.len() > limit {
+        return Err(format!("result exceeds {limit:#x} Bytes"));
+    }
+    Ok(data)
+}
+
+/// Decompress the first member of gzip file, see RFC 1952.
+fn gunzip(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    let truncated = || String::from("truncated gzip header");
+    let header = data.get(..10).ok_or_else(truncated)?;
+    if header[2] != GZIP_METHOD_DEFLATE {
+        return Err(format!("unknown gzip method {}", header[2]));
+    }
+    let flags = header[3];
+
+    let mut offset = 10;
+    if flags & GZIP_FLAG_EXTRA != 0 {
+        let len = data.get(offset..offset + 2).ok_or_else(truncated)?;
+        offset += 2 + u16::from_le_bytes([len[0], len[1]]) as usize;
+    }
+    for flag in [GZIP_FLAG_NAME, GZIP_FLAG_COMMENT] {
+        if flags & flag != 0 {
+            let len = data
+                .get(offset..)
+                .and_then(|rest| rest.iter().position(|&b| b == 0))
+                .ok_or_else(truncated)?;
+            offset += len + 1;
+        }
+    }
+    if flags & GZIP_FLAG_HCRC != 0 {
+        offset += 2;
+    }
+    let deflate = data.get(offset..).ok_or_else(truncated)?;
+
+    let output = miniz_oxide::inflate::decompress_to_vec_with_limit(deflate, limit).map_err(
+        |err| match err.status {
+            miniz_oxide::inflate::TINFLStatus::HasMoreOutput => {
+                format!("result exceeds {limit:#x} Bytes")
+            }
+            _ => err.to_string(),
+        },
+    )?;
+
+    // The trailer ends with size of the original data modulo 2^32.
+    let trailer = data.len().checked_sub(4).map(|start| &data[start..]);
+    let size = trailer.map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+    if size != Some(output.len() as u32) {
+        return Err(format!(
+            "size {:#x} mismatches gzip trailer {size:#x?}, multi-member gzip is not supported",
+            output.len()
+        ));
+    }
+    Ok(output)
+}
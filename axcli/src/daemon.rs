@@ -1,31 +1,98 @@
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
 use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
 use std::path::PathBuf;
 use std::str::FromStr;
 
 use colored::Colorize;
+use serde::de::DeserializeOwned;
 
-use axdaemon_request::{DaemonReply, DaemonRequest, ARCEOS_DAEMON_PORT_DEFAULT, LOCALHOST};
+use axdaemon_request::{
+    DaemonHello, DaemonReply, DaemonRequest, DiskCfg, ARCEOS_DAEMON_PORT_DEFAULT,
+    AXDAEMON_SOCKET_PATH_DEFAULT, LOCALHOST, MAX_FRAME_SIZE,
+};
 use axerrno::{ax_err, ax_err_type, AxError, AxResult};
 
-/// Register VM information to axdaemon process.
-pub fn register_vm_to_daemon(vmid: usize, disk_image_path: PathBuf) {
-    request_daemon(DaemonRequest::RegisterVM {
-        vmid,
-        disk_image_path,
-    })
-    .expect("Failed to register VM to axdaemon");
+/// Register VM with its disks to axdaemon process.
+pub fn register_vm_to_daemon(vmid: usize, disks: Vec<DiskCfg>) -> AxResult {
+    request_daemon(DaemonRequest::RegisterVM { vmid, disks }).map(|_| ())
 }
 
 /// Setup VM on axdaemon process.
 ///
 /// Including:
 /// * Virtio-Blk service if needed.
-pub fn setup_vm_on_daemon(vmid: usize) {
-    request_daemon(DaemonRequest::BootVM { vmid }).expect("Failed to setup VM on axdaemon");
+pub fn setup_vm_on_daemon(vmid: usize) -> AxResult {
+    request_daemon(DaemonRequest::BootVM { vmid }).map(|_| ())
 }
 
-fn request_daemon(request: DaemonRequest) -> AxResult {
+/// Tell axdaemon process that VM is booted by hypervisor.
+pub fn confirm_vm_booted_on_daemon(vmid: usize) -> AxResult {
+    request_daemon(DaemonRequest::VMBooted { vmid }).map(|_| ())
+}
+
+/// Tear down emulated devices of a shut down VM on axdaemon process.
+pub fn shutdown_vm_on_daemon(vmid: usize) -> AxResult {
+    request_daemon(DaemonRequest::ShutdownVM { vmid }).map(|_| ())
+}
+
+/// Unregister a destroyed VM from axdaemon process.
+pub fn unregister_vm_from_daemon(vmid: usize) -> AxResult {
+    request_daemon(DaemonRequest::UnregisterVM { vmid }).map(|_| ())
+}
+
+/// Query disks of VMs registered in axdaemon process.
+pub fn list_vm_on_daemon() -> AxResult<BTreeMap<usize, Vec<DiskCfg>>> {
+    match request_daemon(DaemonRequest::ListVM)? {
+        DaemonReply::VMList(vm_disks) => Ok(vm_disks),
+        other => ax_err!(
+            InvalidData,
+            format!("unexpected list reply from axdaemon: {other:?}")
+        ),
+    }
+}
+
+/// Fail if any of disk images `paths` is open for a VM on axdaemon process, including
+/// backing files of its disks.
+pub fn check_disks_unused_on_daemon(paths: Vec<PathBuf>) -> AxResult {
+    request_daemon(DaemonRequest::CheckDisksUnused { paths }).map(|_| ())
+}
+
+/// Connection to axdaemon, through Unix socket or TCP.
+trait DaemonConnection: Read + Write {}
+
+impl<T: Read + Write> DaemonConnection for T {}
+
+/// Connect to axdaemon.
+///
+/// Unix socket (`AXDAEMON_SOCKET`, or `AXDAEMON_SOCKET_PATH_DEFAULT`) is used by default,
+/// TCP is used only if `AXDAEMON_IP` or `AXDAEMON_PORT` is set.
+fn connect_daemon() -> AxResult<Box<dyn DaemonConnection>> {
+    let use_tcp =
+        std::env::var_os("AXDAEMON_IP").is_some() || std::env::var_os("AXDAEMON_PORT").is_some();
+    if use_tcp {
+        return connect_daemon_tcp().map(|stream| Box::new(stream) as Box<dyn DaemonConnection>);
+    }
+
+    let socket_path = std::env::var_os("AXDAEMON_SOCKET")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(AXDAEMON_SOCKET_PATH_DEFAULT));
+    let stream = UnixStream::connect(&socket_path).map_err(|err| {
+        warn!(
+            "[{}] failed to connect Unix socket {socket_path:?} {err:?}\n{}",
+            "AxCli".bold().purple(),
+            format!(
+                "Note: please check if {} is running and you have permission to access the socket",
+                "AxDaemon".bold().green()
+            )
+        );
+        AxError::BadState
+    })?;
+    Ok(Box::new(stream))
+}
+
+fn connect_daemon_tcp() -> AxResult<TcpStream> {
     let daemon_ip = match std::env::var("AXDAEMON_IP") {
         Ok(ip) => IpAddr::from_str(ip.as_str()).unwrap_or(LOCALHOST),
         Err(_) => LOCALHOST,
@@ -38,12 +105,12 @@ fn request_daemon(request: DaemonRequest) -> AxResult {
 
     let daemon_addr = SocketAddr::new(daemon_ip, daemon_port);
 
-    let mut stream = TcpStream::connect(daemon_addr).map_err(|err| {
+    let stream = TcpStream::connect(daemon_addr).map_err(|err| {
         warn!(
             "[{}] failed to open TCP connection {err:?}\n{}",
             "AxCli".bold().purple(),
             format!(
-                "Note: please check if {} is running as well as the ip address and port",
+                "Note: please check if {} is running with `--tcp` as well as the ip address and port",
                 "AxDaemon".bold().green()
             )
         );
@@ -52,6 +119,25 @@ fn request_daemon(request: DaemonRequest) -> AxResult {
     stream
         .set_nodelay(true)
         .map_err(|err| ax_err_type!(BadState, format!("failed to set nodelay {err:?}")))?;
+    Ok(stream)
+}
+
+fn request_daemon(request: DaemonRequest) -> AxResult<DaemonReply> {
+    let mut stream = connect_daemon()?;
+    let daemon_hello = handshake(&mut stream)?;
+    if let Some(feature) = request
+        .required_features()
+        .into_iter()
+        .find(|feature| !daemon_hello.supports(feature))
+    {
+        return ax_err!(
+            Unsupported,
+            format!(
+                "{} does not support {feature:?}, please upgrade it",
+                "AxDaemon".bold().green()
+            )
+        );
+    }
 
     let message = bincode::serialize(&request).map_err(|err| {
         ax_err_type!(
@@ -63,22 +149,55 @@ fn request_daemon(request: DaemonRequest) -> AxResult {
     tcp_send(&mut stream, &message)
         .map_err(|err| ax_err_type!(BadState, format!("failed to send DaemonRequest {err:?}")))?;
 
-    let reply = receive_reply(&mut stream).and_then(|reply| {
+    let reply = receive_message::<DaemonReply>(&mut stream, "DaemonReply").and_then(|reply| {
         reply.ok_or_else(|| ax_err_type!(BadState, "server disconnected unexpectedly"))
     })?;
 
     match reply {
-        DaemonReply::Result(result) => result.map_err(|e| ax_err_type!(BadState, e.as_str()))?,
-        other => warn!(
-            "[{}] unexpected register reply: {other:?}",
-            "AxCli".bold().purple()
-        ),
+        DaemonReply::Result(Err(err)) => Err(ax_err_type!(BadState, err.as_str())),
+        other => Ok(other),
     }
+}
 
-    Ok(())
+/// Exchange `DaemonHello` with axdaemon, fail if it speaks an incompatible protocol.
+fn handshake(stream: &mut (impl Read + Write + Unpin)) -> AxResult<DaemonHello> {
+    let hello = bincode::serialize(&DaemonHello::default()).map_err(|err| {
+        ax_err_type!(
+            InvalidData,
+            format!("failed to serialize DaemonHello {err:?}")
+        )
+    })?;
+    tcp_send(stream, &hello)
+        .map_err(|err| ax_err_type!(BadState, format!("failed to send DaemonHello {err:?}")))?;
+
+    let daemon_hello = receive_message::<DaemonHello>(stream, "DaemonHello")?.ok_or_else(|| {
+        ax_err_type!(
+            Unsupported,
+            "server disconnected during handshake, axdaemon may be too old"
+        )
+    })?;
+    daemon_hello.check_compatible().map_err(|err| {
+        ax_err_type!(
+            Unsupported,
+            format!(
+                "{} is incompatible with axcli: {err}",
+                "AxDaemon".bold().green()
+            )
+        )
+    })?;
+    debug!(
+        "{} protocol version {}, features {:?}",
+        "AxDaemon".bold().green(),
+        daemon_hello.version,
+        daemon_hello.features
+    );
+    Ok(daemon_hello)
 }
 
-fn receive_reply(connection: &mut TcpStream) -> AxResult<Option<DaemonReply>> {
+fn receive_message<T: DeserializeOwned>(
+    connection: &mut (impl Read + Unpin),
+    name: &str,
+) -> AxResult<Option<T>> {
     let raw = match tcp_receive(connection) {
         Ok(raw) => raw,
         Err(err) => match err.kind() {
@@ -88,9 +207,7 @@ fn receive_reply(connection: &mut TcpStream) -> AxResult<Option<DaemonReply>> {
             other => {
                 return ax_err!(
                     BadState,
-                    format!(
-                        "unexpected I/O error (kind {other:?}) while trying to receive DaemonReply"
-                    )
+                    format!("unexpected I/O error (kind {other:?}) while trying to receive {name}")
                 )
             }
         },
@@ -99,7 +216,7 @@ fn receive_reply(connection: &mut TcpStream) -> AxResult<Option<DaemonReply>> {
         .map_err(|err| {
             ax_err_type!(
                 InvalidData,
-                format!("failed to deserialize DaemonReply, {err:?}")
+                format!("failed to deserialize {name}, {err:?}")
             )
         })
         .map(Some)
@@ -119,7 +236,35 @@ fn tcp_receive(connection: &mut (impl Read + Unpin)) -> std::io::Result<Vec<u8>>
         connection.read_exact(&mut raw)?;
         u64::from_le_bytes(raw) as usize
     };
+    if reply_len > MAX_FRAME_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("frame of {reply_len} Bytes exceeds limit {MAX_FRAME_SIZE} Bytes"),
+        ));
+    }
     let mut reply = vec![0; reply_len];
     connection.read_exact(&mut reply)?;
     Ok(reply)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::FakeDaemon;
+
+    #[test]
+    fn requests_need_daemon_features() {
+        let daemon = FakeDaemon::start();
+        daemon.set_features(&["vm-lifecycle"]);
+        assert_eq!(list_vm_on_daemon().unwrap_err(), AxError::Unsupported);
+        assert_eq!(
+            check_disks_unused_on_daemon(vec![PathBuf::from("/disk.img")]),
+            Err(AxError::Unsupported)
+        );
+        // Requests are not sent to axdaemon not supporting them.
+        assert!(daemon.take_requests().is_empty());
+
+        unregister_vm_from_daemon(1).unwrap();
+        assert_eq!(daemon.take_requests(), ["UnregisterVM { vmid: 1 }"]);
+    }
+}
@@ -0,0 +1,144 @@
+//! SHA-256 digests of image files referenced by VM config.
+
+use std::fs::{read_to_string, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+use axerrno::{ax_err, ax_err_type, AxError, AxResult};
+use sha2::{Digest, Sha256};
+
+use crate::cfg::VmCreateCliArg;
+
+fn to_hex(hasher: Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Compute SHA-256 of file at `path` in lowercase hex, the file is read in chunks.
+pub fn sha256_file(path: impl AsRef<Path>) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+    }
+    Ok(to_hex(hasher))
+}
+
+/// Compute SHA-256 of `data` in lowercase hex.
+pub fn sha256(data: &[u8]) -> String {
+    to_hex(Sha256::new_with_prefix(data))
+}
+
+/// Check `data` of image at `path` against `expected` digest.
+///
+/// Images loaded by axcli are checked as they are mapped, so that the bytes checked are
+/// the ones passed to hypervisor.
+pub fn check_image_data(path: &str, data: &[u8], expected: &str) -> Result<(), String> {
+    let actual = sha256(data);
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(format!("{path:?} has SHA-256 {actual}, expect {expected}"));
+    }
+    Ok(())
+}
+
+fn image_sha256(key: &str, path: &str) -> AxResult<String> {
+    sha256_file(path).map_err(|err| {
+        warn!("Failed to hash {path:?} for `{key}`, {err:?}");
+        AxError::InvalidInput
+    })
+}
+
+/// Verify `images` of digest keys and expected digests from `VmCreateCliArg`, images
+/// without digests are not checked.
+pub fn verify_images(images: Vec<(String, &str, Option<&str>)>) -> AxResult {
+    let mut mismatches = 0;
+    for (key, path, expected) in images {
+        let Some(expected) = expected else {
+            continue;
+        };
+        let actual = image_sha256(&key, path)?;
+        if actual.eq_ignore_ascii_case(expected) {
+            debug!("{path:?} matches `{key}`");
+        } else {
+            println!("  * `{key}`: {path:?} has SHA-256 {actual}, expect {expected}");
+            mismatches += 1;
+        }
+    }
+    if mismatches > 0 {
+        return ax_err!(
+            InvalidData,
+            format!("{mismatches} images mismatch their SHA-256 digests")
+        );
+    }
+    Ok(())
+}
+
+/// Compute digests of images in config file at `config_path`, and write them into it.
+///
+/// Other content of the file, including comments, is kept as it is.
+pub fn fill_image_digests(config_path: &Path, vm_arg: &VmCreateCliArg) -> AxResult {
+    let content = read_to_string(config_path).map_err(|err| {
+        warn!("Failed to get VM config file {err:?}");
+        AxError::InvalidInput
+    })?;
+    let mut doc: toml_edit::DocumentMut = content.parse().map_err(|err| {
+        ax_err_type!(
+            InvalidInput,
+            format!("failed to parse VM config file {err}")
+        )
+    })?;
+
+    for (key, path, expected) in vm_arg.image_digests() {
+        let actual = image_sha256(&key, path)?;
+        match expected {
+            Some(expected) if actual.eq_ignore_ascii_case(expected) => {
+                println!("  * `{key}`: {path:?} unchanged");
+            }
+            Some(_) => println!("  * `{key}`: {path:?} updated to {actual}"),
+            None => println!("  * `{key}`: {path:?} is {actual}"),
+        }
+        let item = digest_item(&mut doc, &key).ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("failed to locate `{key}` in VM config file")
+            )
+        })?;
+        *item = toml_edit::value(actual);
+    }
+
+    std::fs::write(config_path, doc.to_string()).map_err(|err| {
+        warn!("Failed to write VM config file {err:?}");
+        AxError::PermissionDenied
+    })
+}
+
+/// Locate item of dotted `key` like `disks[1].sha256`, the last part is inserted if missing.
+fn digest_item<'a>(
+    doc: &'a mut toml_edit::DocumentMut,
+    key: &str,
+) -> Option<&'a mut toml_edit::Item> {
+    let (parents, last) = match key.rsplit_once('.') {
+        Some((parents, last)) => (Some(parents), last),
+        None => (None, key),
+    };
+    let mut item = doc.as_item_mut();
+    for part in parents.into_iter().flat_map(|parents| parents.split('.')) {
+        item = match part.strip_suffix(']').and_then(|part| part.split_once('[')) {
+            Some((name, index)) => item.get_mut(name)?.get_mut(index.parse::<usize>().ok()?)?,
+            None => item.get_mut(part)?,
+        };
+    }
+    Some(
+        item.as_table_like_mut()?
+            .entry(last)
+            .or_insert(toml_edit::value("")),
+    )
+}
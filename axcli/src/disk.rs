@@ -0,0 +1,154 @@
+//! Copy-on-write disk overlays, created and updated by axcli with permissions of its user.
+//!
+//! Axdaemon is only asked whether images are open for VMs, it never writes images on
+//! behalf of axcli.
+
+use std::path::{Path, PathBuf};
+
+use axdisk::overlay;
+use axerrno::{ax_err_type, AxResult};
+
+use crate::cli::{DiskCommitArgs, DiskCreateArgs, DiskRebaseArgs};
+
+/// Paths are stored in overlays and sent to axdaemon, neither shares our working directory.
+fn absolute(path: &Path) -> AxResult<PathBuf> {
+    std::path::absolute(path)
+        .map_err(|err| ax_err_type!(InvalidInput, format!("invalid path {path:?}, {err}")))
+}
+
+pub fn axdisk_create(arg: DiskCreateArgs) -> AxResult {
+    let path = absolute(&arg.path)?;
+    let base = absolute(&arg.base)?;
+    println!(
+        "Create overlay {} on {} base {}",
+        path.display(),
+        arg.base_format,
+        base.display()
+    );
+    overlay::create(&path, &base, arg.base_format)
+}
+
+/// Base image is written as well, neither may be open for a VM.
+pub fn axdisk_commit(arg: DiskCommitArgs) -> AxResult {
+    let path = absolute(&arg.path)?;
+    let base = overlay::base_of(&path)?;
+    crate::daemon::check_disks_unused_on_daemon(vec![path.clone(), base.clone()])?;
+    println!("Commit overlay {} into {}", path.display(), base.display());
+    let merged = overlay::commit(&path)?;
+    println!("{merged} chunks merged");
+    Ok(())
+}
+
+pub fn axdisk_rebase(arg: DiskRebaseArgs) -> AxResult {
+    let path = absolute(&arg.path)?;
+    let base = absolute(&arg.base)?;
+    crate::daemon::check_disks_unused_on_daemon(vec![path.clone()])?;
+    println!(
+        "Rebase overlay {} on {} base {}",
+        path.display(),
+        arg.base_format,
+        base.display()
+    );
+    let copied = overlay::rebase(&path, &base, arg.base_format, !arg.unsafe_)?;
+    println!("{copied} chunks copied from old base");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axdaemon_request::{DiskCfg, DiskFormat};
+    use axdisk::block::{self, BlockBackend};
+    use axdisk::test_utils::{disk_cfg, TempDir};
+    use axerrno::AxError;
+
+    use super::*;
+    use crate::test_utils::FakeDaemon;
+
+    const SIZE: usize = 1 << 20;
+
+    fn open(path: &Path, format: DiskFormat) -> Box<dyn BlockBackend> {
+        let cfg = DiskCfg {
+            format,
+            ..disk_cfg(path)
+        };
+        block::open_block_backend(&cfg).unwrap()
+    }
+
+    /// Raw base image of zero with an overlay on it, the overlay has `data` at sector 8.
+    fn overlay_with(dir: &TempDir, data: &[u8]) -> (PathBuf, PathBuf) {
+        let base = dir.file("base.img", &[0; SIZE]);
+        let path = dir.path().join("disk.overlay");
+        axdisk_create(DiskCreateArgs {
+            path: path.clone(),
+            base: base.clone(),
+            base_format: DiskFormat::Raw,
+        })
+        .unwrap();
+        let mut overlay = open(&path, DiskFormat::Overlay);
+        overlay.write_sectors(8, data).unwrap();
+        overlay.flush().unwrap();
+        (path, base)
+    }
+
+    #[test]
+    fn commit_checks_disks_with_daemon() {
+        let daemon = FakeDaemon::start();
+        let dir = TempDir::new();
+        let data = [0x5a; 1024];
+        let (path, base) = overlay_with(&dir, &data);
+        let commit = || axdisk_commit(DiskCommitArgs { path: path.clone() });
+
+        daemon.set_disks_in_use(&[&base]);
+        assert_eq!(commit(), Err(AxError::BadState));
+        assert_eq!(std::fs::read(&base).unwrap(), [0; SIZE]);
+        assert_eq!(
+            daemon.take_requests(),
+            [format!(
+                "CheckDisksUnused {{ paths: [{path:?}, {base:?}] }}"
+            )]
+        );
+
+        daemon.set_disks_in_use(&[]);
+        commit().unwrap();
+        assert_eq!(std::fs::read(&base).unwrap()[8 * 512..][..1024], data);
+        assert_eq!(daemon.take_requests().len(), 1);
+    }
+
+    #[test]
+    fn rebase_checks_overlay_with_daemon() {
+        let daemon = FakeDaemon::start();
+        let dir = TempDir::new();
+        let (path, _) = overlay_with(&dir, &[0x5a; 512]);
+        let new_base = dir.file("new.img", &[0xff; SIZE]);
+        let rebase = || {
+            axdisk_rebase(DiskRebaseArgs {
+                path: path.clone(),
+                base: new_base.clone(),
+                base_format: DiskFormat::Raw,
+                unsafe_: false,
+            })
+        };
+
+        daemon.set_disks_in_use(&[&path]);
+        assert_eq!(rebase(), Err(AxError::BadState));
+        assert_eq!(
+            overlay::base_of(&path).unwrap(),
+            dir.path().join("base.img")
+        );
+        assert_eq!(
+            daemon.take_requests(),
+            [format!("CheckDisksUnused {{ paths: [{path:?}] }}")]
+        );
+
+        daemon.set_disks_in_use(&[]);
+        rebase().unwrap();
+        assert_eq!(overlay::base_of(&path).unwrap(), new_base);
+        let mut buf = vec![0xff; SIZE];
+        open(&path, DiskFormat::Overlay)
+            .read_sectors(0, &mut buf)
+            .unwrap();
+        assert_eq!(buf[..8 * 512], [0; 8 * 512]);
+        assert_eq!(buf[8 * 512..9 * 512], [0x5a; 512]);
+        assert_eq!(daemon.take_requests().len(), 1);
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::BTreeSet;
+use std::fs::read_to_string;
+use std::fs::File;
+use std::io::prelude::*;
+
+use colored::Colorize;
+
+use axerrno::{ax_err, AxError, AxResult};
+
+use crate::backend::HypervisorBackend;
+use crate::cfg::HvEnableCliArg;
+use crate::cli::{HvDisableArgs, HvEnableArgs};
+
+pub fn axhv_enable(backend: &mut dyn HypervisorBackend, arg: HvEnableArgs) -> AxResult {
+    let config_content = read_to_string(arg.config_path).map_err(|err| {
+        warn!("Failed to get hypervisor config file {err:?}");
+        AxError::InvalidInput
+    })?;
+
+    let hv_arg: HvEnableCliArg = toml::from_str(config_content.as_str()).map_err(|err| {
+        warn!("Failed to deserialize hypervisor config file {err:?}");
+        AxError::InvalidInput
+    })?;
+
+    debug!("get hv_arg {:#x?}", hv_arg);
+
+    let mut hv_img = File::open(hv_arg.image_path.clone()).map_err(|err| {
+        warn!(
+            "Failed to open hypervisor image file on {:?}, {:?}",
+            hv_arg.image_path, err
+        );
+        AxError::NotFound
+    })?;
+
+    let mut hv_img_buffer = Vec::new();
+    hv_img.read_to_end(&mut hv_img_buffer).map_err(|err| {
+        warn!(
+            "Failed to read hypervisor image file on {:?}, {:?}",
+            hv_arg.image_path, err
+        );
+        AxError::Io
+    })?;
+
+    if hv_img_buffer.is_empty() {
+        return ax_err!(
+            InvalidData,
+            format!("hypervisor image {:?} is empty", hv_arg.image_path)
+        );
+    }
+
+    backend.enable(&hv_img_buffer, &config_content)?;
+
+    info!("{} enabled", "ArceOS Hypervisor".bold().green());
+
+    Ok(())
+}
+
+pub fn axhv_disable(backend: &mut dyn HypervisorBackend, arg: HvDisableArgs) -> AxResult {
+    let mut vmids = backend
+        .list_vm()?
+        .iter()
+        .map(|info| info.id)
+        .collect::<BTreeSet<_>>();
+    // VMs whose devices are still served by axdaemon, e.g. left by a failed destroy.
+    match crate::daemon::list_vm_on_daemon() {
+        Ok(vm_disks) => vmids.extend(vm_disks.into_keys()),
+        Err(err) => warn!(
+            "Failed to query VMs from {}: {err:?}, only VMs in hypervisor are checked",
+            "AxDaemon".bold().green()
+        ),
+    }
+
+    if !vmids.is_empty() {
+        let vmids = vmids
+            .iter()
+            .map(|vmid| vmid.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if !arg.force {
+            return ax_err!(
+                ResourceBusy,
+                format!("VM [{vmids}] still registered, shut down them first or use `--force`")
+            );
+        }
+        warn!("Force disabling hypervisor with VM [{vmids}] still registered");
+    }
+
+    backend.disable()?;
+
+    info!("{} disabled", "ArceOS Hypervisor".bold().green());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use axdisk::test_utils::disk_cfg;
+
+    use super::*;
+    use crate::backend::MockBackend;
+    use crate::test_utils::FakeDaemon;
+
+    #[test]
+    fn disable_checks_vms_on_daemon() {
+        let _daemon = FakeDaemon::start();
+        // VM left in axdaemon only.
+        crate::daemon::register_vm_to_daemon(3, vec![disk_cfg(Path::new("disk.img"))]).unwrap();
+        let mut backend = MockBackend::default();
+        backend.state.enabled = true;
+
+        let disable =
+            |backend: &mut MockBackend, force| axhv_disable(backend, HvDisableArgs { force });
+        assert_eq!(disable(&mut backend, false), Err(AxError::ResourceBusy));
+        assert!(backend.state.enabled);
+        disable(&mut backend, true).unwrap();
+        assert!(!backend.state.enabled);
+    }
+}
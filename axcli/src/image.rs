@@ -0,0 +1,641 @@
+//! Kernel image formats understood by axcli.
+//!
+//! ELF and multiboot images are split into segments referring to the image file, with
+//! their load address and entry point derived from the image headers. The driver takes the
+//! first segment at `kernel_load_addr` when VM is created, others are loaded separately,
+//! see `HypervisorBackend::create_vm`.
+
+use core::fmt;
+use core::ops::Range;
+
+use crate::mapped::ImageData;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const PT_LOAD: u32 = 1;
+
+/// "HdrS" at `BZIMAGE_HEADER_MAGIC_OFFSET`, see Linux `Documentation/arch/x86/boot.rst`.
+const BZIMAGE_HEADER_MAGIC: u32 = 0x5372_6448;
+const BZIMAGE_HEADER_MAGIC_OFFSET: usize = 0x202;
+const BZIMAGE_BOOT_FLAG: u16 = 0xaa55;
+const BZIMAGE_BOOT_FLAG_OFFSET: usize = 0x1fe;
+const BZIMAGE_SETUP_SECTS_OFFSET: usize = 0x1f1;
+const BZIMAGE_VERSION_OFFSET: usize = 0x206;
+const BZIMAGE_CODE32_START_OFFSET: usize = 0x214;
+const BZIMAGE_XLOADFLAGS_OFFSET: usize = 0x236;
+const BZIMAGE_PREF_ADDRESS_OFFSET: usize = 0x258;
+const BZIMAGE_INIT_SIZE_OFFSET: usize = 0x260;
+/// Kernel has the legacy 64-bit entry point at `0x200` from its load address.
+const XLF_KERNEL_64: u16 = 1 << 0;
+/// Oldest boot protocol supported, which has `cmd_line_ptr` above 1 MiB.
+pub const BZIMAGE_MIN_VERSION: u16 = 0x0206;
+
+const MULTIBOOT_MAGIC: u32 = 0x1bad_b002;
+/// Multiboot header is 4-byte aligned within the first 8 KiB.
+const MULTIBOOT_SEARCH: usize = 8192;
+/// Address fields of multiboot header are valid.
+const MULTIBOOT_AOUT_KLUDGE: u32 = 1 << 16;
+
+const MULTIBOOT2_MAGIC: u32 = 0xe852_50d6;
+/// Multiboot2 header is 8-byte aligned within the first 32 KiB.
+const MULTIBOOT2_SEARCH: usize = 32768;
+const MULTIBOOT2_TAG_END: u16 = 0;
+const MULTIBOOT2_TAG_ADDRESS: u16 = 2;
+const MULTIBOOT2_TAG_ENTRY: u16 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelFormat {
+    /// Raw binary, loaded at `kernel_load_addr` as is.
+    Flat,
+    Elf,
+    Multiboot,
+    Multiboot2,
+    /// Linux bzImage, booted through Linux boot protocol, see `crate::linux`.
+    BzImage,
+}
+
+impl fmt::Display for KernelFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format = match self {
+            Self::Flat => "flat binary",
+            Self::Elf => "ELF",
+            Self::Multiboot => "multiboot",
+            Self::Multiboot2 => "multiboot2",
+            Self::BzImage => "Linux bzImage",
+        };
+        f.pad(format)
+    }
+}
+
+/// A part of kernel image to be loaded, zeroed after its content up to `mem_size` Bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelSegment {
+    /// Offset of the segment in guest memory from `load_addr`.
+    pub load_offset: usize,
+    /// Content of the segment in `KernelImage::data`.
+    pub range: Range<usize>,
+    pub mem_size: usize,
+}
+
+/// Kernel image split into segments for the driver.
+#[derive(Debug)]
+pub struct KernelImage {
+    pub format: KernelFormat,
+    /// Image file without the setup sectors of bzImage, segments refer to ranges of it.
+    pub data: ImageData,
+    /// Sorted by `load_offset`, the first one is at `load_addr`.
+    pub segments: Vec<KernelSegment>,
+    /// Guest physical load address from image headers, `None` for flat binary.
+    pub load_addr: Option<usize>,
+    /// Guest physical entry point from image headers.
+    pub entry: Option<usize>,
+    /// Memory required from `load_addr`, may exceed the last segment for bzImage.
+    pub mem_size: usize,
+    /// Real-mode setup sectors of bzImage, containing its setup header.
+    pub setup: Option<Vec<u8>>,
+}
+
+impl KernelImage {
+    /// Detect format of `raw` and split it into segments.
+    ///
+    /// ELF is preferred over multiboot headers, multiboot headers without address fields
+    /// are treated as flat binary.
+    pub fn parse(raw: ImageData) -> Result<Self, String> {
+        if raw.starts_with(ELF_MAGIC) {
+            return parse_elf(raw);
+        }
+        if is_bzimage(&raw) {
+            return parse_bzimage(raw);
+        }
+        if let Some((offset, addresses, entry)) = find_multiboot2(&raw)? {
+            return load_multiboot_addresses(
+                raw,
+                KernelFormat::Multiboot2,
+                offset,
+                addresses,
+                entry,
+            );
+        }
+        if let Some((offset, addresses, entry)) = find_multiboot(&raw)? {
+            return load_multiboot_addresses(
+                raw,
+                KernelFormat::Multiboot,
+                offset,
+                addresses,
+                entry,
+            );
+        }
+        Ok(Self::whole(KernelFormat::Flat, raw, None, None, 0))
+    }
+
+    /// Image loaded as one segment, which reserves `mem_size` Bytes at least.
+    fn whole(
+        format: KernelFormat,
+        data: ImageData,
+        load_addr: Option<usize>,
+        entry: Option<usize>,
+        mem_size: usize,
+    ) -> Self {
+        Self {
+            format,
+            segments: vec![KernelSegment {
+                load_offset: 0,
+                range: 0..data.len(),
+                mem_size: data.len(),
+            }],
+            mem_size: data.len().max(mem_size),
+            data,
+            load_addr,
+            entry,
+            setup: None,
+        }
+    }
+
+    pub fn segment_data(&self, segment: &KernelSegment) -> &[u8] {
+        &self.data[segment.range.clone()]
+    }
+
+    /// Bytes of image file to be loaded.
+    pub fn file_size(&self) -> usize {
+        self.segments.iter().map(|seg| seg.range.len()).sum()
+    }
+}
+
+/// A segment to be loaded: `file_size` Bytes from `offset` of file to `paddr`,
+/// then zeroed up to `mem_size` Bytes.
+struct Segment {
+    offset: usize,
+    paddr: usize,
+    file_size: usize,
+    mem_size: usize,
+}
+
+/// Check segments against the file of `file_len` Bytes, then sort them by address.
+///
+/// Return the lowest `paddr`, the span of all segments, and segments relative to it.
+fn place_segments(
+    file_len: usize,
+    mut segments: Vec<Segment>,
+) -> Result<(usize, usize, Vec<KernelSegment>), String> {
+    segments.sort_by_key(|seg| seg.paddr);
+    let start = segments.first().ok_or("no loadable segment")?.paddr;
+    let mut end = start;
+    let mut placed = Vec::with_capacity(segments.len());
+    for seg in segments {
+        if seg.file_size > seg.mem_size {
+            return Err(format!(
+                "segment at {:#x} has file size {:#x} larger than memory size {:#x}",
+                seg.paddr, seg.file_size, seg.mem_size
+            ));
+        }
+        if seg.paddr < end {
+            return Err(format!(
+                "segment at {:#x} overlaps with the previous one ending at {end:#x}",
+                seg.paddr
+            ));
+        }
+        let range = seg.offset..seg.offset.saturating_add(seg.file_size);
+        if range.end > file_len {
+            return Err(format!("segment at {:#x} exceeds the file", seg.paddr));
+        }
+        end = seg
+            .paddr
+            .checked_add(seg.mem_size)
+            .ok_or("segment overflows address space")?;
+        placed.push(KernelSegment {
+            load_offset: seg.paddr - start,
+            range,
+            mem_size: seg.mem_size,
+        });
+    }
+    Ok((start, end - start, placed))
+}
+
+pub(crate) fn read_u16(raw: &[u8], offset: usize) -> Result<u16, String> {
+    offset
+        .checked_add(2)
+        .and_then(|end| raw.get(offset..end))
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| format!("image truncated at {offset:#x}"))
+}
+
+pub(crate) fn read_u32(raw: &[u8], offset: usize) -> Result<u32, String> {
+    offset
+        .checked_add(4)
+        .and_then(|end| raw.get(offset..end))
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| format!("image truncated at {offset:#x}"))
+}
+
+pub(crate) fn read_u64(raw: &[u8], offset: usize) -> Result<u64, String> {
+    offset
+        .checked_add(8)
+        .and_then(|end| raw.get(offset..end))
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| format!("image truncated at {offset:#x}"))
+}
+
+fn parse_elf(image: ImageData) -> Result<KernelImage, String> {
+    let raw: &[u8] = &image;
+    let is_64 = match raw.get(4) {
+        Some(1) => false,
+        Some(2) => true,
+        class => return Err(format!("unsupported ELF class {class:?}")),
+    };
+    if raw.get(5) != Some(&1) {
+        return Err(String::from("only little-endian ELF is supported"));
+    }
+
+    // Read a word of ELF class size.
+    let read_word = |offset: usize| -> Result<usize, String> {
+        if is_64 {
+            read_u64(raw, offset).map(|v| v as usize)
+        } else {
+            read_u32(raw, offset).map(|v| v as usize)
+        }
+    };
+
+    let (entry, phoff, phentsize, phnum) = if is_64 {
+        (
+            read_word(0x18)?,
+            read_word(0x20)?,
+            read_u16(raw, 0x36)?,
+            read_u16(raw, 0x38)?,
+        )
+    } else {
+        (
+            read_word(0x18)?,
+            read_word(0x1c)?,
+            read_u16(raw, 0x2a)?,
+            read_u16(raw, 0x2c)?,
+        )
+    };
+
+    let mut segments = Vec::new();
+    let mut entry_paddr = None;
+    for i in 0..phnum as usize {
+        let ph = phoff.saturating_add(i * phentsize as usize);
+        if read_u32(raw, ph)? != PT_LOAD {
+            continue;
+        }
+        let (offset, vaddr, paddr, file_size, mem_size) = if is_64 {
+            (
+                read_word(ph + 0x08)?,
+                read_word(ph + 0x10)?,
+                read_word(ph + 0x18)?,
+                read_word(ph + 0x20)?,
+                read_word(ph + 0x28)?,
+            )
+        } else {
+            (
+                read_word(ph + 0x04)?,
+                read_word(ph + 0x08)?,
+                read_word(ph + 0x0c)?,
+                read_word(ph + 0x10)?,
+                read_word(ph + 0x14)?,
+            )
+        };
+        if mem_size == 0 {
+            continue;
+        }
+        // `e_entry` is virtual, translate it through the segment containing it.
+        if (vaddr..vaddr.saturating_add(mem_size)).contains(&entry) {
+            entry_paddr = Some(entry - vaddr + paddr);
+        }
+        segments.push(Segment {
+            offset,
+            paddr,
+            file_size,
+            mem_size,
+        });
+    }
+
+    let (load_addr, mem_size, segments) =
+        place_segments(raw.len(), segments).map_err(|err| format!("bad ELF, {err}"))?;
+    Ok(KernelImage {
+        format: KernelFormat::Elf,
+        data: image,
+        segments,
+        load_addr: Some(load_addr),
+        entry: Some(entry_paddr.unwrap_or(entry)),
+        mem_size,
+        setup: None,
+    })
+}
+
+fn is_bzimage(raw: &[u8]) -> bool {
+    read_u16(raw, BZIMAGE_BOOT_FLAG_OFFSET) == Ok(BZIMAGE_BOOT_FLAG)
+        && read_u32(raw, BZIMAGE_HEADER_MAGIC_OFFSET) == Ok(BZIMAGE_HEADER_MAGIC)
+}
+
+/// Split bzImage into real-mode setup sectors and protected-mode kernel.
+///
+/// The kernel is loaded at its preferred address, and entered through its 64-bit entry
+/// point if it has one.
+fn parse_bzimage(raw: ImageData) -> Result<KernelImage, String> {
+    let version = read_u16(&raw, BZIMAGE_VERSION_OFFSET)?;
+    if version < BZIMAGE_MIN_VERSION {
+        return Err(format!(
+            "boot protocol {}.{:02} is too old, expect {}.{:02} or newer",
+            version >> 8,
+            version & 0xff,
+            BZIMAGE_MIN_VERSION >> 8,
+            BZIMAGE_MIN_VERSION & 0xff
+        ));
+    }
+
+    let setup_sects = match raw[BZIMAGE_SETUP_SECTS_OFFSET] {
+        // 0 means 4 for compatibility.
+        0 => 4,
+        sects => sects as usize,
+    };
+    let setup_size = (setup_sects + 1) * 512;
+    if setup_size >= raw.len() {
+        return Err(format!(
+            "setup sectors of {setup_size:#x} Bytes exceed the file"
+        ));
+    }
+
+    // `pref_address` is introduced by boot protocol 2.10.
+    let load_addr = if version >= 0x020a {
+        read_u64(&raw, BZIMAGE_PREF_ADDRESS_OFFSET)? as usize
+    } else {
+        read_u32(&raw, BZIMAGE_CODE32_START_OFFSET)? as usize
+    };
+    // `xloadflags` is introduced by boot protocol 2.12.
+    let has_entry_64 =
+        version >= 0x020c && read_u16(&raw, BZIMAGE_XLOADFLAGS_OFFSET)? & XLF_KERNEL_64 != 0;
+    let entry = if has_entry_64 {
+        load_addr + 0x200
+    } else {
+        load_addr
+    };
+
+    let init_size = if version >= 0x020a {
+        read_u32(&raw, BZIMAGE_INIT_SIZE_OFFSET)? as usize
+    } else {
+        0
+    };
+    let setup = raw[..setup_size].to_vec();
+    Ok(KernelImage {
+        setup: Some(setup),
+        ..KernelImage::whole(
+            KernelFormat::BzImage,
+            raw.skip(setup_size),
+            Some(load_addr),
+            Some(entry),
+            init_size,
+        )
+    })
+}
+
+/// Load image according to address fields of a multiboot header at `header_offset`.
+fn load_multiboot_addresses(
+    image: ImageData,
+    format: KernelFormat,
+    header_offset: usize,
+    [header_addr, load_addr, load_end_addr, bss_end_addr]: [u32; 4],
+    entry: Option<u32>,
+) -> Result<KernelImage, String> {
+    let raw: &[u8] = &image;
+    let (header_addr, load_end_addr, bss_end_addr) = (
+        header_addr as usize,
+        load_end_addr as usize,
+        bss_end_addr as usize,
+    );
+    // Multiboot2 uses -1 as `load_addr` for the beginning of the file.
+    let load_addr = if load_addr == u32::MAX {
+        header_addr
+            .checked_sub(header_offset)
+            .ok_or("bad header_addr")?
+    } else {
+        load_addr as usize
+    };
+    let offset = header_offset
+        .checked_sub(
+            header_addr
+                .checked_sub(load_addr)
+                .ok_or("load_addr > header_addr")?,
+        )
+        .ok_or("load_addr is before the beginning of file")?;
+    let file_size = if load_end_addr == 0 {
+        raw.len()
+            .checked_sub(offset)
+            .ok_or("load_addr exceeds the file")?
+    } else {
+        load_end_addr
+            .checked_sub(load_addr)
+            .ok_or("load_end_addr < load_addr")?
+    };
+    let mem_size = if bss_end_addr == 0 {
+        file_size
+    } else {
+        bss_end_addr
+            .checked_sub(load_addr)
+            .ok_or("bss_end_addr < load_addr")?
+    };
+
+    let segment = Segment {
+        offset,
+        paddr: load_addr,
+        file_size,
+        mem_size,
+    };
+    let (load_addr, mem_size, segments) = place_segments(raw.len(), vec![segment])
+        .map_err(|err| format!("bad {format} header, {err}"))?;
+    Ok(KernelImage {
+        format,
+        data: image,
+        segments,
+        load_addr: Some(load_addr),
+        entry: entry.map(|entry| entry as usize),
+        mem_size,
+        setup: None,
+    })
+}
+
+/// Offset, address fields and entry of multiboot header.
+type MultibootHeader = (usize, [u32; 4], Option<u32>);
+
+fn find_multiboot(raw: &[u8]) -> Result<Option<MultibootHeader>, String> {
+    let search_end = raw.len().min(MULTIBOOT_SEARCH);
+    for offset in (0..search_end.saturating_sub(11)).step_by(4) {
+        let magic = read_u32(raw, offset)?;
+        let flags = read_u32(raw, offset + 4)?;
+        let checksum = read_u32(raw, offset + 8)?;
+        if magic != MULTIBOOT_MAGIC || magic.wrapping_add(flags).wrapping_add(checksum) != 0 {
+            continue;
+        }
+        if flags & MULTIBOOT_AOUT_KLUDGE == 0 {
+            debug!("multiboot header without address fields at {offset:#x}");
+            return Ok(None);
+        }
+        let addresses = [
+            read_u32(raw, offset + 12)?,
+            read_u32(raw, offset + 16)?,
+            read_u32(raw, offset + 20)?,
+            read_u32(raw, offset + 24)?,
+        ];
+        let entry = read_u32(raw, offset + 28)?;
+        return Ok(Some((offset, addresses, Some(entry))));
+    }
+    Ok(None)
+}
+
+fn find_multiboot2(raw: &[u8]) -> Result<Option<MultibootHeader>, String> {
+    let search_end = raw.len().min(MULTIBOOT2_SEARCH);
+    for offset in (0..search_end.saturating_sub(15)).step_by(8) {
+        let magic = read_u32(raw, offset)?;
+        let arch = read_u32(raw, offset + 4)?;
+        let header_len = read_u32(raw, offset + 8)?;
+        let checksum = read_u32(raw, offset + 12)?;
+        if magic != MULTIBOOT2_MAGIC
+            || magic
+                .wrapping_add(arch)
+                .wrapping_add(header_len)
+                .wrapping_add(checksum)
+                != 0
+        {
+            continue;
+        }
+
+        let header_end = offset + header_len as usize;
+        let mut addresses = None;
+        let mut entry = None;
+        let mut tag = offset + 16;
+        while tag + 8 <= header_end {
+            let tag_type = read_u16(raw, tag)?;
+            let tag_size = read_u32(raw, tag + 4)? as usize;
+            match tag_type {
+                MULTIBOOT2_TAG_END => break,
+                MULTIBOOT2_TAG_ADDRESS => {
+                    addresses = Some([
+                        read_u32(raw, tag + 8)?,
+                        read_u32(raw, tag + 12)?,
+                        read_u32(raw, tag + 16)?,
+                        read_u32(raw, tag + 20)?,
+                    ])
+                }
+                MULTIBOOT2_TAG_ENTRY => entry = Some(read_u32(raw, tag + 8)?),
+                _ => {}
+            }
+            if tag_size < 8 {
+                return Err(format!("bad multiboot2 tag size {tag_size} at {tag:#x}"));
+            }
+            // Tags are 8-byte aligned.
+            tag += tag_size.next_multiple_of(8);
+        }
+
+        let Some(addresses) = addresses else {
+            debug!("multiboot2 header without address tag at {offset:#x}");
+            return Ok(None);
+        };
+        return Ok(Some((offset, addresses, entry)));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ELF64 image of `len` Bytes with PT_LOAD segments of
+    /// `(offset, vaddr, paddr, file_size, mem_size)`.
+    fn elf64(entry: u64, segments: &[(u64, u64, u64, u64, u64)], len: usize) -> Vec<u8> {
+        let mut raw = vec![0; len];
+        raw[..4].copy_from_slice(ELF_MAGIC);
+        raw[4] = 2;
+        raw[5] = 1;
+        raw[0x18..0x20].copy_from_slice(&entry.to_le_bytes());
+        raw[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+        raw[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        raw[0x38..0x3a].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+        for (i, &(offset, vaddr, paddr, file_size, mem_size)) in segments.iter().enumerate() {
+            let ph = 0x40 + i * 56;
+            raw[ph..ph + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            for (field, value) in [offset, vaddr, paddr, file_size, mem_size]
+                .into_iter()
+                .enumerate()
+            {
+                let at = ph + 8 + field * 8;
+                raw[at..at + 8].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+        raw
+    }
+
+    #[test]
+    fn elf_segments_refer_to_file() {
+        const KERNEL_BASE: u64 = 0xffff_8000_0000_0000;
+        let mut raw = elf64(
+            KERNEL_BASE + 0x8020_0010,
+            &[
+                // Far away from the first segment, with bss.
+                (0x1000, KERNEL_BASE + 0x9000_0000, 0x9000_0000, 0x10, 0x1000),
+                (0x200, KERNEL_BASE + 0x8020_0000, 0x8020_0000, 0x100, 0x100),
+            ],
+            0x1010,
+        );
+        raw[0x200] = 0xaa;
+        raw[0x1000] = 0xbb;
+        let image = KernelImage::parse(ImageData::Owned(raw)).unwrap();
+
+        assert_eq!(image.format, KernelFormat::Elf);
+        assert_eq!(image.load_addr, Some(0x8020_0000));
+        assert_eq!(image.entry, Some(0x8020_0010));
+        assert_eq!(image.mem_size, 0x0fe0_1000);
+        assert_eq!(
+            image.segments,
+            [
+                KernelSegment {
+                    load_offset: 0,
+                    range: 0x200..0x300,
+                    mem_size: 0x100,
+                },
+                KernelSegment {
+                    load_offset: 0x0fe0_0000,
+                    range: 0x1000..0x1010,
+                    mem_size: 0x1000,
+                },
+            ]
+        );
+        assert_eq!(image.segment_data(&image.segments[0])[0], 0xaa);
+        assert_eq!(image.segment_data(&image.segments[1])[0], 0xbb);
+        assert_eq!(image.file_size(), 0x110);
+    }
+
+    #[test]
+    fn elf_rejects_bad_segments() {
+        let overlapping = elf64(
+            0x8020_0000,
+            &[
+                (0x200, 0x8020_0000, 0x8020_0000, 0x100, 0x1000),
+                (0x300, 0x8020_0800, 0x8020_0800, 0x100, 0x100),
+            ],
+            0x400,
+        );
+        let beyond_file = elf64(
+            0x8020_0000,
+            &[(0x200, 0x8020_0000, 0x8020_0000, 0x1000, 0x1000)],
+            0x400,
+        );
+        let no_segment = elf64(0x8020_0000, &[], 0x400);
+        for raw in [overlapping, beyond_file, no_segment] {
+            assert!(KernelImage::parse(ImageData::Owned(raw)).is_err());
+        }
+    }
+
+    #[test]
+    fn flat_image_is_one_segment() {
+        let image = KernelImage::parse(ImageData::Owned(vec![0x90; 0x1000])).unwrap();
+        assert_eq!(image.format, KernelFormat::Flat);
+        assert_eq!(image.load_addr, None);
+        assert_eq!(
+            image.segments,
+            [KernelSegment {
+                load_offset: 0,
+                range: 0..0x1000,
+                mem_size: 0x1000,
+            }]
+        );
+    }
+}
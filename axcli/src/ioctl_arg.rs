@@ -1,6 +1,61 @@
 use core::ffi::c_void;
 use rustix::ioctl;
 
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct HvEnableIoctlArg {
+    /// User address of hypervisor image file.
+    pub hv_img_ptr: usize,
+    /// Size of hypervisor image file.
+    pub hv_img_size: usize,
+    /// User address which stores the raw TOML config file in String format.
+    pub raw_cfg_file_ptr: usize,
+    /// Size of the raw TOML config file in String format.
+    pub raw_cfg_file_size: usize,
+}
+
+unsafe impl ioctl::Ioctl for HvEnableIoctlArg {
+    type Output = ();
+
+    const OPCODE: ioctl::Opcode = ioctl::Opcode::write::<Self>(0, 0);
+
+    const IS_MUTATING: bool = false;
+
+    fn as_ptr(&mut self) -> *mut c_void {
+        self as *const _ as *mut c_void
+    }
+
+    unsafe fn output_from_ptr(
+        _out: ioctl::IoctlOutput,
+        _extract_output: *mut c_void,
+    ) -> rustix::io::Result<Self::Output> {
+        Ok(())
+    }
+}
+
+/// Disable hypervisor, no argument is needed.
+#[derive(Debug, Default)]
+pub struct HvDisableIoctlArg;
+
+unsafe impl ioctl::Ioctl for HvDisableIoctlArg {
+    type Output = ();
+
+    const OPCODE: ioctl::Opcode = ioctl::Opcode::none::<Self>(0, 1);
+
+    const IS_MUTATING: bool = false;
+
+    fn as_ptr(&mut self) -> *mut c_void {
+        core::ptr::null_mut()
+    }
+
+    unsafe fn output_from_ptr(
+        _out: ioctl::IoctlOutput,
+        _extract_output: *mut c_void,
+    ) -> rustix::io::Result<Self::Output> {
+        Ok(())
+    }
+}
+
 #[derive(Debug, Default)]
 #[repr(C)]
 pub struct VmCreateIoctlArg {
@@ -46,6 +101,41 @@ unsafe impl ioctl::Ioctl for VmCreateIoctlArg {
     }
 }
 
+/// Load a kernel segment into a created VM, for segments other than the one passed by
+/// `VmCreateIoctlArg`.
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct VmLoadSegmentIoctlArg {
+    pub id: usize,
+    /// Guest physical address of the segment.
+    pub gpa: usize,
+    /// User address of segment content.
+    pub img_ptr: usize,
+    /// Size of segment content.
+    pub img_size: usize,
+    /// Memory size of the segment, Bytes after the content are zeroed.
+    pub mem_size: usize,
+}
+
+unsafe impl ioctl::Ioctl for VmLoadSegmentIoctlArg {
+    type Output = ();
+
+    const OPCODE: ioctl::Opcode = ioctl::Opcode::write::<Self>(0, 11);
+
+    const IS_MUTATING: bool = false;
+
+    fn as_ptr(&mut self) -> *mut c_void {
+        self as *const _ as *mut c_void
+    }
+
+    unsafe fn output_from_ptr(
+        _out: ioctl::IoctlOutput,
+        _extract_output: *mut c_void,
+    ) -> rustix::io::Result<Self::Output> {
+        Ok(())
+    }
+}
+
 #[derive(Debug, Default)]
 #[repr(C)]
 pub struct VmBootIoctlArg {
@@ -95,3 +185,89 @@ unsafe impl ioctl::Ioctl for VmShutdownIoctlArg {
         Ok(())
     }
 }
+
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct VmDestroyIoctlArg {
+    pub id: usize,
+}
+
+unsafe impl ioctl::Ioctl for VmDestroyIoctlArg {
+    type Output = ();
+
+    const OPCODE: ioctl::Opcode = ioctl::Opcode::write::<Self>(0, 10);
+
+    const IS_MUTATING: bool = false;
+
+    fn as_ptr(&mut self) -> *mut c_void {
+        self as *const _ as *mut c_void
+    }
+
+    unsafe fn output_from_ptr(
+        _out: ioctl::IoctlOutput,
+        _extract_output: *mut c_void,
+    ) -> rustix::io::Result<Self::Output> {
+        Ok(())
+    }
+}
+
+/// Max length of VM name reported by hypervisor, including the trailing `\0`.
+pub const VM_NAME_MAX_LEN: usize = 32;
+
+/// Information of a single VM reported by hypervisor.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct VmInfoEntry {
+    /// VM id.
+    pub id: usize,
+    /// VM type, see `vm_type` in VM config file.
+    pub vm_type: usize,
+    /// VM cpu mask.
+    pub cpu_set: usize,
+    /// VM state, see `VmState`.
+    pub state: usize,
+    /// VM name in C string format.
+    pub name: [u8; VM_NAME_MAX_LEN],
+}
+
+impl Default for VmInfoEntry {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            vm_type: 0,
+            cpu_set: 0,
+            state: 0,
+            name: [0; VM_NAME_MAX_LEN],
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct VmListIoctlArg {
+    /// User address of `VmInfoEntry` array to be filled by hypervisor.
+    pub vm_info_ptr: usize,
+    /// Capacity of `VmInfoEntry` array.
+    pub vm_info_capacity: usize,
+    /// User address which stores the number of VMs, written by hypervisor.
+    pub vm_num_ptr: usize,
+}
+
+unsafe impl ioctl::Ioctl for VmListIoctlArg {
+    type Output = ();
+
+    const OPCODE: ioctl::Opcode = ioctl::Opcode::write::<Self>(0, 9);
+
+    const IS_MUTATING: bool = false;
+
+    fn as_ptr(&mut self) -> *mut c_void {
+        self as *const _ as *mut c_void
+    }
+
+    unsafe fn output_from_ptr(
+        _out: ioctl::IoctlOutput,
+        _extract_output: *mut c_void,
+    ) -> rustix::io::Result<Self::Output> {
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+//! Linux x86 boot protocol, see Linux `Documentation/arch/x86/boot.rst`.
+//!
+//! The protected-mode kernel of bzImage is loaded as kernel image, and the zero page
+//! (`struct boot_params`) followed by kernel command line takes the place of bios image,
+//! loaded at `bios_load_addr`. The kernel expects `rsi` to point to the zero page when it
+//! is entered at its 64-bit entry point, and axcli has no way to set registers itself, so
+//! the address is passed to hypervisor as `boot_params_addr` in the raw VM config, see
+//! `VmCreateCliArg::raw_config_for_hypervisor`. Hypervisor must load it into `rsi` of the
+//! boot CPU, the key is ignored by hypervisors unaware of it, whose Linux VMs fault early.
+
+use crate::cfg::VmCreateCliArg;
+use crate::image::{read_u32, KernelImage};
+
+/// Size of zero page, command line is placed right after it.
+pub const ZERO_PAGE_SIZE: usize = 0x1000;
+
+/// Offset of setup header in both bzImage and zero page.
+const SETUP_HEADER_OFFSET: usize = 0x1f1;
+/// The jump instruction at `0x200` skips the setup header, its offset byte tells the end.
+const SETUP_HEADER_JUMP_OFFSET: usize = 0x201;
+
+const EXT_RAMDISK_IMAGE: usize = 0x0c0;
+const EXT_RAMDISK_SIZE: usize = 0x0c4;
+const EXT_CMD_LINE_PTR: usize = 0x0c8;
+const E820_ENTRIES: usize = 0x1e8;
+const VID_MODE: usize = 0x1fa;
+const TYPE_OF_LOADER: usize = 0x210;
+const LOADFLAGS: usize = 0x211;
+const RAMDISK_IMAGE: usize = 0x218;
+const RAMDISK_SIZE: usize = 0x21c;
+const CMD_LINE_PTR: usize = 0x228;
+const INITRD_ADDR_MAX: usize = 0x22c;
+const CMDLINE_SIZE: usize = 0x238;
+const E820_TABLE: usize = 0x2d0;
+const E820_MAX_ENTRIES: usize = 128;
+const E820_ENTRY_SIZE: usize = 20;
+
+const E820_TYPE_RAM: u32 = 1;
+const E820_TYPE_RESERVED: u32 = 2;
+
+/// Boot loader of undefined type.
+const LOADER_TYPE_UNDEFINED: u8 = 0xff;
+/// Kernel is loaded at 1 MiB or above.
+const LOADED_HIGH: u8 = 1 << 0;
+/// "normal" video mode, do not prompt.
+const VID_MODE_NORMAL: u16 = 0xffff;
+
+/// Build zero page for bzImage `image`, followed by the NUL-terminated command line.
+///
+/// `initrd_size` is the size of ramdisk loaded at `ramdisk_load_addr`, if any.
+pub fn build_boot_params(
+    image: &KernelImage,
+    vm_arg: &VmCreateCliArg,
+    initrd_size: Option<usize>,
+) -> Result<Vec<u8>, String> {
+    let setup = image
+        .setup
+        .as_deref()
+        .ok_or_else(|| String::from("not a bzImage"))?;
+
+    let cmdline = vm_arg.cmdline.as_deref().unwrap_or_default();
+    let mut params = vec![0u8; ZERO_PAGE_SIZE + cmdline.len() + 1];
+    params[ZERO_PAGE_SIZE..ZERO_PAGE_SIZE + cmdline.len()].copy_from_slice(cmdline.as_bytes());
+
+    let header_end = (SETUP_HEADER_JUMP_OFFSET + 1 + setup[SETUP_HEADER_JUMP_OFFSET] as usize)
+        .min(setup.len())
+        .min(E820_TABLE);
+    params[SETUP_HEADER_OFFSET..header_end]
+        .copy_from_slice(&setup[SETUP_HEADER_OFFSET..header_end]);
+
+    params[VID_MODE..VID_MODE + 2].copy_from_slice(&VID_MODE_NORMAL.to_le_bytes());
+    params[TYPE_OF_LOADER] = LOADER_TYPE_UNDEFINED;
+    if params[LOADFLAGS] & LOADED_HIGH == 0 {
+        return Err(String::from("zImage loaded below 1 MiB is not supported"));
+    }
+
+    let cmdline_size = read_u32(setup, CMDLINE_SIZE)? as usize;
+    if cmdline.len() > cmdline_size {
+        return Err(format!(
+            "cmdline of {} Bytes exceeds {cmdline_size} Bytes accepted by kernel",
+            cmdline.len()
+        ));
+    }
+    let cmdline_addr = vm_arg.bios_load_addr + ZERO_PAGE_SIZE;
+    put_u64_split(
+        &mut params,
+        CMD_LINE_PTR,
+        EXT_CMD_LINE_PTR,
+        cmdline_addr as u64,
+    );
+
+    if let (Some(size), Some(addr)) = (initrd_size, vm_arg.ramdisk_load_addr) {
+        let addr_max = read_u32(setup, INITRD_ADDR_MAX)? as usize;
+        if addr.saturating_add(size) > addr_max.saturating_add(1) {
+            return Err(format!(
+                "initrd [{addr:#x}, {:#x}) exceeds initrd_addr_max {addr_max:#x}",
+                addr.saturating_add(size)
+            ));
+        }
+        put_u64_split(&mut params, RAMDISK_IMAGE, EXT_RAMDISK_IMAGE, addr as u64);
+        put_u64_split(&mut params, RAMDISK_SIZE, EXT_RAMDISK_SIZE, size as u64);
+    }
+
+    if vm_arg.memory_regions.len() > E820_MAX_ENTRIES {
+        return Err(format!(
+            "{} memory regions exceed {E820_MAX_ENTRIES} E820 entries",
+            vm_arg.memory_regions.len()
+        ));
+    }
+    for (i, region) in vm_arg.memory_regions.iter().enumerate() {
+        let entry = E820_TABLE + i * E820_ENTRY_SIZE;
+        let kind = if region.flags.is_device() {
+            E820_TYPE_RESERVED
+        } else {
+            E820_TYPE_RAM
+        };
+        params[entry..entry + 8].copy_from_slice(&(region.gpa as u64).to_le_bytes());
+        params[entry + 8..entry + 16].copy_from_slice(&(region.size as u64).to_le_bytes());
+        params[entry + 16..entry + 20].copy_from_slice(&kind.to_le_bytes());
+    }
+    params[E820_ENTRIES] = vm_arg.memory_regions.len() as u8;
+
+    debug!(
+        "zero page built with {} E820 entries, cmdline {cmdline:?}",
+        vm_arg.memory_regions.len()
+    );
+    Ok(params)
+}
+
+/// Write low 32 bits of `value` at `offset`, and high 32 bits at `ext_offset`.
+fn put_u64_split(params: &mut [u8], offset: usize, ext_offset: usize, value: u64) {
+    params[offset..offset + 4].copy_from_slice(&(value as u32).to_le_bytes());
+    params[ext_offset..ext_offset + 4].copy_from_slice(&((value >> 32) as u32).to_le_bytes());
+}
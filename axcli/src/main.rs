@@ -1,14 +1,26 @@
 #[macro_use]
 extern crate log;
 
+mod backend;
 mod cfg;
 mod cli;
+mod compress;
 mod daemon;
+mod digest;
+mod disk;
+mod hv;
+mod image;
 mod ioctl_arg;
+mod linux;
+mod mapped;
+#[cfg(test)]
+mod test_utils;
 mod vmm;
 
+use axerrno::AxResult;
 use clap::Parser;
-use cli::{CLISubCmd, HvSubCmd, VmSubCmd, CLI};
+use cli::{CLISubCmd, DiskSubCmd, HvSubCmd, VmSubCmd, CLI};
+use colored::Colorize;
 
 fn main() {
     // configure logger and set log level
@@ -16,17 +28,36 @@ fn main() {
         .filter_level(log::LevelFilter::Debug)
         .init();
 
+    if let Err(err) = run() {
+        eprintln!("\n\n{}", "[ERROR]".bold().red());
+        eprintln!("{err:#}");
+        std::process::exit(1);
+    }
+}
+
+fn run() -> AxResult {
     let cli = CLI::parse();
+    // Only open backend for subcommands accessing hypervisor.
+    let device = cli.device;
+    let backend = || backend::open_backend(device.clone());
     match cli.subcmd {
         CLISubCmd::Hv { subcmd } => match subcmd {
-            HvSubCmd::Enable => todo!(),
-            HvSubCmd::Disable => todo!(),
+            HvSubCmd::Enable(arg) => hv::axhv_enable(backend()?.as_mut(), arg),
+            HvSubCmd::Disable(arg) => hv::axhv_disable(backend()?.as_mut(), arg),
         },
         CLISubCmd::Vm { subcmd } => match subcmd {
-            VmSubCmd::List => todo!(),
-            VmSubCmd::Create(arg) => vmm::axvmm_create_vm(arg).expect("Failed to create VM"),
-            VmSubCmd::Boot(arg) => vmm::axvmm_boot_vm(arg).expect("Failed to boot VM"),
-            VmSubCmd::Shutdown(arg) => vmm::axvmm_shutdown_vm(arg).expect("Failed to shutdown VM"),
+            VmSubCmd::List(arg) => vmm::axvmm_list_vm(backend()?.as_mut(), arg),
+            VmSubCmd::Create(arg) => vmm::axvmm_create_vm(backend()?.as_mut(), arg),
+            VmSubCmd::Validate(arg) => vmm::axvmm_validate_vm(arg),
+            VmSubCmd::Hash(arg) => vmm::axvmm_hash_vm(arg),
+            VmSubCmd::Boot(arg) => vmm::axvmm_boot_vm(backend()?.as_mut(), arg),
+            VmSubCmd::Shutdown(arg) => vmm::axvmm_shutdown_vm(backend()?.as_mut(), arg),
+            VmSubCmd::Destroy(arg) => vmm::axvmm_destroy_vm(backend()?.as_mut(), arg),
+        },
+        CLISubCmd::Disk { subcmd } => match subcmd {
+            DiskSubCmd::Create(arg) => disk::axdisk_create(arg),
+            DiskSubCmd::Commit(arg) => disk::axdisk_commit(arg),
+            DiskSubCmd::Rebase(arg) => disk::axdisk_rebase(arg),
         },
     }
 }
@@ -0,0 +1,185 @@
+//! Image files mapped read-only into memory.
+//!
+//! Images like initrd may be hundreds of MiB, they are mapped instead of read, so that the
+//! driver copies them into guest memory directly from page cache. Compressed images are
+//! decompressed into memory, see `crate::compress`.
+//!
+//! A mapped file truncated by another process raises `SIGBUS` on access, so a shared
+//! `flock` is held while it is mapped. The lock is advisory, writers not taking it are
+//! not kept off.
+
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+
+use rustix::fs::{flock, FlockOperation};
+use rustix::io::Errno;
+use rustix::mm::{mmap, munmap, MapFlags, ProtFlags};
+
+use crate::compress::Compression;
+
+/// A file mapped read-only and private, unmapped and unlocked on drop.
+pub struct MappedFile {
+    ptr: *mut core::ffi::c_void,
+    len: usize,
+    /// Holds the shared lock until the file is unmapped.
+    _file: File,
+}
+
+impl MappedFile {
+    /// Map the whole file at `path`, empty file is rejected since it cannot be mapped.
+    ///
+    /// Fail if another process holds an exclusive `flock` of the file.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        flock(&file, FlockOperation::NonBlockingLockShared).map_err(|err| match err {
+            Errno::WOULDBLOCK => io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "file is locked for writing by another process",
+            ),
+            err => err.into(),
+        })?;
+        let len = file.metadata()?.len();
+        let len = usize::try_from(len).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("file of {len} Bytes is too large"),
+            )
+        })?;
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "file is empty"));
+        }
+        // SAFETY: a new mapping is created, it is private and never written through.
+        let ptr = unsafe {
+            mmap(
+                core::ptr::null_mut(),
+                len,
+                ProtFlags::READ,
+                MapFlags::PRIVATE,
+                &file,
+                0,
+            )?
+        };
+        Ok(Self {
+            ptr,
+            len,
+            _file: file,
+        })
+    }
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `ptr` points to `len` readable Bytes until `self` is dropped.
+        unsafe { core::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and `len` come from `mmap`, no slice of it outlives `self`.
+        if let Err(err) = unsafe { munmap(self.ptr, self.len) } {
+            warn!("Failed to unmap image of {:#x} Bytes, {err:?}", self.len);
+        }
+    }
+}
+
+impl core::fmt::Debug for MappedFile {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "MappedFile({:#x} Bytes)", self.len)
+    }
+}
+
+/// Image content, either a part of mapped file or built in memory.
+#[derive(Debug)]
+pub enum ImageData {
+    /// Mapped file starting from `offset`.
+    Mapped {
+        file: MappedFile,
+        offset: usize,
+    },
+    Owned(Vec<u8>),
+}
+
+impl ImageData {
+    /// Take mapped image file at `path`, decompress it if it is compressed.
+    ///
+    /// Decompressed image is kept in memory, and must not exceed `limit` Bytes.
+    pub fn from_file(path: &Path, file: MappedFile, limit: usize) -> io::Result<Self> {
+        let Some(compression) = Compression::detect(&file) else {
+            return Ok(Self::Mapped { file, offset: 0 });
+        };
+        let data = compression
+            .decompress(&file, limit)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        debug!(
+            "{path:?} is {compression} compressed, {:#x} Bytes decompressed to {:#x} Bytes",
+            file.len(),
+            data.len()
+        );
+        Ok(Self::Owned(data))
+    }
+
+    /// Drop the first `count` Bytes.
+    pub fn skip(self, count: usize) -> Self {
+        match self {
+            Self::Mapped { file, offset } => Self::Mapped {
+                file,
+                offset: offset + count,
+            },
+            Self::Owned(mut data) => {
+                data.drain(..count);
+                Self::Owned(data)
+            }
+        }
+    }
+}
+
+impl Deref for ImageData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Mapped { file, offset } => &file[*offset..],
+            Self::Owned(data) => data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axdisk::test_utils::TempDir;
+
+    use super::*;
+
+    fn try_lock_exclusive(path: &Path) -> rustix::io::Result<File> {
+        let file = File::open(path).unwrap();
+        flock(&file, FlockOperation::NonBlockingLockExclusive).map(|_| file)
+    }
+
+    #[test]
+    fn mapping_holds_shared_lock() {
+        let dir = TempDir::new();
+        let path = dir.file("image.bin", b"image");
+        let mapped = MappedFile::open(&path).unwrap();
+        assert_eq!(&*mapped, b"image");
+        // Other readers map it as well.
+        assert_eq!(&*MappedFile::open(&path).unwrap(), b"image");
+
+        assert_eq!(try_lock_exclusive(&path).err(), Some(Errno::WOULDBLOCK));
+        drop(mapped);
+        assert!(try_lock_exclusive(&path).is_ok());
+    }
+
+    #[test]
+    fn map_fails_while_file_is_written() {
+        let dir = TempDir::new();
+        let path = dir.file("image.bin", b"image");
+        let _writer = try_lock_exclusive(&path).unwrap();
+        let err = MappedFile::open(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+}
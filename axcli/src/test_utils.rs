@@ -0,0 +1,157 @@
+//! Helpers shared by unit tests, others are in `axdisk::test_utils`.
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+
+use axdaemon_request::{DaemonHello, DaemonReply, DaemonRequest, DiskCfg};
+use axdisk::test_utils::TempDir;
+
+/// Serializes tests depending on `AXDAEMON_SOCKET`, which is shared by the whole process.
+static DAEMON_ENV: Mutex<()> = Mutex::new(());
+
+/// State of `FakeDaemon` shared with its thread.
+#[derive(Default)]
+struct FakeDaemonState {
+    /// Requests received, in `Debug` format.
+    requests: Vec<String>,
+    vms: BTreeMap<usize, Vec<DiskCfg>>,
+    /// Features replied in `DaemonHello`, all of `PROTOCOL_FEATURES` if `None`.
+    features: Option<Vec<String>>,
+    /// Disk images reported open for VMs by `CheckDisksUnused`.
+    disks_in_use: Vec<PathBuf>,
+}
+
+/// Axdaemon answering requests on a Unix socket, `AXDAEMON_SOCKET` points to it while it
+/// is alive.
+///
+/// Only VM registration and disks in use are tracked, other requests succeed.
+pub struct FakeDaemon {
+    state: Arc<Mutex<FakeDaemonState>>,
+    stop: Arc<AtomicBool>,
+    socket_path: PathBuf,
+    thread: Option<JoinHandle<()>>,
+    _dir: TempDir,
+    _env: MutexGuard<'static, ()>,
+}
+
+impl FakeDaemon {
+    pub fn start() -> Self {
+        let env = DAEMON_ENV.lock().unwrap_or_else(|err| err.into_inner());
+        let dir = TempDir::new();
+        let socket_path = dir.path().join("axdaemon.sock");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        std::env::set_var("AXDAEMON_SOCKET", &socket_path);
+
+        let state = Arc::new(Mutex::new(FakeDaemonState::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let state = state.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    serve(&mut stream.unwrap(), &state);
+                }
+            })
+        };
+        Self {
+            state,
+            stop,
+            socket_path,
+            thread: Some(thread),
+            _dir: dir,
+            _env: env,
+        }
+    }
+
+    /// Requests received since last call.
+    pub fn take_requests(&self) -> Vec<String> {
+        std::mem::take(&mut self.state.lock().unwrap().requests)
+    }
+
+    pub fn registered_vms(&self) -> BTreeMap<usize, Vec<DiskCfg>> {
+        self.state.lock().unwrap().vms.clone()
+    }
+
+    /// Reply `features` in handshakes instead of all features.
+    pub fn set_features(&self, features: &[&str]) {
+        self.state.lock().unwrap().features =
+            Some(features.iter().map(|f| f.to_string()).collect());
+    }
+
+    /// Report disk images `paths` open for a VM.
+    pub fn set_disks_in_use(&self, paths: &[&Path]) {
+        self.state.lock().unwrap().disks_in_use =
+            paths.iter().map(|path| path.to_path_buf()).collect();
+    }
+}
+
+impl Drop for FakeDaemon {
+    fn drop(&mut self) {
+        std::env::remove_var("AXDAEMON_SOCKET");
+        self.stop.store(true, Ordering::Relaxed);
+        // Wake up the thread blocked in `accept`.
+        let _ = UnixStream::connect(&self.socket_path);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Serve one connection: handshake, then a single request.
+fn serve(stream: &mut UnixStream, state: &Mutex<FakeDaemonState>) {
+    let Some(_) = receive::<DaemonHello>(stream) else {
+        return;
+    };
+    let mut hello = DaemonHello::default();
+    if let Some(features) = &state.lock().unwrap().features {
+        hello.features = features.clone();
+    }
+    send(stream, &hello);
+    let Some(request) = receive::<DaemonRequest>(stream) else {
+        return;
+    };
+
+    let mut state = state.lock().unwrap();
+    state.requests.push(format!("{request:?}"));
+    let reply = match request {
+        DaemonRequest::RegisterVM { vmid, disks } => {
+            state.vms.insert(vmid, disks);
+            DaemonReply::Result(Ok(()))
+        }
+        DaemonRequest::UnregisterVM { vmid } => {
+            state.vms.remove(&vmid);
+            DaemonReply::Result(Ok(()))
+        }
+        DaemonRequest::ListVM => DaemonReply::VMList(state.vms.clone()),
+        DaemonRequest::CheckDisksUnused { paths } => {
+            match paths.iter().find(|path| state.disks_in_use.contains(path)) {
+                Some(path) => DaemonReply::Result(Err(format!("{path:?} is used by VM [1]"))),
+                None => DaemonReply::Result(Ok(())),
+            }
+        }
+        _ => DaemonReply::Result(Ok(())),
+    };
+    send(stream, &reply);
+}
+
+fn receive<T: serde::de::DeserializeOwned>(stream: &mut UnixStream) -> Option<T> {
+    let mut len = [0; 8];
+    stream.read_exact(&mut len).ok()?;
+    let mut message = vec![0; u64::from_le_bytes(len) as usize];
+    stream.read_exact(&mut message).ok()?;
+    bincode::deserialize(&message).ok()
+}
+
+fn send<T: serde::Serialize>(stream: &mut UnixStream, message: &T) {
+    let message = bincode::serialize(message).unwrap();
+    let _ = stream.write_all(&(message.len() as u64).to_le_bytes());
+    let _ = stream.write_all(&message);
+}
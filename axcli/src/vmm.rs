@@ -1,137 +1,577 @@
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs::read_to_string;
-use std::fs::File;
-use std::io::prelude::*;
+use std::path::Path;
 
 use colored::Colorize;
-use rustix::fd::OwnedFd;
-use rustix::fs::{open, Mode, OFlags};
-use rustix::ioctl;
+use serde::Serialize;
 
-use axerrno::{AxError, AxResult};
+use axdaemon_request::DiskCfg;
+use axerrno::{ax_err, ax_err_type, AxError, AxResult};
 
-use crate::cfg::VmCreateCliArg;
-use crate::cli::{VmCreateArgs, VmIdArgs};
-use crate::ioctl_arg::{VmBootIoctlArg, VmCreateIoctlArg, VmShutdownIoctlArg};
+use crate::backend::{HypervisorBackend, LoadSegment, VmCreateParams};
+use crate::cfg::{VmCreateCliArg, VmImages, VmMemCfg};
+use crate::cli::{VmCreateArgs, VmIdArgs, VmListArgs};
+use crate::digest;
+use crate::image::{KernelFormat, KernelImage};
+use crate::linux;
+use crate::mapped::ImageData;
 
-pub fn open_driver() -> OwnedFd {
-    let driver = String::from("/dev/jailhouse");
-    open(driver, OFlags::RDWR, Mode::RWXO).expect("Failed to open ArceOS driver")
+/// VM state reported by hypervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VmState {
+    Created,
+    Booted,
+    ShutDown,
+    Unknown,
 }
 
-pub fn perform_ioctl<I: ioctl::Ioctl>(ioctl: I) {
-    let fd = open_driver();
-    unsafe { ioctl::ioctl(fd, ioctl) }.expect("failed to perform ioctl");
+impl From<usize> for VmState {
+    fn from(state: usize) -> Self {
+        match state {
+            0 => Self::Created,
+            1 => Self::Booted,
+            2 => Self::ShutDown,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for VmState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            Self::Created => "created",
+            Self::Booted => "booted",
+            Self::ShutDown => "shut down",
+            Self::Unknown => "unknown",
+        };
+        // Use `pad` so that width and alignment flags are respected.
+        f.pad(state)
+    }
 }
 
-pub fn axvmm_create_vm(arg: VmCreateArgs) -> AxResult {
-    let config_content = read_to_string(arg.config_path).map_err(|err| {
+/// VM info combined from hypervisor and axdaemon, printed by `axcli vm list`.
+#[derive(Debug, Serialize)]
+pub struct VmListEntry {
+    pub id: usize,
+    pub name: String,
+    pub vm_type: usize,
+    pub cpu_set: usize,
+    pub state: VmState,
+    /// Disks registered in axdaemon, indexed by device index.
+    pub disks: Vec<DiskCfg>,
+    /// Not reported by every backend.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_regions: Option<Vec<VmMemCfg>>,
+}
+
+/// Read and parse VM config file, return the raw config content and the parsed config.
+fn parse_vm_config(config_path: &Path) -> AxResult<(String, VmCreateCliArg)> {
+    let config_content = read_to_string(config_path).map_err(|err| {
         warn!("Failed to get VM config file {err:?}");
         AxError::InvalidInput
     })?;
 
-    let vm_arg: VmCreateCliArg = toml::from_str(config_content.as_str()).map_err(|err| {
-        warn!("Failed to deserialize VM config file {err:?}");
+    let mut vm_arg: VmCreateCliArg = toml::from_str(config_content.as_str()).map_err(|err| {
+        warn!("Failed to deserialize VM config file {err}");
         AxError::InvalidInput
     })?;
 
+    // Paths in config are relative to the config file, also for daemon with its own cwd.
+    let config_dir = std::path::absolute(config_path)
+        .ok()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("failed to get directory of VM config {config_path:?}")
+            )
+        })?;
+    vm_arg
+        .resolve_paths(&config_dir)
+        .map_err(|problem| ax_err_type!(InvalidInput, format!("invalid VM config {problem}")))?;
+
     debug!("get vm_arg {:#x?}", vm_arg);
 
-    let mut bios_img = File::open(vm_arg.bios_path.clone()).map_err(|err| {
-        warn!(
-            "Failed to open bios file on {:?}, {:?}",
-            vm_arg.bios_path, err
-        );
-        AxError::InvalidInput
-    })?;
-    let mut kernel_img = File::open(vm_arg.kernel_path.clone()).map_err(|err| {
-        warn!(
-            "Failed to open kernel file on {:?}, {:?}",
-            vm_arg.kernel_path, err
-        );
-        AxError::InvalidInput
+    Ok((config_content, vm_arg))
+}
+
+/// Read and parse VM config file, then validate it, every problem found is printed.
+///
+/// Return the raw config content, the parsed config and the images it loads.
+fn load_vm_config(config_path: &Path) -> AxResult<(String, VmCreateCliArg, VmImages)> {
+    let (config_content, vm_arg) = parse_vm_config(config_path)?;
+
+    let images = vm_arg.validate().or_else(|problems| {
+        println!("Invalid VM config {config_path:?}:");
+        for problem in problems.iter() {
+            println!("  * {problem}");
+        }
+        ax_err!(
+            InvalidInput,
+            format!("{} problems found in VM config", problems.len())
+        )
     })?;
 
-    let bios_img_size = bios_img.metadata().unwrap().len() as usize;
-    let kernel_img_size = kernel_img.metadata().unwrap().len() as usize;
+    Ok((config_content, vm_arg, images))
+}
 
-    let mut bios_img_buffer = Vec::new();
-    bios_img
-        .read_to_end(&mut bios_img_buffer)
-        .expect("Failed to read bios image file");
+pub fn axvmm_validate_vm(arg: VmCreateArgs) -> AxResult {
+    let (_, vm_arg, _) = load_vm_config(&arg.config_path)?;
+    println!("VM config {:?} is valid, memory regions:", arg.config_path);
+    for region in vm_arg.memory_regions.iter() {
+        println!("  * {region}");
+    }
+    // Loaded images are verified by validation.
+    digest::verify_images(vm_arg.disk_image_digests())
+}
 
-    let mut kernel_img_buffer = Vec::new();
-    kernel_img
-        .read_to_end(&mut kernel_img_buffer)
-        .expect("Failed to read kernel image file");
+/// Digests are computed even if config is invalid otherwise, e.g. with malformed digests.
+pub fn axvmm_hash_vm(arg: VmCreateArgs) -> AxResult {
+    let (_, vm_arg) = parse_vm_config(&arg.config_path)?;
+    println!("Writing image digests to VM config {:?}:", arg.config_path);
+    digest::fill_image_digests(&arg.config_path, &vm_arg)
+}
 
-    let mut ramdisk_img_ptr = 0;
-    let mut ramdisk_img_size = 0;
-    let mut ramdisk_img_buffer = Vec::new();
+/// The load address and entry point derived from ELF, multiboot or bzImage headers of
+/// kernel `image` override those in config.
+fn apply_kernel_image(vm_arg: &mut VmCreateCliArg, image: &KernelImage) {
+    info!(
+        "kernel {:?} is {}, {:#x} Bytes in {} segments to load",
+        vm_arg.kernel_path,
+        image.format,
+        image.file_size(),
+        image.segments.len()
+    );
 
-    let _ramdisk_img = vm_arg.ramdisk_path.map(|ramdisk_path| {
-        let mut ramdisk_file = File::open(ramdisk_path.clone())
-            .map_err(|err| {
-                warn!(
-                    "Failed to open ramdisk file on {:?}, {:?}",
-                    ramdisk_path, err
-                );
-                AxError::InvalidInput
-            })
-            .unwrap();
-        ramdisk_img_size = ramdisk_file.metadata().unwrap().len() as usize;
-        ramdisk_file
-            .read_to_end(&mut ramdisk_img_buffer)
-            .expect("Failed to read ramdisk image file");
-        ramdisk_img_ptr = ramdisk_img_buffer.as_ptr() as usize;
-        ramdisk_file
-    });
+    if let Some(load_addr) = image.load_addr {
+        if load_addr != vm_arg.kernel_load_addr {
+            warn!(
+                "kernel_load_addr {:#x} in config disagrees with {} image, use {:#x}",
+                vm_arg.kernel_load_addr, image.format, load_addr
+            );
+            vm_arg.kernel_load_addr = load_addr;
+        }
+    }
+    if let Some(entry) = image.entry {
+        // VM enters bios first if they are the same, bios will jump to kernel then.
+        // bzImage has no bios, its zero page is loaded there instead.
+        if vm_arg.entry_point == vm_arg.bios_load_addr && image.format != KernelFormat::BzImage {
+            debug!("VM boots from bios, kernel entry {entry:#x}");
+        } else if entry != vm_arg.entry_point {
+            warn!(
+                "entry_point {:#x} in config disagrees with {} image, use {:#x}",
+                vm_arg.entry_point, image.format, entry
+            );
+            vm_arg.entry_point = entry;
+        }
+    }
+}
 
-    // VM id could be modified by hypervisor.
-    let vmid = vm_arg.id;
+pub fn axvmm_create_vm(backend: &mut dyn HypervisorBackend, arg: VmCreateArgs) -> AxResult {
+    let (config_content, mut vm_arg, images) = load_vm_config(&arg.config_path)?;
+    // Other images are verified as they are loaded by validation.
+    digest::verify_images(vm_arg.disk_image_digests())?;
+    let VmImages {
+        kernel: kernel_img,
+        bios: bios_img,
+        ramdisk: ramdisk_img,
+    } = images;
+    apply_kernel_image(&mut vm_arg, &kernel_img);
+    // Zero page of bzImage takes the place of bios, see `crate::linux`.
+    let boot_params_addr =
+        (kernel_img.format == KernelFormat::BzImage).then_some(vm_arg.bios_load_addr);
+    let config_content = vm_arg
+        .raw_config_for_hypervisor(&config_content, boot_params_addr)
+        .map_err(|err| ax_err_type!(InvalidInput, format!("failed to rewrite VM config {err}")))?;
 
-    let driver_arg = VmCreateIoctlArg {
-        id_ptr: &vmid as *const _ as usize,
-        cpu_set: vm_arg.cpu_set,
-        bios_img_ptr: bios_img_buffer.as_ptr() as usize,
-        bios_img_size,
-        kernel_img_ptr: kernel_img_buffer.as_ptr() as usize,
-        kernel_img_size,
-        ramdisk_img_ptr,
-        ramdisk_img_size,
-        raw_cfg_file_ptr: config_content.as_ptr() as usize,
-        raw_cfg_file_size: config_content.len(),
+    let bios_img = match bios_img {
+        Some(bios_img) => bios_img,
+        // Zero page takes the place of bios, see `crate::linux`.
+        None => {
+            let boot_params = linux::build_boot_params(
+                &kernel_img,
+                &vm_arg,
+                ramdisk_img.as_ref().map(|ramdisk| ramdisk.len()),
+            )
+            .map_err(|err| {
+                ax_err_type!(
+                    InvalidInput,
+                    format!(
+                        "failed to build zero page for {:?}, {err}",
+                        vm_arg.kernel_path
+                    )
+                )
+            })?;
+            ImageData::Owned(boot_params)
+        }
     };
 
-    perform_ioctl(driver_arg);
+    let kernel_segments: Vec<LoadSegment> = kernel_img
+        .segments
+        .iter()
+        .map(|segment| LoadSegment {
+            gpa: vm_arg.kernel_load_addr + segment.load_offset,
+            data: kernel_img.segment_data(segment),
+            mem_size: segment.mem_size,
+        })
+        .collect();
+    let vmid = backend.create_vm(VmCreateParams {
+        id: vm_arg.id,
+        cpu_set: vm_arg.cpu_set,
+        bios_img: &bios_img,
+        kernel_segments: &kernel_segments,
+        ramdisk_img: ramdisk_img.as_deref(),
+        raw_cfg: &config_content,
+    })?;
 
     info!(
         "VM [{vmid}] created success! Trying to register to {} ...",
         "AxDaemon".bold().green()
     );
 
-    if let Some(path_str) = vm_arg.disk_path {
-        crate::daemon::register_vm_to_daemon(vmid, std::path::PathBuf::from(path_str));
+    let disks = vm_arg.disk_cfgs();
+    if !disks.is_empty() {
+        crate::daemon::register_vm_to_daemon(vmid, disks)?;
     }
 
     Ok(())
 }
 
-pub fn axvmm_boot_vm(arg: VmIdArgs) -> Result<(), String> {
+pub fn axvmm_list_vm(backend: &mut dyn HypervisorBackend, arg: VmListArgs) -> AxResult {
+    let vm_infos = backend.list_vm()?;
+
+    let mut vm_disks = crate::daemon::list_vm_on_daemon().unwrap_or_else(|err| {
+        warn!(
+            "Failed to get disk info from {}: {err:?}, disk will not be shown",
+            "AxDaemon".bold().green()
+        );
+        BTreeMap::new()
+    });
+
+    let vm_list: Vec<VmListEntry> = vm_infos
+        .iter()
+        .map(|info| {
+            let memory_regions = backend.vm_memory_regions(info.id);
+            let name_len = info
+                .name
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(info.name.len());
+            VmListEntry {
+                id: info.id,
+                name: String::from_utf8_lossy(&info.name[..name_len]).into_owned(),
+                vm_type: info.vm_type,
+                cpu_set: info.cpu_set,
+                state: VmState::from(info.state),
+                disks: vm_disks.remove(&info.id).unwrap_or_default(),
+                memory_regions,
+            }
+        })
+        .collect();
+
+    for vmid in vm_disks.keys() {
+        warn!(
+            "VM [{vmid}] is registered in {} but not found in hypervisor",
+            "AxDaemon".bold().green()
+        );
+    }
+
+    if arg.json {
+        let json = serde_json::to_string_pretty(&vm_list).map_err(|err| {
+            warn!("Failed to serialize VM list {err:?}");
+            AxError::InvalidData
+        })?;
+        println!("{json}");
+        return Ok(());
+    }
+
+    println!(
+        "{:<6}{:<16}{:<9}{:<12}{:<11}DISK",
+        "ID", "NAME", "VM_TYPE", "CPU_SET", "STATE"
+    );
+    for vm in vm_list.iter() {
+        println!(
+            "{:<6}{:<16}{:<9}{:<12}{:<11}{}",
+            vm.id,
+            vm.name,
+            vm.vm_type,
+            format!("{:#x}", vm.cpu_set),
+            vm.state,
+            vm.disks
+                .first()
+                .map(|disk| disk.to_string())
+                .unwrap_or(String::from("-")),
+        );
+        for (dev_index, disk) in vm.disks.iter().enumerate().skip(1) {
+            println!("{:<54}{disk}", format!("disk {dev_index}"));
+        }
+        for region in vm.memory_regions.iter().flatten() {
+            println!("{:<6}{region}", "");
+        }
+    }
+
+    Ok(())
+}
+
+pub fn axvmm_boot_vm(backend: &mut dyn HypervisorBackend, arg: VmIdArgs) -> AxResult {
     let id = arg.vmid as usize;
 
-    crate::daemon::setup_vm_on_daemon(id);
+    let registered = is_vm_registered_on_daemon(id)?;
+    if registered {
+        crate::daemon::setup_vm_on_daemon(id)?;
+    }
 
     println!("Boot VM [{}]", id);
-    let driver_arg = VmBootIoctlArg { id };
-    perform_ioctl(driver_arg);
+    if let Err(err) = backend.boot_vm(id) {
+        // Release emulated devices set up for the VM.
+        if registered {
+            if let Err(shutdown_err) = crate::daemon::shutdown_vm_on_daemon(id) {
+                warn!(
+                    "Failed to tear down VM [{id}] in {}: {shutdown_err:?}",
+                    "AxDaemon".bold().green()
+                );
+            }
+        }
+        return Err(err);
+    }
 
+    if registered {
+        crate::daemon::confirm_vm_booted_on_daemon(id)?;
+    }
     Ok(())
 }
 
-pub fn axvmm_shutdown_vm(arg: VmIdArgs) -> Result<(), String> {
+pub fn axvmm_shutdown_vm(backend: &mut dyn HypervisorBackend, arg: VmIdArgs) -> AxResult {
     let id = arg.vmid as usize;
     println!("Shutdown VM [{}]", id);
-    let driver_arg = VmShutdownIoctlArg { id };
-    perform_ioctl(driver_arg);
+    backend.shutdown_vm(id)?;
+
+    if is_vm_registered_on_daemon(id)? {
+        crate::daemon::shutdown_vm_on_daemon(id)?;
+    }
     Ok(())
 }
+
+pub fn axvmm_destroy_vm(backend: &mut dyn HypervisorBackend, arg: VmIdArgs) -> AxResult {
+    let id = arg.vmid as usize;
+
+    let vm_info = backend
+        .list_vm()?
+        .into_iter()
+        .find(|info| info.id == id)
+        .ok_or_else(|| ax_err_type!(NotFound, format!("VM [{id}] does not exist")))?;
+    if VmState::from(vm_info.state) == VmState::Booted {
+        return ax_err!(
+            ResourceBusy,
+            format!("VM [{id}] is still running, please shut it down first")
+        );
+    }
+
+    println!("Destroy VM [{}]", id);
+    backend.destroy_vm(id)?;
+
+    if is_vm_registered_on_daemon(id)? {
+        crate::daemon::unregister_vm_from_daemon(id)?;
+    }
+    Ok(())
+}
+
+/// Whether VM is registered in axdaemon, VMs without disk are not registered.
+///
+/// Fails if axdaemon is unreachable, as its devices of the VM could not be updated then.
+fn is_vm_registered_on_daemon(vmid: usize) -> AxResult<bool> {
+    let vm_disks = crate::daemon::list_vm_on_daemon().inspect_err(|_| {
+        warn!(
+            "Failed to query VMs from {}, VM [{vmid}] is not updated in it",
+            "AxDaemon".bold().green()
+        )
+    })?;
+    Ok(vm_disks.contains_key(&vmid))
+}
+
+#[cfg(test)]
+mod tests {
+    use axdisk::test_utils::TempDir;
+
+    use super::*;
+    use crate::backend::MockBackend;
+    use crate::test_utils::FakeDaemon;
+
+    /// Write images and a VM config with `extra` lines into `dir`, return the config path.
+    fn vm_config(dir: &TempDir, extra: &str) -> std::path::PathBuf {
+        dir.file("bios.bin", &[0x90; 0x100]);
+        dir.file("kernel.bin", &[0x90; 0x1000]);
+        dir.file("disk.img", &[0; 0x10000]);
+        let config = format!(
+            r#"
+id = 5
+name = "test"
+vm_type = 1
+cpu_set = 1
+entry_point = 0x80000000
+bios_path = "bios.bin"
+bios_load_addr = 0x80000000
+kernel_path = "kernel.bin"
+kernel_load_addr = 0x80200000
+memory_regions = [[0x80000000, 0x1000000, ["read", "write", "exec"]]]
+{extra}
+"#
+        );
+        dir.file("vm.toml", config.as_bytes())
+    }
+
+    fn create(backend: &mut MockBackend, config_path: std::path::PathBuf) -> AxResult {
+        axvmm_create_vm(backend, VmCreateArgs { config_path })
+    }
+
+    fn vm_state(backend: &MockBackend, vmid: usize) -> VmState {
+        VmState::from(backend.state.vms[&vmid].state)
+    }
+
+    #[test]
+    fn create_vm_registers_disks() {
+        let daemon = FakeDaemon::start();
+        let dir = TempDir::new();
+        let mut backend = MockBackend::default();
+        create(&mut backend, vm_config(&dir, r#"disk_path = "disk.img""#)).unwrap();
+
+        assert_eq!(vm_state(&backend, 5), VmState::Created);
+        assert_eq!(backend.state.vms[&5].name, "test");
+        assert_eq!(backend.state.vms[&5].kernel_img_size, 0x1000);
+        let disks = &daemon.registered_vms()[&5];
+        assert_eq!(disks.len(), 1);
+        assert_eq!(disks[0].path, dir.path().join("disk.img"));
+    }
+
+    #[test]
+    fn create_vm_without_disk_skips_daemon() {
+        let daemon = FakeDaemon::start();
+        let dir = TempDir::new();
+        let mut backend = MockBackend::default();
+        create(&mut backend, vm_config(&dir, "")).unwrap();
+
+        assert_eq!(vm_state(&backend, 5), VmState::Created);
+        assert!(daemon.take_requests().is_empty());
+    }
+
+    #[test]
+    fn create_vm_takes_next_free_id() {
+        let _daemon = FakeDaemon::start();
+        let dir = TempDir::new();
+        let mut backend = MockBackend::default();
+        create(&mut backend, vm_config(&dir, "")).unwrap();
+        create(&mut backend, vm_config(&dir, "")).unwrap();
+        assert_eq!(backend.state.vms.keys().collect::<Vec<_>>(), [&5, &6]);
+    }
+
+    #[test]
+    fn create_vm_rejects_invalid_config() {
+        let daemon = FakeDaemon::start();
+        let dir = TempDir::new();
+        let mut backend = MockBackend::default();
+        let config_path = vm_config(&dir, r#"disk_path = "missing.img""#);
+        assert!(create(&mut backend, config_path).is_err());
+
+        assert!(backend.state.vms.is_empty());
+        assert!(daemon.take_requests().is_empty());
+    }
+
+    #[test]
+    fn create_vm_requires_ramdisk_load_addr() {
+        let _daemon = FakeDaemon::start();
+        let dir = TempDir::new();
+        dir.file("initrd.img", &[0; 0x100]);
+        let mut backend = MockBackend::default();
+        let config_path = vm_config(&dir, r#"ramdisk_path = "initrd.img""#);
+        assert!(create(&mut backend, config_path).is_err());
+        assert!(backend.state.vms.is_empty());
+    }
+
+    #[test]
+    fn create_vm_verifies_loaded_images() {
+        let _daemon = FakeDaemon::start();
+        let dir = TempDir::new();
+        let mut backend = MockBackend::default();
+        let digest = digest::sha256(&[0x90; 0x1000]);
+        let config_path = vm_config(&dir, &format!("kernel_sha256 = \"{digest}\""));
+        create(&mut backend, config_path).unwrap();
+
+        let config_path = vm_config(&dir, &format!("bios_sha256 = \"{digest}\""));
+        assert!(create(&mut backend, config_path).is_err());
+        assert_eq!(backend.state.vms.len(), 1);
+    }
+
+    #[test]
+    fn vm_lifecycle_updates_daemon() {
+        let daemon = FakeDaemon::start();
+        let dir = TempDir::new();
+        let mut backend = MockBackend::default();
+        create(&mut backend, vm_config(&dir, r#"disk_path = "disk.img""#)).unwrap();
+        daemon.take_requests();
+
+        axvmm_boot_vm(&mut backend, VmIdArgs { vmid: 5 }).unwrap();
+        assert_eq!(vm_state(&backend, 5), VmState::Booted);
+        assert_eq!(
+            daemon.take_requests(),
+            ["ListVM", "BootVM { vmid: 5 }", "VMBooted { vmid: 5 }"]
+        );
+
+        axvmm_shutdown_vm(&mut backend, VmIdArgs { vmid: 5 }).unwrap();
+        assert_eq!(vm_state(&backend, 5), VmState::ShutDown);
+        assert_eq!(daemon.take_requests(), ["ListVM", "ShutdownVM { vmid: 5 }"]);
+
+        axvmm_destroy_vm(&mut backend, VmIdArgs { vmid: 5 }).unwrap();
+        assert!(backend.state.vms.is_empty());
+        assert_eq!(
+            daemon.take_requests(),
+            ["ListVM", "UnregisterVM { vmid: 5 }"]
+        );
+        assert!(daemon.registered_vms().is_empty());
+    }
+
+    #[test]
+    fn shutdown_never_booted_vm() {
+        let daemon = FakeDaemon::start();
+        let dir = TempDir::new();
+        let mut backend = MockBackend::default();
+        create(&mut backend, vm_config(&dir, r#"disk_path = "disk.img""#)).unwrap();
+        daemon.take_requests();
+
+        axvmm_shutdown_vm(&mut backend, VmIdArgs { vmid: 5 }).unwrap();
+        assert_eq!(vm_state(&backend, 5), VmState::ShutDown);
+        assert_eq!(daemon.take_requests(), ["ListVM", "ShutdownVM { vmid: 5 }"]);
+    }
+
+    #[test]
+    fn failed_boot_tears_down_daemon_devices() {
+        let daemon = FakeDaemon::start();
+        let dir = TempDir::new();
+        let mut backend = MockBackend::default();
+        create(&mut backend, vm_config(&dir, r#"disk_path = "disk.img""#)).unwrap();
+        daemon.take_requests();
+
+        // Hypervisor refuses to boot a running VM.
+        backend.state.vms.get_mut(&5).unwrap().state = 1;
+        assert!(axvmm_boot_vm(&mut backend, VmIdArgs { vmid: 5 }).is_err());
+        assert_eq!(
+            daemon.take_requests(),
+            ["ListVM", "BootVM { vmid: 5 }", "ShutdownVM { vmid: 5 }"]
+        );
+    }
+
+    #[test]
+    fn boot_fails_if_daemon_is_unreachable() {
+        let daemon = FakeDaemon::start();
+        let dir = TempDir::new();
+        let mut backend = MockBackend::default();
+        create(&mut backend, vm_config(&dir, r#"disk_path = "disk.img""#)).unwrap();
+
+        std::env::set_var("AXDAEMON_SOCKET", dir.path().join("missing.sock"));
+        assert!(axvmm_boot_vm(&mut backend, VmIdArgs { vmid: 5 }).is_err());
+        assert!(!backend.state.calls.contains(&String::from("boot_vm(5)")));
+        assert_eq!(vm_state(&backend, 5), VmState::Created);
+        drop(daemon);
+    }
+}
@@ -1,66 +1,229 @@
-use std::net::IpAddr;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use colored::Colorize;
 use futures_concurrency::stream::Merge;
 use tokio::runtime::Builder;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
 
 use axdaemon_request::DaemonReply;
 use axerrno::{ax_err_type, AxResult};
 
+use crate::scf::{ScfHandler, ScfQueue};
+use crate::uio::BlockReqQueue;
+use crate::vdev::{BlockReqStatus, VDevEventWrapper};
 use crate::vmm::{VMMEventWrapper, VMM};
 
+/// Max time to wait for in-flight requests during shutdown.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Events to be handled.
 /// * `VMM`: requests from axcli.
 /// * `VDEV`: irqs from guest VM for emulated device operations (through UIO).
+/// * `SCF`: virqs from ArceOS guest for syscall forwarding (through arceos_vdev).
 /// * `CtrlC`: Ctrl+C from os to terminate axdaemon process.
 #[derive(Debug)]
 pub enum Event {
     VMM(VMMEventWrapper),
-    VDEV,
+    VDEV(VDevEventWrapper),
+    SCF,
     CtrlC,
 }
 
 #[derive(Debug, Default)]
 struct Daemon {
     vmm: VMM,
+    /// Block request queue of `axservice` UIO device, `None` if it is not available.
+    req_queue: Option<BlockReqQueue>,
+    /// Syscall forwarding queue of `arceos_vdev` device, `None` if it is not available.
+    scf: Option<(ScfQueue, ScfHandler)>,
 }
 
 impl Daemon {
     fn init() -> Self {
-        Self { vmm: VMM::new() }
+        Self {
+            vmm: VMM::new(),
+            req_queue: None,
+            scf: None,
+        }
     }
 
-    pub async fn run(&mut self, bind: SocketAddr) -> AxResult {
-        info!("{} running, bind to {}", "AxDaemon".bold().green(), bind);
+    pub async fn run(&mut self, socket_path: PathBuf, tcp_bind: Option<SocketAddr>) -> AxResult {
+        info!(
+            "{} running, listen on {:?}",
+            "AxDaemon".bold().green(),
+            socket_path
+        );
 
         // Setup ctrlc events.
         let ctrlc_events = set_up_ctrlc_handler()?;
 
-        // Setup VMM events, which comes from TCP connection.
+        // Setup VMM events, which comes from Unix socket and optional TCP connections.
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
         let (events_tx, events_rx) = flume::bounded(10);
-        let _listen_port = crate::listener::spawn_listener_loop(bind, events_tx).await?;
-        let vmm_events = events_rx.into_stream().map(|e| Event::VMM(e));
+        crate::listener::spawn_unix_listener_loop(
+            socket_path,
+            events_tx.clone(),
+            shutdown_rx.clone(),
+        )
+        .await?;
+        if let Some(bind) = tcp_bind {
+            warn!(
+                "{} accepts TCP connections on {}, which are not authenticated",
+                "AxDaemon".bold().green(),
+                bind
+            );
+            let _listen_port =
+                crate::listener::spawn_listener_loop(bind, events_tx.clone(), shutdown_rx).await?;
+        }
+        // Only listeners and their connections hold senders, so that the channel is
+        // disconnected once they all exit during shutdown.
+        drop(events_tx);
+        let vmm_events = events_rx.clone().into_stream().map(Event::VMM);
+
+        // Setup VDEV events, which comes from irqs of axservice UIO device.
+        let vdev_events = self.set_up_vdev_events();
+
+        // Setup SCF events, which comes from signals of arceos_vdev device.
+        let scf_events = self.set_up_scf_events();
 
-        let mut events = (ctrlc_events, vmm_events).merge();
+        let mut events = (ctrlc_events, vmm_events, vdev_events, scf_events).merge();
 
         while let Some(event) = events.next().await {
             match event {
                 Event::VMM(vmm_event) => self.handle_vmm_event(vmm_event).await?,
-                Event::VDEV => todo!(),
-                Event::CtrlC => todo!(),
+                Event::VDEV(vdev_event) => self.handle_vdev_event(vdev_event),
+                Event::SCF => self.handle_scf_event(),
+                Event::CtrlC => break,
+            }
+        }
+        drop(events);
+
+        self.shutdown(shutdown_tx, events_rx).await
+    }
+
+    /// Drain in-flight requests and release all resources before exiting.
+    async fn shutdown(
+        &mut self,
+        shutdown_tx: watch::Sender<bool>,
+        events_rx: flume::Receiver<VMMEventWrapper>,
+    ) -> AxResult {
+        info!(
+            "{} shutting down, stop accepting connections",
+            "AxDaemon".bold().green()
+        );
+        // Listener and connections may have exited already, ignore the error.
+        let _ = shutdown_tx.send(true);
+
+        // The channel is disconnected once the listener loop and all connections exit.
+        let mut drained = 0;
+        let drain = async {
+            while let Ok(event) = events_rx.recv_async().await {
+                self.handle_vmm_event(event).await?;
+                drained += 1;
             }
+            AxResult::Ok(())
+        };
+        match tokio::time::timeout(DRAIN_TIMEOUT, drain).await {
+            Ok(result) => result?,
+            Err(_) => warn!(
+                "timeout while draining in-flight requests, {} requests dropped",
+                events_rx.len()
+            ),
+        }
+
+        let released = self.vmm.shutdown();
+
+        info!("{} shut down:", "AxDaemon".bold().green());
+        info!("  * {drained} in-flight requests replied");
+        match released {
+            Ok(released) => {
+                info!("  * {released} emulated blocks synced and released");
+                Ok(())
+            }
+            Err(err) => {
+                error!("  * failed to release some emulated blocks, {err:?}");
+                Err(err)
+            }
+        }
+    }
+
+    /// Open axservice UIO device, emulated devices are disabled if it is not available.
+    fn set_up_vdev_events(&mut self) -> impl Stream<Item = Event> {
+        let (vdev_tx, vdev_rx) = mpsc::channel(1);
+
+        let sysfs_class = Path::new(crate::uio::UIO_SYSFS_CLASS);
+        let dev_dir = Path::new(crate::uio::UIO_DEV_DIR);
+        let result =
+            crate::uio::open_axservice(sysfs_class, dev_dir).and_then(|(dev_file, req_queue)| {
+                crate::uio::spawn_irq_loop(dev_file, vdev_tx)?;
+                self.req_queue = Some(req_queue);
+                Ok(())
+            });
+        if let Err(err) = result {
+            warn!(
+                "{} failed to set up axservice UIO device {err:?}, emulated devices are disabled",
+                "AxDaemon".bold().green()
+            );
+        }
+
+        ReceiverStream::new(vdev_rx)
+    }
+
+    fn handle_vdev_event(&mut self, event: VDevEventWrapper) {
+        let Some(req_queue) = self.req_queue.as_mut() else {
+            return;
+        };
+        let vmm = &mut self.vmm;
+        match req_queue
+            .handle_requests(|vmid, req| BlockReqStatus::from(&vmm.handle_block_request(vmid, req)))
+        {
+            Ok(handled) => debug!("irq {} handled {handled} block requests", event.irq_count),
+            Err(err) => warn!(
+                "irq {} failed to handle block requests {err:?}",
+                event.irq_count
+            ),
+        }
+    }
+
+    /// Open arceos_vdev device, syscall forwarding is disabled if it is not available.
+    fn set_up_scf_events(&mut self) -> impl Stream<Item = Event> {
+        let (scf_tx, scf_rx) = mpsc::channel(1);
+
+        match crate::scf::open_arceos_vdev(scf_tx.clone()) {
+            Ok(scf) => {
+                self.scf = Some(scf);
+                // Process requests pushed before we are ready.
+                let _ = scf_tx.try_send(Event::SCF);
+            }
+            Err(err) => info!(
+                "{} syscall forwarding is disabled, {err:?}",
+                "AxDaemon".bold().green()
+            ),
+        }
+
+        ReceiverStream::new(scf_rx)
+    }
+
+    fn handle_scf_event(&mut self) {
+        let Some((queue, handler)) = self.scf.as_mut() else {
+            return;
+        };
+        match handler.poll_requests(queue) {
+            Ok(handled) => trace!("handled {handled} forwarded syscalls"),
+            Err(err) => warn!("failed to handle forwarded syscalls {err:?}"),
         }
-        Ok(())
     }
 
     async fn handle_vmm_event(&mut self, event: VMMEventWrapper) -> AxResult {
         match event {
             VMMEventWrapper { request, reply_tx } => {
-                let result = self.vmm.handle_daemon_request(request);
-                let reply = DaemonReply::Result(result.map_err(|err| err.to_string()));
+                let reply = match self.vmm.handle_daemon_request(request) {
+                    Ok(reply) => reply,
+                    Err(err) => DaemonReply::Result(Err(err.to_string())),
+                };
                 let _ = reply_tx.send(Some(reply)).map_err(|_| {
                     error!("could not send node info reply from daemon to coordinator")
                 });
@@ -70,15 +233,15 @@ impl Daemon {
     }
 }
 
-pub fn run(listen_addr: IpAddr, listen_port: u16) -> AxResult {
+/// Run axdaemon, listening on Unix socket `socket_path` and also on `tcp_bind` if provided.
+pub fn run(socket_path: PathBuf, tcp_bind: Option<SocketAddr>) -> AxResult {
     let rt = Builder::new_multi_thread()
         .enable_all()
         .build()
         .map_err(|err| ax_err_type!(BadState, format!("tokio runtime failed, {err:?}")))?;
     rt.block_on(async {
-        let bind = SocketAddr::new(listen_addr, listen_port);
         let mut daemon = Daemon::init();
-        daemon.run(bind).await
+        daemon.run(socket_path, tcp_bind).await
     })
 }
 
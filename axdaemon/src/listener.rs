@@ -1,18 +1,29 @@
 use std::io::ErrorKind;
 use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
 
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::oneshot;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tokio::sync::{oneshot, watch};
 
-use axdaemon_request::DaemonRequest;
+use axdaemon_request::{DaemonHello, DaemonRequest};
 use axerrno::{ax_err, ax_err_type, AxResult};
 
 use crate::tcp_utils::{tcp_receive, tcp_send};
 use crate::vmm::VMMEventWrapper;
 
+/// Permission of the Unix socket, only owner and its group are allowed to connect.
+const UNIX_SOCKET_MODE: u32 = 0o660;
+
+/// Spawn the loop accepting TCP connections from axcli.
+///
+/// The loop stops accepting new connections once `true` is sent through `shutdown_rx`,
+/// existing connections are closed after their in-flight requests are replied.
 pub async fn spawn_listener_loop(
     bind: SocketAddr,
     events_tx: flume::Sender<VMMEventWrapper>,
+    shutdown_rx: watch::Receiver<bool>,
 ) -> AxResult<u16> {
     let socket = match TcpListener::bind(bind).await {
         Ok(socket) => socket,
@@ -31,36 +42,193 @@ pub async fn spawn_listener_loop(
         .port();
 
     tokio::spawn(async move {
-        listener_loop(socket, events_tx).await;
+        listener_loop(socket, events_tx, shutdown_rx).await;
         debug!("Local listener loop finished");
     });
 
     Ok(listen_port)
 }
 
-async fn listener_loop(listener: TcpListener, events_tx: flume::Sender<VMMEventWrapper>) {
+async fn listener_loop(
+    listener: TcpListener,
+    events_tx: flume::Sender<VMMEventWrapper>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
     loop {
-        match listener.accept().await {
+        let accepted = tokio::select! {
+            _ = shutdown_rx.changed() => break,
+            accepted = listener.accept() => accepted,
+        };
+        match accepted {
             Err(err) => {
                 warn!("TcpListen accept err {:?}", err);
             }
             Ok((connection, _)) => {
-                tokio::spawn(handle_connection_loop(connection, events_tx.clone()));
+                if let Err(err) = connection.set_nodelay(true) {
+                    warn!("failed to set nodelay for connection: {err}");
+                }
+                tokio::spawn(handle_connection_loop(
+                    connection,
+                    events_tx.clone(),
+                    shutdown_rx.clone(),
+                ));
+            }
+        }
+    }
+}
+
+/// Spawn the loop accepting Unix socket connections from axcli.
+///
+/// The socket is created with `UNIX_SOCKET_MODE`, and peers are checked through `SO_PEERCRED`,
+/// see `is_peer_allowed`. The socket file is removed once the loop stops.
+pub async fn spawn_unix_listener_loop(
+    path: PathBuf,
+    events_tx: flume::Sender<VMMEventWrapper>,
+    shutdown_rx: watch::Receiver<bool>,
+) -> AxResult {
+    remove_stale_socket(&path)?;
+
+    // The socket file takes its permission from umask, so it is never accessible to others.
+    // umask is process-wide, no other file is created by axdaemon while it is starting.
+    let umask = unsafe { libc::umask(!UNIX_SOCKET_MODE & 0o777) };
+    let socket = UnixListener::bind(&path);
+    unsafe { libc::umask(umask) };
+    let socket = socket.map_err(|err| {
+        ax_err_type!(
+            BadState,
+            format!("failed to bind Unix socket {path:?}, {err:?}")
+        )
+    })?;
+    let socket_gid = std::fs::metadata(&path)
+        .map_err(|err| ax_err_type!(BadState, format!("failed to stat {path:?}, {err:?}")))?
+        .gid();
+
+    tokio::spawn(async move {
+        unix_listener_loop(socket, socket_gid, events_tx, shutdown_rx).await;
+        if let Err(err) = std::fs::remove_file(&path) {
+            warn!("failed to remove Unix socket {path:?}: {err}");
+        }
+        debug!("Unix socket listener loop finished");
+    });
+
+    Ok(())
+}
+
+/// Remove socket file left by a previous axdaemon, refuse to remove other kinds of files.
+fn remove_stale_socket(path: &Path) -> AxResult {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            std::fs::remove_file(path).map_err(|err| {
+                ax_err_type!(
+                    BadState,
+                    format!("failed to remove stale socket {path:?}, {err:?}")
+                )
+            })
+        }
+        Ok(_) => ax_err!(
+            AlreadyExists,
+            format!("{path:?} exists and is not a socket")
+        ),
+        Err(_) => Ok(()),
+    }
+}
+
+async fn unix_listener_loop(
+    listener: UnixListener,
+    socket_gid: u32,
+    events_tx: flume::Sender<VMMEventWrapper>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            _ = shutdown_rx.changed() => break,
+            accepted = listener.accept() => accepted,
+        };
+        match accepted {
+            Err(err) => {
+                warn!("UnixListener accept err {:?}", err);
+            }
+            Ok((connection, _)) => {
+                if !is_peer_allowed(&connection, socket_gid) {
+                    continue;
+                }
+                tokio::spawn(handle_connection_loop(
+                    connection,
+                    events_tx.clone(),
+                    shutdown_rx.clone(),
+                ));
             }
         }
     }
 }
 
+/// Only root, the user running axdaemon, and members of the group owning the socket,
+/// also through supplementary groups, are allowed.
+fn is_peer_allowed(connection: &UnixStream, socket_gid: u32) -> bool {
+    let cred = match connection.peer_cred() {
+        Ok(cred) => cred,
+        Err(err) => {
+            warn!("failed to get peer credential, connection rejected: {err}");
+            return false;
+        }
+    };
+    let daemon_uid = unsafe { libc::geteuid() };
+    if cred.uid() == 0 || cred.uid() == daemon_uid || cred.gid() == socket_gid {
+        return true;
+    }
+    match cred.pid().map(supplementary_groups) {
+        Some(Ok(groups)) if groups.contains(&socket_gid) => return true,
+        Some(Ok(_)) => {}
+        Some(Err(err)) => warn!("failed to get groups of peer: {err}"),
+        None => warn!("pid of peer is unknown"),
+    }
+    warn!(
+        "connection from uid {} gid {} pid {:?} rejected",
+        cred.uid(),
+        cred.gid(),
+        cred.pid()
+    );
+    false
+}
+
+/// Supplementary groups of process `pid`, from `Groups` in `/proc/<pid>/status`.
+fn supplementary_groups(pid: i32) -> std::io::Result<Vec<u32>> {
+    let status = std::fs::read_to_string(format!("/proc/{pid}/status"))?;
+    let groups = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "no Groups in status"))?;
+    groups
+        .split_whitespace()
+        .map(|gid| {
+            gid.parse()
+                .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))
+        })
+        .collect()
+}
+
 async fn handle_connection_loop(
-    mut connection: TcpStream,
+    mut connection: impl AsyncRead + AsyncWrite + Unpin,
     events_tx: flume::Sender<VMMEventWrapper>,
+    mut shutdown_rx: watch::Receiver<bool>,
 ) {
-    if let Err(err) = connection.set_nodelay(true) {
-        warn!("failed to set nodelay for connection: {err}");
+    // A peer which never says hello is dropped on shutdown, not waited for by the drain.
+    let handshake = tokio::select! {
+        _ = shutdown_rx.changed() => return,
+        result = handshake(&mut connection) => result,
+    };
+    if let Err(err) = handshake {
+        warn!("{err:?}");
+        return;
     }
 
     loop {
-        match receive_message(&mut connection).await {
+        // Only wait for new requests here, so that an in-flight request is always replied.
+        let message = tokio::select! {
+            _ = shutdown_rx.changed() => break,
+            message = receive_message(&mut connection) => message,
+        };
+        match message {
             Ok(Some(daemon_request)) => {
                 let (reply_tx, reply_rx) = oneshot::channel();
                 if events_tx
@@ -107,7 +275,44 @@ async fn handle_connection_loop(
     }
 }
 
-async fn receive_message(connection: &mut TcpStream) -> AxResult<Option<DaemonRequest>> {
+/// Receive `DaemonHello` from axcli and reply ours, the connection is closed if incompatible.
+///
+/// Our hello is replied even on mismatch, so that axcli could report the version of axdaemon.
+async fn handshake(connection: &mut (impl AsyncRead + AsyncWrite + Unpin)) -> AxResult {
+    let raw = tcp_receive(connection)
+        .await
+        .map_err(|err| ax_err_type!(BadState, format!("failed to receive DaemonHello, {err:?}")))?;
+    let hello = bincode::deserialize::<DaemonHello>(&raw).map_err(|err| {
+        ax_err_type!(
+            Unsupported,
+            format!("failed to deserialize DaemonHello, peer may be too old, {err:?}")
+        )
+    });
+
+    let serialized = bincode::serialize(&DaemonHello::default()).map_err(|err| {
+        ax_err_type!(
+            InvalidData,
+            format!("failed to serialize DaemonHello {err:?}")
+        )
+    })?;
+    tcp_send(connection, &serialized)
+        .await
+        .map_err(|err| ax_err_type!(BadState, format!("failed to send DaemonHello {err:?}")))?;
+
+    let hello = hello?;
+    hello
+        .check_compatible()
+        .map_err(|err| ax_err_type!(Unsupported, format!("connection rejected, {err}")))?;
+    debug!(
+        "axcli connected, protocol version {}, features {:?}",
+        hello.version, hello.features
+    );
+    Ok(())
+}
+
+async fn receive_message(
+    connection: &mut (impl AsyncRead + Unpin),
+) -> AxResult<Option<DaemonRequest>> {
     let raw = match tcp_receive(connection).await {
         Ok(raw) => raw,
         Err(err) => match err.kind() {
@@ -115,7 +320,7 @@ async fn receive_message(connection: &mut TcpStream) -> AxResult<Option<DaemonRe
             | ErrorKind::ConnectionAborted
             | ErrorKind::ConnectionReset => return Ok(None),
             _other => {
-                warn!("tcp recive err {:?}", err);
+                warn!("recive err {:?}", err);
                 return ax_err!(
                     BadState,
                     "unexpected I/O error while trying to receive DaemonRequest"
@@ -130,3 +335,68 @@ async fn receive_message(connection: &mut TcpStream) -> AxResult<Option<DaemonRe
         })
         .map(Some)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use axdisk::test_utils::TempDir;
+
+    #[tokio::test]
+    async fn unix_socket_permission() {
+        let dir = TempDir::new();
+        let path = dir.path().join("axdaemon.sock");
+        let (events_tx, _events_rx) = flume::bounded(1);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        spawn_unix_listener_loop(path.clone(), events_tx, shutdown_rx)
+            .await
+            .unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, UNIX_SOCKET_MODE);
+
+        // A socket left behind is replaced, other files are kept.
+        let other = dir.file("other", b"not a socket");
+        assert!(remove_stale_socket(&other).is_err());
+        assert!(other.exists());
+
+        shutdown_tx.send(true).unwrap();
+        for _ in 0..100 {
+            if !path.exists() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("socket {path:?} is not removed after shutdown");
+    }
+
+    #[tokio::test]
+    async fn idle_connection_closed_on_shutdown() {
+        let (events_tx, _events_rx) = flume::bounded(1);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        // Peer connected but never sends its hello.
+        let (_peer, connection) = tokio::io::duplex(64);
+        let task = tokio::spawn(handle_connection_loop(connection, events_tx, shutdown_rx));
+
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        shutdown_tx.send(true).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(1), task)
+            .await
+            .expect("idle connection is kept after shutdown")
+            .unwrap();
+    }
+
+    #[test]
+    fn groups_of_current_process() {
+        let mut expect = vec![0; 256];
+        let len = unsafe { libc::getgroups(expect.len() as i32, expect.as_mut_ptr()) };
+        assert!(len >= 0);
+        expect.truncate(len as usize);
+
+        let groups = supplementary_groups(std::process::id() as i32).unwrap();
+        assert_eq!(groups, expect);
+        assert!(supplementary_groups(-1).is_err());
+    }
+}
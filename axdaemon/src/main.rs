@@ -3,10 +3,12 @@
 use axerrno::AxResult;
 use clap::Parser;
 use colored::Colorize;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 
 mod daemon;
 mod listener;
+mod scf;
 mod tcp_utils;
 mod uio;
 mod vdev;
@@ -25,16 +27,22 @@ struct Args {
     command: Command,
 }
 
-/// AxDaemon: Daemon process of arceos-hypervisor for VMM support. 
+/// AxDaemon: Daemon process of arceos-hypervisor for VMM support.
 #[derive(Debug, clap::Subcommand)]
 enum Command {
-    /// Start daemon, make sure to run under **sudo** privilege for mmap related operations. 
+    /// Start daemon, make sure to run under **sudo** privilege for mmap related operations.
     Init {
-        /// Address of the dora coordinator
-        #[clap(long, value_name = "IP", default_value_t = LOCALHOST)]
+        /// Path of the Unix socket for axcli
+        #[clap(long, value_name = "PATH", default_value = axdaemon_request::AXDAEMON_SOCKET_PATH_DEFAULT)]
+        socket: PathBuf,
+        /// Also accept TCP connections, e.g. for remote management (NOT authenticated)
+        #[clap(long, action)]
+        tcp: bool,
+        /// Address of the TCP listener, only used with `--tcp`
+        #[clap(long, value_name = "IP", default_value_t = LOCALHOST, requires = "tcp")]
         listen_addr: IpAddr,
-        /// Port number of the coordinator control server
-        #[clap(long, value_name = "PORT", default_value_t = axdaemon_request::ARCEOS_DAEMON_PORT_DEFAULT)]
+        /// Port number of the TCP listener, only used with `--tcp`
+        #[clap(long, value_name = "PORT", default_value_t = axdaemon_request::ARCEOS_DAEMON_PORT_DEFAULT, requires = "tcp")]
         listen_port: u16,
         /// Run the daemon in background
         #[clap(long, action)]
@@ -61,11 +69,14 @@ fn run() -> AxResult {
 
     match args.command {
         Command::Init {
+            socket,
+            tcp,
             listen_addr,
             listen_port,
             detach: _,
         } => {
-            daemon::run(listen_addr, listen_port)?;
+            let tcp_bind = tcp.then(|| SocketAddr::new(listen_addr, listen_port));
+            daemon::run(socket, tcp_bind)?;
         }
     }
 
@@ -0,0 +1,849 @@
+//! Syscall forwarding (SCF) from ArceOS guest, ported from `shadow-process/arceos_scf.c`.
+//!
+//! ArceOS guest pushes `ScfDescriptor`s into the syscall queue buffer and raises a virq,
+//! the `arceos_vdev` driver then notifies axdaemon through signal `ARCEOS_VIRQ_SIG_NUM`.
+//! Arguments of each request are stored in the syscall data buffer.
+
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{fence, AtomicU8, Ordering};
+
+use memmap::{MmapMut, MmapOptions};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+use axerrno::{ax_err, ax_err_type, AxResult};
+
+use crate::daemon::Event;
+
+const ARCEOS_VDEV_PATH: &str = "/dev/arceos_vdev";
+
+/// nimbos use 44, so...
+const ARCEOS_VIRQ_SIG_NUM: i32 = 44;
+
+/// ('A' ^ 'r' + 'c' ^ 'e'), with no collision in ioctl-number.txt
+const ARCEOS_VDEV_IOCTL_MAGIC: libc::c_ulong = 0xF1;
+const ARCEOS_VDEV_IOCTL_REGISTER_VIRQ_HANDLER: libc::c_ulong = ARCEOS_VDEV_IOCTL_MAGIC << 8;
+const ARCEOS_VDEV_IOCTL_UNREGISTER_VIRQ_HANDLER: libc::c_ulong = (ARCEOS_VDEV_IOCTL_MAGIC << 8) | 1;
+const ARCEOS_VDEV_IOCTL_INVOKE_HYPERCALL: libc::c_ulong = (ARCEOS_VDEV_IOCTL_MAGIC << 8) | 2;
+
+/// "Shdw"
+const ARCEOS_HYPERCALL_SHADOW_PROCESS_READY: u32 = 0x5368_6477;
+/// "prcs"
+const ARCEOS_HYPERCALL_SHADOW_PROCESS_READY_ARG0: u32 = 0x7072_6373;
+/// "Rdy!"
+const ARCEOS_HYPERCALL_SHADOW_PROCESS_READY_ARG1: u32 = 0x5264_7921;
+/// "EMap"
+const ARCEOS_HYPERCALL_EPT_MAPPING_REQUEST: u32 = 0x454d_6170;
+
+const ARCEOS_SYSCALL_DATA_BUF_SIZE: usize = 0x0010_0000;
+const ARCEOS_SYSCALL_QUEUE_BUF_SIZE: usize = 0x0000_1000;
+/// The kernel maps the syscall queue buffer for mmap offset of this value.
+const ARCEOS_SYSCALL_QUEUE_BUF_MMAP_OFFSET: u64 = 0x1000;
+/// "\x7fSCF"
+const ARCEOS_SYSCALL_QUEUE_BUF_MAGIC: u32 = 0x4643_537f;
+
+/// Guest physical address allocated for regions mapped by `IPC_OP_SPECIAL_MUST_MMAP`.
+/// Todo: move this to vdev driver, because there might be multiple shadow processes.
+const SHADOW_PROCESS_GPA_BASE: u64 = 0x6000_0000;
+
+const VDISK_BLOCK_SIZE: usize = 512;
+const VDISK_BLOCK_SIZE_SHIFT: u64 = 9;
+const MAX_VDISK: usize = 4;
+
+/// Opcodes of forwarded syscalls, see `enum scf_opcode` in `shadow-process/arceos_scf.h`.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScfOpcode {
+    Read = 0,
+    Write = 1,
+    Open = 2,
+    Close = 3,
+    Writev = 20,
+    /// Hypervisor tells us to mmap a region, params: hpa, va, size.
+    MustMmap = 0xfa,
+    /// Open a virtual disk, params: id, size expected (in blocks).
+    OpenVdisk = 0xfb,
+    /// Read a block from a virtual disk, params: id, block number, buffer offset.
+    ReadVdiskBlock = 0xfc,
+    /// Write a block to a virtual disk, params: id, block number, buffer offset.
+    WriteVdiskBlock = 0xfd,
+    Unknown = 0xfe,
+    Nop = 0xff,
+}
+
+impl From<u8> for ScfOpcode {
+    fn from(opcode: u8) -> Self {
+        match opcode {
+            0 => Self::Read,
+            1 => Self::Write,
+            2 => Self::Open,
+            3 => Self::Close,
+            20 => Self::Writev,
+            0xfa => Self::MustMmap,
+            0xfb => Self::OpenVdisk,
+            0xfc => Self::ReadVdiskBlock,
+            0xfd => Self::WriteVdiskBlock,
+            0xff => Self::Nop,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Header of the syscall queue buffer, followed by (aligned to 8 Bytes)
+/// * `capacity` entries of `ScfDescriptor`.
+/// * request ring of `capacity` descriptor indexes, produced by guest.
+/// * response ring of `capacity` descriptor indexes, produced by axdaemon.
+#[repr(C)]
+struct ScfQueueMeta {
+    magic: u32,
+    lock: u8,
+    capacity: u16,
+    req_index: u16,
+    rsp_index: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ScfDescriptor {
+    pub valid: u8,
+    pub opcode: u8,
+    /// Offset of `ScfArgs` in syscall data buffer.
+    pub args: u64,
+    pub ret_val: u64,
+}
+
+/// Arguments of a forwarded syscall.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct ScfArgs {
+    args: [u64; 6],
+}
+
+const _: () = assert!(core::mem::size_of::<ScfQueueMeta>() == 0xc);
+const _: () = assert!(core::mem::size_of::<ScfDescriptor>() == 0x18);
+
+/// Offset of descriptors in the syscall queue buffer.
+const SCF_DESC_OFFSET: usize = core::mem::size_of::<ScfQueueMeta>().next_multiple_of(8);
+
+/// Syscall queue buffer shared with ArceOS guest.
+#[derive(Debug)]
+pub struct ScfQueue {
+    region: MmapMut,
+    capacity_mask: u16,
+    req_index_last: u16,
+    rsp_index_shadow: u16,
+}
+
+/// Holds the spin lock in `ScfQueueMeta`, which is shared with guest.
+struct ScfQueueGuard {
+    lock: *const AtomicU8,
+}
+
+impl ScfQueueGuard {
+    fn lock(meta: *mut ScfQueueMeta) -> Self {
+        let lock = unsafe { &*(core::ptr::addr_of_mut!((*meta).lock) as *const AtomicU8) };
+        while lock
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while lock.load(Ordering::Relaxed) != 0 {
+                std::thread::yield_now();
+            }
+        }
+        Self { lock }
+    }
+}
+
+impl Drop for ScfQueueGuard {
+    fn drop(&mut self) {
+        unsafe { &*self.lock }.store(0, Ordering::Release);
+    }
+}
+
+impl ScfQueue {
+    /// Check the header initialized by guest and take over the queue.
+    pub fn new(region: MmapMut) -> AxResult<Self> {
+        if region.len() < SCF_DESC_OFFSET {
+            return ax_err!(InvalidInput, "syscall queue buffer too small");
+        }
+        let meta = region.as_ptr() as *const ScfQueueMeta;
+        let (magic, capacity, rsp_index) = unsafe {
+            (
+                core::ptr::addr_of!((*meta).magic).read_volatile(),
+                core::ptr::addr_of!((*meta).capacity).read_volatile(),
+                core::ptr::addr_of!((*meta).rsp_index).read_volatile(),
+            )
+        };
+
+        if magic != ARCEOS_SYSCALL_QUEUE_BUF_MAGIC {
+            return ax_err!(
+                InvalidData,
+                format!("bad syscall queue buffer magic {magic:#x}")
+            );
+        }
+        let size = SCF_DESC_OFFSET
+            + capacity as usize
+                * (core::mem::size_of::<ScfDescriptor>() + 2 * core::mem::size_of::<u16>());
+        if !capacity.is_power_of_two() || size > region.len() {
+            return ax_err!(
+                InvalidData,
+                format!("bad syscall queue buffer capacity {capacity}")
+            );
+        }
+
+        Ok(Self {
+            region,
+            capacity_mask: capacity - 1,
+            req_index_last: 0,
+            rsp_index_shadow: rsp_index,
+        })
+    }
+
+    fn meta(&self) -> *mut ScfQueueMeta {
+        self.region.as_ptr() as *mut ScfQueueMeta
+    }
+
+    fn desc(&self, index: u16) -> *mut ScfDescriptor {
+        let base = unsafe { self.region.as_ptr().add(SCF_DESC_OFFSET) } as *mut ScfDescriptor;
+        unsafe { base.add((index & self.capacity_mask) as usize) }
+    }
+
+    fn req_ring(&self, index: u16) -> *mut u16 {
+        let capacity = self.capacity_mask as usize + 1;
+        let base = unsafe {
+            (self.desc(0) as *mut u8).add(capacity * core::mem::size_of::<ScfDescriptor>())
+        } as *mut u16;
+        unsafe { base.add((index & self.capacity_mask) as usize) }
+    }
+
+    fn rsp_ring(&self, index: u16) -> *mut u16 {
+        let capacity = self.capacity_mask as usize + 1;
+        unsafe {
+            self.req_ring(0)
+                .add(capacity + (index & self.capacity_mask) as usize)
+        }
+    }
+
+    /// Pop a request from guest, return its descriptor index and a copy of the descriptor.
+    pub fn pop_request(&mut self) -> AxResult<Option<(u16, ScfDescriptor)>> {
+        let _guard = ScfQueueGuard::lock(self.meta());
+
+        let req_index = unsafe { core::ptr::addr_of!((*self.meta()).req_index).read_volatile() };
+        if self.req_index_last == req_index {
+            return Ok(None);
+        }
+        fence(Ordering::SeqCst);
+
+        let index = unsafe { self.req_ring(self.req_index_last).read_volatile() };
+        if index > self.capacity_mask {
+            return ax_err!(
+                InvalidData,
+                format!("bad syscall request descriptor index {index}")
+            );
+        }
+        let desc = unsafe { self.desc(index).read_volatile() };
+        self.req_index_last = self.req_index_last.wrapping_add(1);
+        Ok(Some((index, desc)))
+    }
+
+    /// Push the return value of the request at descriptor `index` to guest.
+    pub fn push_response(&mut self, index: u16, ret_val: u64) -> AxResult {
+        let _guard = ScfQueueGuard::lock(self.meta());
+
+        if index > self.capacity_mask {
+            return ax_err!(
+                InvalidInput,
+                format!("bad syscall response descriptor index {index}")
+            );
+        }
+        unsafe {
+            core::ptr::addr_of_mut!((*self.desc(index)).ret_val).write_volatile(ret_val);
+            self.rsp_ring(self.rsp_index_shadow).write_volatile(index);
+        }
+        self.rsp_index_shadow = self.rsp_index_shadow.wrapping_add(1);
+        fence(Ordering::SeqCst);
+        unsafe {
+            core::ptr::addr_of_mut!((*self.meta()).rsp_index).write_volatile(self.rsp_index_shadow)
+        };
+        Ok(())
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct ArceOSVdevHypercallArgs {
+    id: u32,
+    return_value: u32,
+    arg0: u32,
+    arg1: u32,
+    arg2: u32,
+    arg3: u32,
+    arg4: u32,
+    reserved: u32,
+}
+
+/// The `arceos_vdev` device with our virq handler registered, which is unregistered when
+/// the device is dropped.
+#[derive(Debug)]
+struct ArceosVdev {
+    file: File,
+}
+
+impl ArceosVdev {
+    /// Register virq handler of `file`, so that virqs are notified by signal.
+    fn register(file: File) -> AxResult<Self> {
+        if unsafe { libc::ioctl(file.as_raw_fd(), ARCEOS_VDEV_IOCTL_REGISTER_VIRQ_HANDLER, 0) } < 0
+        {
+            return ax_err!(
+                BadState,
+                format!(
+                    "failed to register virq handler, {}",
+                    std::io::Error::last_os_error()
+                )
+            );
+        }
+        Ok(Self { file })
+    }
+}
+
+impl Drop for ArceosVdev {
+    fn drop(&mut self) {
+        unsafe {
+            libc::ioctl(
+                self.file.as_raw_fd(),
+                ARCEOS_VDEV_IOCTL_UNREGISTER_VIRQ_HANDLER,
+                0,
+            )
+        };
+    }
+}
+
+/// Executes forwarded syscalls on behalf of ArceOS guest.
+///
+/// Buffers passed by guest are only accessed if they lie in the syscall data buffer
+/// or in regions mapped through `IPC_OP_SPECIAL_MUST_MMAP`.
+#[derive(Debug)]
+pub struct ScfHandler {
+    data_buf: MmapMut,
+    /// The `arceos_vdev` device, used for hypercalls and mmap, `None` if not available.
+    /// Dropped after regions mapped from it are unmapped.
+    vdev: Option<ArceosVdev>,
+    /// Regions (va, size) mapped through `IPC_OP_SPECIAL_MUST_MMAP`.
+    mapped_regions: Vec<(usize, usize)>,
+    next_gpa: u64,
+    vdisks: [Option<File>; MAX_VDISK],
+}
+
+impl ScfHandler {
+    fn new(data_buf: MmapMut, vdev: Option<ArceosVdev>) -> Self {
+        Self {
+            data_buf,
+            vdev,
+            mapped_regions: Vec::new(),
+            next_gpa: SHADOW_PROCESS_GPA_BASE,
+            vdisks: Default::default(),
+        }
+    }
+
+    /// Handle all pending requests in `queue`, return the number of requests handled.
+    pub fn poll_requests(&mut self, queue: &mut ScfQueue) -> AxResult<usize> {
+        let mut handled = 0;
+        while let Some((index, desc)) = queue.pop_request()? {
+            let ret_val = match self.handle_request(&desc) {
+                Ok(ret) => ret,
+                Err(errno) => -(errno as i64),
+            };
+            queue.push_response(index, ret_val as u64)?;
+            handled += 1;
+        }
+        Ok(handled)
+    }
+
+    /// Handle a single request, return its return value or errno.
+    fn handle_request(&mut self, desc: &ScfDescriptor) -> Result<i64, i32> {
+        let args = self.read_args(desc.args)?.args;
+        let opcode = ScfOpcode::from(desc.opcode);
+        trace!("SCF request {opcode:?} args {args:x?}");
+
+        match opcode {
+            ScfOpcode::Read => {
+                let buf = self.guest_buf(args[1], args[2] as usize)?;
+                let ret = unsafe { libc::read(args[0] as i32, buf.as_mut_ptr() as _, buf.len()) };
+                check_ret(ret as i64)
+            }
+            ScfOpcode::Write | ScfOpcode::Writev => {
+                let buf = self.guest_buf(args[1], args[2] as usize)?;
+                let ret = unsafe { libc::write(args[0] as i32, buf.as_ptr() as _, buf.len()) };
+                check_ret(ret as i64)
+            }
+            ScfOpcode::Open => {
+                let path = self.guest_cstr(args[0])?;
+                let ret = unsafe { libc::open(path.as_ptr(), args[1] as i32, args[2] as u32) };
+                check_ret(ret as i64)
+            }
+            ScfOpcode::Close => check_ret(unsafe { libc::close(args[0] as i32) } as i64),
+            ScfOpcode::MustMmap => self.must_mmap(args[0], args[1] as usize, args[2] as u32),
+            ScfOpcode::OpenVdisk => {
+                let vdisk = self.vdisks.get_mut(args[0] as usize).ok_or(libc::EINVAL)?;
+                if vdisk.is_some() {
+                    return Err(libc::EBUSY);
+                }
+                let path = format!("vdisk-{:4x}.img", args[0]);
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(path)
+                    .map_err(io_errno)?;
+                *vdisk = Some(file);
+                Ok(0)
+            }
+            ScfOpcode::ReadVdiskBlock | ScfOpcode::WriteVdiskBlock => {
+                let offset = args[1]
+                    .checked_shl(VDISK_BLOCK_SIZE_SHIFT as u32)
+                    .ok_or(libc::EINVAL)?;
+                let buf_offset = args[2] as usize;
+                let buf = self
+                    .data_buf
+                    .get_mut(buf_offset..buf_offset.saturating_add(VDISK_BLOCK_SIZE))
+                    .ok_or(libc::EFAULT)?;
+                let vdisk = self
+                    .vdisks
+                    .get(args[0] as usize)
+                    .and_then(|vdisk| vdisk.as_ref())
+                    .ok_or(libc::EINVAL)?;
+                let len = if opcode == ScfOpcode::ReadVdiskBlock {
+                    vdisk.read_at(buf, offset)
+                } else {
+                    vdisk.write_at(buf, offset)
+                }
+                .map_err(io_errno)?;
+                Ok(len as i64)
+            }
+            ScfOpcode::Nop => Ok(0),
+            ScfOpcode::Unknown => {
+                warn!("unknown SCF opcode {:#x}", desc.opcode);
+                Err(libc::ENOSYS)
+            }
+        }
+    }
+
+    fn read_args(&self, offset: u64) -> Result<ScfArgs, i32> {
+        let offset = offset as usize;
+        let raw = self
+            .data_buf
+            .get(offset..offset.saturating_add(core::mem::size_of::<ScfArgs>()))
+            .ok_or(libc::EFAULT)?;
+        Ok(unsafe { (raw.as_ptr() as *const ScfArgs).read_unaligned() })
+    }
+
+    /// Translate a guest buffer address, which must lie in a known region, into a slice.
+    fn guest_buf(&mut self, addr: u64, len: usize) -> Result<&mut [u8], i32> {
+        let addr = addr as usize;
+        let end = addr.checked_add(len).ok_or(libc::EFAULT)?;
+        let data_buf = (self.data_buf.as_ptr() as usize, self.data_buf.len());
+        let known = core::iter::once(data_buf)
+            .chain(self.mapped_regions.iter().copied())
+            .any(|(base, size)| addr >= base && end <= base + size);
+        if !known {
+            return Err(libc::EFAULT);
+        }
+        Ok(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) })
+    }
+
+    /// Translate a guest C string address, which must lie in a known region.
+    fn guest_cstr(&mut self, addr: u64) -> Result<&CStr, i32> {
+        let data_buf = (self.data_buf.as_ptr() as usize, self.data_buf.len());
+        let (base, size) = core::iter::once(data_buf)
+            .chain(self.mapped_regions.iter().copied())
+            .find(|&(base, size)| (addr as usize) >= base && (addr as usize) < base + size)
+            .ok_or(libc::EFAULT)?;
+        let buf = self.guest_buf(addr, base + size - addr as usize)?;
+        CStr::from_bytes_until_nul(buf).map_err(|_| libc::EFAULT)
+    }
+
+    /// Request EPT mapping of `hpa` for axdaemon, then map it at `va`.
+    fn must_mmap(&mut self, hpa: u64, va: usize, size: u32) -> Result<i64, i32> {
+        let vdev = &self.vdev.as_ref().ok_or(libc::ENODEV)?.file;
+
+        // Round size up to 4KB.
+        let size = (size as u64).next_multiple_of(0x1000);
+        let gpa = self.next_gpa;
+
+        debug!("shadow mapping: hpa={hpa:#x}, gpa={gpa:#x}, va={va:#x}, size={size:#x}");
+        let ret = send_hypercall(
+            vdev,
+            ARCEOS_HYPERCALL_EPT_MAPPING_REQUEST,
+            [
+                (hpa >> 32) as u32,
+                hpa as u32,
+                (gpa >> 32) as u32,
+                gpa as u32,
+                size as u32,
+            ],
+        )
+        .map_err(|err| err.raw_os_error().unwrap_or(libc::EIO))?;
+        if ret != 0 {
+            warn!("EPT mapping request failed: {ret}");
+            return Err(libc::EFAULT);
+        }
+        self.next_gpa += size;
+
+        // Never replace existing mappings of axdaemon itself.
+        let addr = unsafe {
+            libc::mmap(
+                va as *mut libc::c_void,
+                size as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_FIXED_NOREPLACE,
+                vdev.as_raw_fd(),
+                gpa as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io_errno(std::io::Error::last_os_error()));
+        }
+        self.mapped_regions.push((va, size as usize));
+        Ok(0)
+    }
+}
+
+impl Drop for ScfHandler {
+    fn drop(&mut self) {
+        for (va, size) in self.mapped_regions.drain(..) {
+            unsafe { libc::munmap(va as *mut libc::c_void, size) };
+        }
+    }
+}
+
+/// Open `arceos_vdev` device and map syscall buffers, then tell hypervisor we are ready.
+///
+/// Signals from the device are sent as `Event::SCF`.
+pub fn open_arceos_vdev(scf_tx: mpsc::Sender<Event>) -> AxResult<(ScfQueue, ScfHandler)> {
+    let vdev = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(ARCEOS_VDEV_PATH)
+        .map_err(|err| {
+            ax_err_type!(
+                NotFound,
+                format!("failed to open {ARCEOS_VDEV_PATH}, {err:?}")
+            )
+        })?;
+
+    // Listen to the signal before registering, so that no virq is lost.
+    let mut signals = signal(SignalKind::from_raw(ARCEOS_VIRQ_SIG_NUM))
+        .map_err(|err| ax_err_type!(BadState, format!("failed to listen to signal {err:?}")))?;
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            if scf_tx.send(Event::SCF).await.is_err() {
+                break;
+            }
+        }
+        debug!("SCF signal loop finished");
+    });
+
+    // The virq handler is unregistered once `vdev` is dropped, also on errors below.
+    let vdev = ArceosVdev::register(vdev)?;
+    let data_buf = unsafe {
+        MmapOptions::new()
+            .len(ARCEOS_SYSCALL_DATA_BUF_SIZE)
+            .map_mut(&vdev.file)
+    }
+    .map_err(|err| ax_err_type!(NoMemory, format!("failed to mmap data buffer {err:?}")))?;
+    let handler = ScfHandler::new(data_buf, Some(vdev));
+    let vdev = &handler.vdev.as_ref().unwrap().file;
+
+    let queue_buf = unsafe {
+        MmapOptions::new()
+            .offset(ARCEOS_SYSCALL_QUEUE_BUF_MMAP_OFFSET)
+            .len(ARCEOS_SYSCALL_QUEUE_BUF_SIZE)
+            .map_mut(vdev)
+    }
+    .map_err(|err| ax_err_type!(NoMemory, format!("failed to mmap queue buffer {err:?}")))?;
+    let queue = ScfQueue::new(queue_buf)?;
+
+    send_hypercall(
+        vdev,
+        ARCEOS_HYPERCALL_SHADOW_PROCESS_READY,
+        [
+            ARCEOS_HYPERCALL_SHADOW_PROCESS_READY_ARG0,
+            ARCEOS_HYPERCALL_SHADOW_PROCESS_READY_ARG1,
+            0,
+            0,
+            0,
+        ],
+    )
+    .map_err(|err| ax_err_type!(BadState, format!("shadow process ready hypercall {err:?}")))?;
+
+    info!("open {ARCEOS_VDEV_PATH}, syscall forwarding is ready");
+
+    Ok((queue, handler))
+}
+
+fn send_hypercall(vdev: &File, id: u32, args: [u32; 5]) -> std::io::Result<u32> {
+    let mut hypercall_args = ArceOSVdevHypercallArgs {
+        id,
+        arg0: args[0],
+        arg1: args[1],
+        arg2: args[2],
+        arg3: args[3],
+        arg4: args[4],
+        ..Default::default()
+    };
+    let ret = unsafe {
+        libc::ioctl(
+            vdev.as_raw_fd(),
+            ARCEOS_VDEV_IOCTL_INVOKE_HYPERCALL,
+            &mut hypercall_args as *mut ArceOSVdevHypercallArgs,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(hypercall_args.return_value)
+}
+
+fn check_ret(ret: i64) -> Result<i64, i32> {
+    if ret < 0 {
+        Err(std::io::Error::last_os_error()
+            .raw_os_error()
+            .unwrap_or(libc::EIO))
+    } else {
+        Ok(ret)
+    }
+}
+
+fn io_errno(err: std::io::Error) -> i32 {
+    err.raw_os_error().unwrap_or(libc::EIO)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+    use axdisk::test_utils::TempDir;
+
+    const CAPACITY: u16 = 4;
+    /// Offset of arguments in data buffer, other space holds buffers of syscalls.
+    const ARGS_OFFSET: usize = 0;
+    const BUF_OFFSET: usize = 0x1000;
+
+    /// ArceOS guest pushing requests into queue and data buffers on anonymous mappings.
+    struct FakeGuest {
+        queue: *mut u8,
+        data: *mut u8,
+        /// Next descriptor to use, and the response expected next.
+        next_desc: u16,
+        rsp_index: u16,
+    }
+
+    impl FakeGuest {
+        /// Initialize the queue buffer like guest, return the queue and handler of axdaemon.
+        fn new() -> (Self, ScfQueue, ScfHandler) {
+            let mut queue_buf = MmapOptions::new()
+                .len(ARCEOS_SYSCALL_QUEUE_BUF_SIZE)
+                .map_anon()
+                .unwrap();
+            let mut data_buf = MmapOptions::new().len(0x10000).map_anon().unwrap();
+            let guest = Self {
+                queue: queue_buf.as_mut_ptr(),
+                data: data_buf.as_mut_ptr(),
+                next_desc: 0,
+                rsp_index: 0,
+            };
+            let meta = guest.queue as *mut ScfQueueMeta;
+            unsafe {
+                (*meta).magic = ARCEOS_SYSCALL_QUEUE_BUF_MAGIC;
+                (*meta).capacity = CAPACITY;
+            }
+            let queue = ScfQueue::new(queue_buf).unwrap();
+            (guest, queue, ScfHandler::new(data_buf, None))
+        }
+
+        /// Address of `offset` in data buffer, as passed in arguments.
+        fn addr(&self, offset: usize) -> u64 {
+            self.data as u64 + offset as u64
+        }
+
+        fn data(&mut self, offset: usize, len: usize) -> &mut [u8] {
+            unsafe { core::slice::from_raw_parts_mut(self.data.add(offset), len) }
+        }
+
+        fn push(&mut self, opcode: u8, args: [u64; 6]) -> u16 {
+            let index = self.next_desc % CAPACITY;
+            self.next_desc = self.next_desc.wrapping_add(1);
+            let args_offset = ARGS_OFFSET + index as usize * core::mem::size_of::<ScfArgs>();
+            unsafe {
+                (self.data.add(args_offset) as *mut ScfArgs).write_unaligned(ScfArgs { args });
+                let desc = self.queue.add(SCF_DESC_OFFSET) as *mut ScfDescriptor;
+                desc.add(index as usize).write_volatile(ScfDescriptor {
+                    valid: 1,
+                    opcode,
+                    args: args_offset as u64,
+                    ret_val: 0,
+                });
+
+                let meta = self.queue as *mut ScfQueueMeta;
+                let _guard = ScfQueueGuard::lock(meta);
+                let req_index = (*meta).req_index;
+                let req_ring = desc.add(CAPACITY as usize) as *mut u16;
+                req_ring
+                    .add((req_index % CAPACITY) as usize)
+                    .write_volatile(index);
+                (*meta).req_index = req_index.wrapping_add(1);
+            }
+            index
+        }
+
+        /// Pop the next response, return its descriptor index and return value.
+        fn pop(&mut self) -> Option<(u16, i64)> {
+            unsafe {
+                let meta = self.queue as *mut ScfQueueMeta;
+                let _guard = ScfQueueGuard::lock(meta);
+                if (*meta).rsp_index == self.rsp_index {
+                    return None;
+                }
+                let desc = self.queue.add(SCF_DESC_OFFSET) as *mut ScfDescriptor;
+                let rsp_ring = desc.add(CAPACITY as usize) as *mut u16;
+                let index = rsp_ring
+                    .add((CAPACITY + self.rsp_index % CAPACITY) as usize)
+                    .read_volatile();
+                self.rsp_index = self.rsp_index.wrapping_add(1);
+                Some((index, (*desc.add(index as usize)).ret_val as i64))
+            }
+        }
+
+        /// Forward a syscall and let axdaemon handle it, return the return value.
+        fn call(
+            &mut self,
+            queue: &mut ScfQueue,
+            handler: &mut ScfHandler,
+            opcode: ScfOpcode,
+            args: [u64; 6],
+        ) -> i64 {
+            let index = self.push(opcode as u8, args);
+            assert_eq!(handler.poll_requests(queue).unwrap(), 1);
+            let (rsp, ret_val) = self.pop().unwrap();
+            assert_eq!(rsp, index);
+            assert_eq!(self.pop(), None);
+            ret_val
+        }
+    }
+
+    #[test]
+    fn reject_bad_queue() {
+        let mut region = MmapOptions::new().len(0x1000).map_anon().unwrap();
+        assert_eq!(
+            ScfQueue::new(MmapOptions::new().len(0x1000).map_anon().unwrap()).err(),
+            Some(axerrno::AxError::InvalidData)
+        );
+
+        let meta = region.as_mut_ptr() as *mut ScfQueueMeta;
+        unsafe {
+            (*meta).magic = ARCEOS_SYSCALL_QUEUE_BUF_MAGIC;
+            (*meta).capacity = 3;
+        }
+        assert!(ScfQueue::new(region).is_err());
+    }
+
+    #[test]
+    fn forward_file_syscalls() {
+        let dir = TempDir::new();
+        let (mut guest, mut queue, mut handler) = FakeGuest::new();
+
+        let path = CString::new(
+            dir.path()
+                .join("file")
+                .into_os_string()
+                .into_encoded_bytes(),
+        )
+        .unwrap();
+        let path_len = path.as_bytes_with_nul().len();
+        guest
+            .data(BUF_OFFSET, path_len)
+            .copy_from_slice(path.as_bytes_with_nul());
+        let flags = (libc::O_RDWR | libc::O_CREAT) as u64;
+        let fd = guest.call(
+            &mut queue,
+            &mut handler,
+            ScfOpcode::Open,
+            [guest.addr(BUF_OFFSET), flags, 0o644, 0, 0, 0],
+        );
+        assert!(fd >= 0, "open returns {fd}");
+
+        let content = b"written by guest";
+        let buf = BUF_OFFSET + 0x100;
+        guest.data(buf, content.len()).copy_from_slice(content);
+        let args = [fd as u64, guest.addr(buf), content.len() as u64, 0, 0, 0];
+        let ret = guest.call(&mut queue, &mut handler, ScfOpcode::Write, args);
+        assert_eq!(ret, content.len() as i64);
+        assert_eq!(std::fs::read(dir.path().join("file")).unwrap(), content);
+
+        // Requests wrap around the queue of `CAPACITY` descriptors.
+        for _ in 0..CAPACITY {
+            let args = [fd as u64, guest.addr(buf), 0, 0, 0, 0];
+            assert_eq!(
+                guest.call(&mut queue, &mut handler, ScfOpcode::Write, args),
+                0
+            );
+        }
+
+        std::fs::write(dir.path().join("file"), b"written by host").unwrap();
+        let file = File::open(dir.path().join("file")).unwrap();
+        let args = [file.as_raw_fd() as u64, guest.addr(buf), 0x100, 0, 0, 0];
+        let ret = guest.call(&mut queue, &mut handler, ScfOpcode::Read, args);
+        assert_eq!(ret, 15);
+        assert_eq!(guest.data(buf, 15), b"written by host");
+
+        let args = [fd as u64, 0, 0, 0, 0, 0];
+        assert_eq!(
+            guest.call(&mut queue, &mut handler, ScfOpcode::Close, args),
+            0
+        );
+    }
+
+    #[test]
+    fn reject_bad_requests() {
+        let (mut guest, mut queue, mut handler) = FakeGuest::new();
+        let mut call = |opcode: u8, args: [u64; 6]| {
+            let index = guest.push(opcode, args);
+            assert_eq!(handler.poll_requests(&mut queue).unwrap(), 1);
+            let (rsp, ret_val) = guest.pop().unwrap();
+            assert_eq!(rsp, index);
+            ret_val
+        };
+
+        // Buffers must lie in data buffer.
+        let outside = [1, 0x1000, 16, 0, 0, 0];
+        assert_eq!(call(ScfOpcode::Write as u8, outside), -libc::EFAULT as i64);
+        assert_eq!(call(ScfOpcode::Open as u8, outside), -libc::EFAULT as i64);
+
+        assert_eq!(call(ScfOpcode::Nop as u8, [0; 6]), 0);
+        assert_eq!(call(0x42, [0; 6]), -libc::ENOSYS as i64);
+        assert_eq!(
+            call(ScfOpcode::MustMmap as u8, [0, 0, 0x1000, 0, 0, 0]),
+            -libc::ENODEV as i64
+        );
+
+        let id = MAX_VDISK as u64;
+        assert_eq!(
+            call(ScfOpcode::OpenVdisk as u8, [id, 0, 0, 0, 0, 0]),
+            -libc::EINVAL as i64
+        );
+        assert_eq!(
+            call(
+                ScfOpcode::ReadVdiskBlock as u8,
+                [0, 0, BUF_OFFSET as u64, 0, 0, 0]
+            ),
+            -libc::EINVAL as i64
+        );
+        assert_eq!(
+            call(ScfOpcode::WriteVdiskBlock as u8, [0, 0, u64::MAX, 0, 0, 0]),
+            -libc::EFAULT as i64
+        );
+    }
+}
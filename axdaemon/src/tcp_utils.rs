@@ -1,5 +1,9 @@
+use std::io::{Error, ErrorKind};
+
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use axdaemon_request::MAX_FRAME_SIZE;
+
 pub async fn tcp_send(
     connection: &mut (impl AsyncWrite + Unpin),
     message: &[u8],
@@ -17,6 +21,12 @@ pub async fn tcp_receive(connection: &mut (impl AsyncRead + Unpin)) -> std::io::
         connection.read_exact(&mut raw).await?;
         u64::from_le_bytes(raw) as usize
     };
+    if reply_len > MAX_FRAME_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("frame of {reply_len} Bytes exceeds limit {MAX_FRAME_SIZE} Bytes"),
+        ));
+    }
     let mut reply = vec![0; reply_len];
     connection.read_exact(&mut reply).await?;
     Ok(reply)
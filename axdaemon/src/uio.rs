@@ -1,3 +1,476 @@
-use uio;
+//! Emulated device requests from hypervisor through the `axservice` UIO device.
+//!
+//! The `axservice` kernel module registers a UIO device whose irq is raised by hypervisor
+//! and whose first memory map (`req_queue`) holds the block request queue.
 
-pub fn test_uio() {}
+use std::fs::{read_to_string, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{fence, Ordering};
+
+use memmap::{MmapMut, MmapOptions};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc;
+
+use axerrno::{ax_err, ax_err_type, AxResult};
+
+use crate::daemon::Event;
+use crate::vdev::{BlockReqStatus, BlockRequest, VDevEventWrapper};
+
+/// Where UIO devices are listed, see `open_axservice`.
+pub const UIO_SYSFS_CLASS: &str = "/sys/class/uio";
+/// Where device nodes of UIO devices are created.
+pub const UIO_DEV_DIR: &str = "/dev";
+const AXSERVICE_UIO_NAME: &str = "axservice";
+const REQ_QUEUE_MAP_NAME: &str = "req_queue";
+/// Index of `req_queue` in memory maps of `axservice`.
+const REQ_QUEUE_MAP_INDEX: usize = 0;
+
+const PAGE_SIZE: usize = 0x1000;
+
+/// "\x7fBLK"
+const BLOCK_REQ_QUEUE_MAGIC: u32 = 0x4b4c_427f;
+
+/// Header of `req_queue`, followed by `capacity` entries of `BlockReqDesc`.
+///
+/// Hypervisor fills `BlockReqDesc` at `req_index % capacity` then increases `req_index`,
+/// axdaemon writes `status` of the request at `rsp_index % capacity` then increases `rsp_index`.
+#[repr(C)]
+struct BlockReqQueueMeta {
+    magic: u32,
+    capacity: u32,
+    req_index: u32,
+    rsp_index: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct BlockReqDesc {
+    vmid: u64,
+    /// Index of disk in VM.
+    dev: u64,
+    req_type: u64,
+    sector: u64,
+    count: u64,
+    /// Written by axdaemon, see `BlockReqStatus`.
+    status: u64,
+}
+
+/// Block request queue shared with hypervisor.
+#[derive(Debug)]
+pub struct BlockReqQueue {
+    region: MmapMut,
+}
+
+impl BlockReqQueue {
+    pub fn new(region: MmapMut) -> Self {
+        Self { region }
+    }
+
+    fn meta(&self) -> *mut BlockReqQueueMeta {
+        self.region.as_ptr() as *mut BlockReqQueueMeta
+    }
+
+    fn desc(&self, index: u32, capacity: u32) -> *mut BlockReqDesc {
+        let base = unsafe { self.meta().add(1) } as *mut BlockReqDesc;
+        unsafe { base.add((index % capacity) as usize) }
+    }
+
+    /// Check header written by hypervisor and return the queue capacity.
+    fn capacity(&self) -> AxResult<u32> {
+        let meta = self.meta();
+        let magic = unsafe { core::ptr::addr_of!((*meta).magic).read_volatile() };
+        if magic != BLOCK_REQ_QUEUE_MAGIC {
+            return ax_err!(
+                BadState,
+                format!("bad block request queue magic {magic:#x}")
+            );
+        }
+        let capacity = unsafe { core::ptr::addr_of!((*meta).capacity).read_volatile() };
+        let size = core::mem::size_of::<BlockReqQueueMeta>()
+            + capacity as usize * core::mem::size_of::<BlockReqDesc>();
+        if capacity == 0 || size > self.region.len() {
+            return ax_err!(
+                BadState,
+                format!("bad block request queue capacity {capacity}")
+            );
+        }
+        Ok(capacity)
+    }
+
+    /// Handle all pending requests through `handler`, return the number of requests handled.
+    pub fn handle_requests(
+        &mut self,
+        mut handler: impl FnMut(usize, BlockRequest) -> BlockReqStatus,
+    ) -> AxResult<usize> {
+        let capacity = self.capacity()?;
+        let meta = self.meta();
+
+        let mut handled = 0;
+        loop {
+            let req_index = unsafe { core::ptr::addr_of!((*meta).req_index).read_volatile() };
+            let rsp_index = unsafe { core::ptr::addr_of!((*meta).rsp_index).read_volatile() };
+            if req_index == rsp_index {
+                break;
+            }
+            if req_index.wrapping_sub(rsp_index) > capacity {
+                return ax_err!(
+                    BadState,
+                    format!("bad block request queue index req {req_index} rsp {rsp_index}")
+                );
+            }
+            // Read descriptor after seeing `req_index` updated by hypervisor.
+            fence(Ordering::Acquire);
+
+            let desc = self.desc(rsp_index, capacity);
+            let BlockReqDesc {
+                vmid,
+                dev,
+                req_type,
+                sector,
+                count,
+                ..
+            } = unsafe { desc.read_volatile() };
+            let status = handler(
+                vmid as usize,
+                BlockRequest::new(
+                    dev as usize,
+                    req_type as usize,
+                    sector as usize,
+                    count as usize,
+                ),
+            );
+
+            unsafe { core::ptr::addr_of_mut!((*desc).status).write_volatile(status as u64) };
+            // Publish status before `rsp_index`.
+            fence(Ordering::Release);
+            let rsp_index = rsp_index.wrapping_add(1);
+            unsafe { core::ptr::addr_of_mut!((*meta).rsp_index).write_volatile(rsp_index) };
+            handled += 1;
+        }
+        Ok(handled)
+    }
+}
+
+/// Open the `axservice` UIO device and map its `req_queue` region.
+///
+/// The device is looked up in `sysfs_class`, its device node is opened in `dev_dir`.
+pub fn open_axservice(sysfs_class: &Path, dev_dir: &Path) -> AxResult<(File, BlockReqQueue)> {
+    let uio_sysfs = find_uio_device(sysfs_class, AXSERVICE_UIO_NAME)?;
+    let map_sysfs = uio_sysfs.join(format!("maps/map{REQ_QUEUE_MAP_INDEX}"));
+
+    let map_name = read_sysfs(&map_sysfs.join("name"))?;
+    if map_name != REQ_QUEUE_MAP_NAME {
+        return ax_err!(
+            NotFound,
+            format!("unexpected map {map_name:?} of {AXSERVICE_UIO_NAME}")
+        );
+    }
+    let map_size = read_sysfs(&map_sysfs.join("size")).and_then(|size| {
+        usize::from_str_radix(size.trim_start_matches("0x"), 16)
+            .map_err(|err| ax_err_type!(InvalidData, format!("bad map size {size:?}, {err:?}")))
+    })?;
+
+    let dev_path = dev_dir.join(uio_sysfs.file_name().unwrap_or_default());
+    let dev_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(&dev_path)
+        .map_err(|err| {
+            ax_err_type!(
+                NotFound,
+                format!("failed to open UIO device {dev_path:?}, {err:?}")
+            )
+        })?;
+
+    // Memory map N of a UIO device is mapped by offset N pages.
+    let region = unsafe {
+        MmapOptions::new()
+            .offset((REQ_QUEUE_MAP_INDEX * PAGE_SIZE) as u64)
+            .len(map_size)
+            .map_mut(&dev_file)
+    }
+    .map_err(|err| ax_err_type!(BadState, format!("failed to mmap req_queue {err:?}")))?;
+
+    info!("open UIO device {dev_path:?}, req_queue of {map_size:#x} Bytes");
+
+    Ok((dev_file, BlockReqQueue::new(region)))
+}
+
+/// Spawn the loop waiting for irqs from UIO device, each irq is sent as an `Event::VDEV`.
+///
+/// The irq is re-enabled by writing 1 to `dev_file` after each irq is read, unless the
+/// device has no irq control. `dev_file` must be opened with `O_NONBLOCK`.
+pub fn spawn_irq_loop(dev_file: File, vdev_tx: mpsc::Sender<Event>) -> AxResult {
+    let dev = AsyncFd::new(dev_file)
+        .map_err(|err| ax_err_type!(BadState, format!("failed to poll UIO device {err:?}")))?;
+
+    tokio::spawn(async move {
+        let mut irq_control = true;
+        loop {
+            let mut guard = match dev.readable().await {
+                Ok(guard) => guard,
+                Err(err) => {
+                    warn!("UIO device poll err {err:?}");
+                    break;
+                }
+            };
+            // UIO device returns the total irq count as an u32.
+            let mut raw = [0; 4];
+            match guard.try_io(|dev| (&mut dev.get_ref()).read(&mut raw)) {
+                Ok(Ok(4)) => {
+                    let irq_count = u32::from_ne_bytes(raw);
+                    // Re-enabled before requests are handled, a request queued meanwhile
+                    // raises another irq instead of being missed.
+                    if irq_control {
+                        irq_control = enable_irq(dev.get_ref());
+                    }
+                    if vdev_tx
+                        .send(Event::VDEV(VDevEventWrapper { irq_count }))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Ok(Ok(len)) => {
+                    warn!("UIO device read {len} Bytes, expect 4 Bytes");
+                    break;
+                }
+                Ok(Err(err)) => {
+                    warn!("UIO device read err {err:?}");
+                    break;
+                }
+                Err(_would_block) => continue,
+            }
+        }
+        debug!("UIO irq loop finished");
+    });
+
+    Ok(())
+}
+
+/// Re-enable irq of UIO device, return false if the device has no irq control.
+fn enable_irq(mut dev: &File) -> bool {
+    match dev.write(&1u32.to_ne_bytes()) {
+        Ok(_) => true,
+        Err(err) if err.raw_os_error() == Some(libc::ENOSYS) => {
+            debug!("UIO device has no irq control, irq is not re-enabled");
+            false
+        }
+        Err(err) => {
+            warn!("failed to re-enable irq of UIO device {err:?}");
+            true
+        }
+    }
+}
+
+/// Find UIO device in `sysfs_class` according to its name, return its sysfs path.
+fn find_uio_device(sysfs_class: &Path, name: &str) -> AxResult<PathBuf> {
+    let entries = std::fs::read_dir(sysfs_class).map_err(|err| {
+        ax_err_type!(
+            NotFound,
+            format!("failed to read {sysfs_class:?}, is UIO enabled? {err:?}")
+        )
+    })?;
+
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| read_sysfs(&path.join("name")).is_ok_and(|n| n == name))
+        .ok_or_else(|| {
+            ax_err_type!(
+                NotFound,
+                format!("UIO device {name} not found, please make sure axservice is loaded")
+            )
+        })
+}
+
+fn read_sysfs(path: &Path) -> AxResult<String> {
+    read_to_string(path)
+        .map(|content| content.trim().to_string())
+        .map_err(|err| ax_err_type!(NotFound, format!("failed to read {path:?}, {err:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixStream;
+
+    use axerrno::AxError;
+
+    use super::*;
+    use axdisk::test_utils::TempDir;
+
+    const REQ_QUEUE_SIZE: usize = 0x1000;
+    /// `VIRTIO_BLK_T_IN`.
+    const READ: u64 = 0;
+
+    /// Fake sysfs class and device directory with `axservice` as `uio3`, whose device node
+    /// is a plain file holding `req_queue`.
+    fn fake_axservice(dir: &TempDir) -> (PathBuf, PathBuf) {
+        let class = dir.path().join("class");
+        for (uio, name) in [("uio0", "other"), ("uio3", AXSERVICE_UIO_NAME)] {
+            let map = class.join(uio).join("maps/map0");
+            std::fs::create_dir_all(&map).unwrap();
+            std::fs::write(class.join(uio).join("name"), format!("{name}\n")).unwrap();
+            std::fs::write(map.join("name"), format!("{REQ_QUEUE_MAP_NAME}\n")).unwrap();
+            std::fs::write(map.join("size"), format!("{REQ_QUEUE_SIZE:#x}\n")).unwrap();
+        }
+        let dev = dir.path().join("dev");
+        std::fs::create_dir_all(&dev).unwrap();
+        std::fs::write(dev.join("uio3"), [0; REQ_QUEUE_SIZE]).unwrap();
+        (class, dev)
+    }
+
+    /// `req_queue` as seen by hypervisor.
+    struct FakeHypervisor {
+        region: MmapMut,
+    }
+
+    impl FakeHypervisor {
+        fn new(dev: &Path, capacity: u32) -> Self {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(dev.join("uio3"))
+                .unwrap();
+            let mut region =
+                unsafe { MmapOptions::new().len(REQ_QUEUE_SIZE).map_mut(&file) }.unwrap();
+            region[..4].copy_from_slice(&BLOCK_REQ_QUEUE_MAGIC.to_ne_bytes());
+            region[4..8].copy_from_slice(&capacity.to_ne_bytes());
+            Self { region }
+        }
+
+        fn u32_at(&self, offset: usize) -> u32 {
+            u32::from_ne_bytes(self.region[offset..offset + 4].try_into().unwrap())
+        }
+
+        fn capacity(&self) -> u32 {
+            self.u32_at(4)
+        }
+
+        fn desc_offset(&self, index: u32) -> usize {
+            let slot = (index % self.capacity()) as usize;
+            core::mem::size_of::<BlockReqQueueMeta>() + slot * core::mem::size_of::<BlockReqDesc>()
+        }
+
+        /// Queue a request of `[vmid, dev, req_type, sector, count]`.
+        fn push(&mut self, fields: [u64; 5]) {
+            let req_index = self.u32_at(8);
+            let offset = self.desc_offset(req_index);
+            for (i, field) in fields.iter().chain([&u64::MAX]).enumerate() {
+                self.region[offset + i * 8..offset + i * 8 + 8]
+                    .copy_from_slice(&field.to_ne_bytes());
+            }
+            self.region[8..12].copy_from_slice(&(req_index + 1).to_ne_bytes());
+        }
+
+        fn status(&self, index: u32) -> u64 {
+            let offset = self.desc_offset(index) + 5 * 8;
+            u64::from_ne_bytes(self.region[offset..offset + 8].try_into().unwrap())
+        }
+
+        fn rsp_index(&self) -> u32 {
+            self.u32_at(12)
+        }
+    }
+
+    #[test]
+    fn open_fake_device() {
+        let dir = TempDir::new();
+        let (class, dev) = fake_axservice(&dir);
+        assert!(open_axservice(&class, &dev).is_ok());
+
+        std::fs::write(class.join("uio3/name"), "other\n").unwrap();
+        assert_eq!(open_axservice(&class, &dev).err(), Some(AxError::NotFound));
+    }
+
+    #[test]
+    fn handle_requests_in_order() {
+        let dir = TempDir::new();
+        let (class, dev) = fake_axservice(&dir);
+        let (_dev_file, mut queue) = open_axservice(&class, &dev).unwrap();
+        let mut hv = FakeHypervisor::new(&dev, 4);
+
+        // Requests of odd sectors fail, the second round wraps around the queue.
+        let mut handled = Vec::new();
+        let mut handler = |vmid, req| {
+            handled.push((vmid, req));
+            match handled.len() % 2 {
+                0 => BlockReqStatus::IoErr,
+                _ => BlockReqStatus::Ok,
+            }
+        };
+        for round in 0..2 {
+            for i in 0..3 {
+                hv.push([round + 1, i, READ, round * 3 + i, 8]);
+            }
+            assert_eq!(queue.handle_requests(&mut handler).unwrap(), 3);
+            assert_eq!(queue.handle_requests(&mut handler).unwrap(), 0);
+        }
+
+        assert_eq!(hv.rsp_index(), 6);
+        for index in 0..6u32 {
+            let (vmid, req) = &handled[index as usize];
+            let round = index as usize / 3;
+            let i = index as usize % 3;
+            assert_eq!(*vmid, round + 1);
+            assert_eq!(*req, BlockRequest::new(i, READ as usize, index as usize, 8));
+        }
+        // Slots of the first round are reused, only the last 4 statuses are kept.
+        for index in 2..6 {
+            let expect = match index % 2 {
+                0 => BlockReqStatus::Ok,
+                _ => BlockReqStatus::IoErr,
+            };
+            assert_eq!(hv.status(index), expect as u64, "request {index}");
+        }
+    }
+
+    #[test]
+    fn reject_corrupted_queue() {
+        let dir = TempDir::new();
+        let (class, dev) = fake_axservice(&dir);
+        let (_dev_file, mut queue) = open_axservice(&class, &dev).unwrap();
+        let mut hv = FakeHypervisor::new(&dev, 4);
+        let handler = |_, _| BlockReqStatus::Ok;
+
+        hv.region[8..12].copy_from_slice(&5u32.to_ne_bytes());
+        assert_eq!(queue.handle_requests(handler), Err(AxError::BadState));
+
+        hv.region[4..8].copy_from_slice(&u32::MAX.to_ne_bytes());
+        assert_eq!(queue.handle_requests(handler), Err(AxError::BadState));
+
+        hv.region[..4].fill(0);
+        assert_eq!(queue.handle_requests(handler), Err(AxError::BadState));
+    }
+
+    /// Irq counts are written to a socket in place of UIO device, which could not be faked
+    /// by an eventfd as UIO device reads and writes 4 Bytes instead of 8 Bytes.
+    #[tokio::test]
+    async fn irq_loop_reenables_irq() {
+        let (mut hv, dev) = UnixStream::pair().unwrap();
+        dev.set_nonblocking(true).unwrap();
+        let (vdev_tx, mut vdev_rx) = mpsc::channel(1);
+        spawn_irq_loop(File::from(OwnedFd::from(dev)), vdev_tx).unwrap();
+
+        for irq_count in 1..=3u32 {
+            hv.write_all(&irq_count.to_ne_bytes()).unwrap();
+            let Some(Event::VDEV(event)) = vdev_rx.recv().await else {
+                panic!("irq {irq_count} is not reported");
+            };
+            assert_eq!(event.irq_count, irq_count);
+
+            let mut enable = [0; 4];
+            hv.read_exact(&mut enable).unwrap();
+            assert_eq!(u32::from_ne_bytes(enable), 1);
+        }
+
+        // Irq loop stops once the device is gone.
+        drop(hv);
+        assert!(vdev_rx.recv().await.is_none());
+    }
+}
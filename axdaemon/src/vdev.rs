@@ -1,12 +1,13 @@
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::os::unix::fs::OpenOptionsExt;
 use std::path::PathBuf;
 
+use axdaemon_request::{DiskCacheMode, DiskCfg, DiskFormat, DISK_SERIAL_MAX_LEN};
 use colored::Colorize;
 use memmap::{MmapMut, MmapOptions};
 
-use axerrno::{ax_err, ax_err_type, AxResult};
+use axerrno::{ax_err, ax_err_type, AxError, AxResult};
+
+use axdisk::block::{self, BlockBackend, SECTOR_SIZE};
 
 /// Currently 2MB.
 /// I get a warning from kernel output when I try to set page size for HugeTLB as 32MB.
@@ -14,34 +15,88 @@ use axerrno::{ax_err, ax_err_type, AxResult};
 /// Todo: make it 32MB through some way.
 const HUGE_TLB_MAX: usize = 2 * 1024 * 1024;
 
-const BLOCK_SIZE: usize = 512;
-
+/// Block request types, same as `VIRTIO_BLK_T_*` in Virtio spec.
+const BLOCK_REQ_READ: usize = 0;
+const BLOCK_REQ_WRITE: usize = 1;
+const BLOCK_REQ_FLUSH: usize = 4;
+const BLOCK_REQ_GET_ID: usize = 8;
+const BLOCK_REQ_DISCARD: usize = 11;
+
+/// Request from guest VM to read/write `count` sectors starting at `sector` of disk `dev`.
+/// Data is transferred through the beginning of the emulated block's cache, discard
+/// requests have no data and may exceed the cache.
+#[derive(Debug, PartialEq, Eq)]
 pub struct BlockRequest {
+    dev: usize,
     req_type: usize,
     sector: usize,
     count: usize,
 }
 
+/// Status of a processed block request, same as `VIRTIO_BLK_S_*` in Virtio spec.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockReqStatus {
+    Ok = 0,
+    IoErr = 1,
+    Unsupported = 2,
+}
+
+impl From<&AxResult> for BlockReqStatus {
+    fn from(result: &AxResult) -> Self {
+        match result {
+            Ok(()) => Self::Ok,
+            Err(AxError::Unsupported) => Self::Unsupported,
+            Err(_) => Self::IoErr,
+        }
+    }
+}
+
+impl BlockRequest {
+    pub fn new(dev: usize, req_type: usize, sector: usize, count: usize) -> Self {
+        Self {
+            dev,
+            req_type,
+            sector,
+            count,
+        }
+    }
+}
+
 /// Events related to emulated device, e.g. Virtio-Blk request.
-/// See crate `xxx` for details.
-/// * `request`: emulated device request from guest VM.
-/// * `reply_tx`: returned `notify` refers to request that have been processed.
+/// See `uio.rs` for details.
+/// * `irq_count`: total irq count of the UIO device, requests are fetched from `req_queue`.
 #[derive(Debug)]
 pub struct VDevEventWrapper {
-    // pub request: DaemonRequest,
-    // pub reply_tx: oneshot::Sender<Option<DaemonReply>>,
+    pub irq_count: u32,
 }
 
 #[derive(Debug)]
 struct EmulatedBlock {
     base: EmulatedBlockCfgMmio,
-    drive_file: DriveFile,
+    /// Path of disk image or size of RAM disk, for logs.
+    name: String,
+    backend: Box<dyn BlockBackend>,
+    snapshot: bool,
+    serial: Option<String>,
+    cache_mode: DiskCacheMode,
+    /// Cache shared with hypervisor, see `cache_gva` in `EmulatedBlockCfgMmio`.
+    cache: MmapMut,
 }
 
 #[repr(C)]
 #[derive(Debug, Default)]
 struct EmulatedBlockCfgMmio {
     vmid: usize,
+    /// Index of disk in VM, see `DaemonRequest::RegisterVM`.
+    dev_index: usize,
+    /// Non-zero if disk is read-only, guest writes are failed by axdaemon.
+    ///
+    /// Meant for hypervisor to report `VIRTIO_BLK_F_RO`, but this struct is not passed to
+    /// hypervisor yet, see `setup_emulated_block_rw_cache`.
+    read_only: usize,
+    /// Non-zero if disk supports discard, meant for `VIRTIO_BLK_F_DISCARD` like `read_only`.
+    discard: usize,
     block_num: usize,
     dma_block_max: usize,
     cache_size: usize,
@@ -51,102 +106,253 @@ struct EmulatedBlockCfgMmio {
     cache_hpa: usize,
 }
 
-/// Represent a single drive backend file.
-#[derive(Debug)]
-pub struct DriveFile {
-    /// VM id.
-    vmid: usize,
-    /// The opened file.
-    file: File,
-    /// File path.
-    path: PathBuf,
-}
-
 #[derive(Debug, Default)]
 pub struct EmulatedBlockBackends {
-    emulated_blocks: HashMap<usize, EmulatedBlock>,
+    /// Indexed by (VM id, disk index).
+    emulated_blocks: HashMap<(usize, usize), EmulatedBlock>,
 }
 
 impl EmulatedBlockBackends {
-    /// Add a drive file for target VM.
-    pub fn setup_emulated_block(&mut self, vmid: usize, path: PathBuf, direct: bool) -> AxResult {
+    /// Add a disk image as disk `dev_index` of target VM.
+    pub fn setup_emulated_block(
+        &mut self,
+        vmid: usize,
+        dev_index: usize,
+        cfg: &DiskCfg,
+    ) -> AxResult {
         info!(
-            "{} set up emulated block {:?} for VM [{}]",
+            "{} set up emulated block {} {} for VM [{}], format {} read_only {} snapshot {} direct {} cache {} discard {}",
             "AxDaemon".bold().green(),
-            path,
-            vmid
+            dev_index,
+            cfg,
+            vmid,
+            cfg.format,
+            cfg.read_only,
+            cfg.snapshot,
+            cfg.direct,
+            cfg.cache,
+            cfg.discard
         );
 
-        let file = open_file(&path, direct)?;
-        let file_size = file.metadata().unwrap().len();
-        let drive_file = DriveFile { vmid, file, path };
-
-        let mut base = EmulatedBlockCfgMmio::default();
-        setup_emulated_block_rw_cache(&mut base, file_size)?;
-
-        let emulated_block = EmulatedBlock { base, drive_file };
-
-        self.emulated_blocks.insert(vmid, emulated_block);
+        if self.emulated_blocks.contains_key(&(vmid, dev_index)) {
+            return ax_err!(
+                AlreadyExists,
+                format!("emulated block {dev_index} of VM [{vmid}] already exists")
+            );
+        }
+        if cfg
+            .serial
+            .as_ref()
+            .is_some_and(|serial| serial.len() > DISK_SERIAL_MAX_LEN)
+        {
+            return ax_err!(
+                InvalidInput,
+                format!("serial of disk {dev_index} exceeds {DISK_SERIAL_MAX_LEN} Bytes")
+            );
+        }
+
+        let backend = block::open_block_backend(cfg)?;
+
+        let mut base = EmulatedBlockCfgMmio {
+            vmid,
+            dev_index,
+            read_only: cfg.read_only as usize,
+            discard: (cfg.discard || cfg.format == DiskFormat::Ram) as usize,
+            ..Default::default()
+        };
+        let cache = setup_emulated_block_rw_cache(&mut base, backend.size())?;
+
+        let emulated_block = EmulatedBlock {
+            base,
+            name: cfg.to_string(),
+            backend,
+            snapshot: cfg.snapshot,
+            serial: cfg.serial.clone(),
+            cache_mode: cfg.cache,
+            cache,
+        };
+
+        self.emulated_blocks
+            .insert((vmid, dev_index), emulated_block);
         Ok(())
     }
 
-    /// Remove a drive file according to vmid.
-    pub fn remove_emulated_block(&mut self, vmid: usize) -> AxResult {
-        let _removed_block = self.emulated_blocks.remove(&vmid).ok_or(ax_err_type!(
-            InvalidInput,
-            format!(
-                "Failed to remove drive file for VM[{}], it does not exist",
-                vmid
-            )
-        ))?;
+    /// Remove all drive files of VM, return the number of blocks removed.
+    ///
+    /// Every block is released even if some of them fail, the last error is returned.
+    pub fn remove_emulated_blocks(&mut self, vmid: usize) -> AxResult<usize> {
+        let mut keys: Vec<_> = self
+            .emulated_blocks
+            .keys()
+            .filter(|(id, _)| *id == vmid)
+            .copied()
+            .collect();
+        keys.sort_unstable();
+        let mut result = Ok(keys.len());
+        for key in keys {
+            if let Some(block) = self.emulated_blocks.remove(&key) {
+                if let Err(err) = block.release() {
+                    result = Err(err);
+                }
+            }
+        }
+        result
+    }
 
-        // Todo: release sources allocated for `EmulatedBlock` here.
+    /// Disk images open for VM, including backing files.
+    pub fn image_paths(&self, vmid: usize) -> Vec<PathBuf> {
+        self.emulated_blocks
+            .iter()
+            .filter(|((id, _), _)| *id == vmid)
+            .flat_map(|(_, block)| block.backend.image_paths())
+            .collect()
+    }
 
-        Ok(())
+    /// Flush and release all emulated blocks, return the number of blocks released.
+    ///
+    /// Every block is released even if some of them fail, the last error is returned.
+    pub fn release_all(&mut self) -> AxResult<usize> {
+        let mut released = 0;
+        let mut result = Ok(());
+        for (_key, block) in self.emulated_blocks.drain() {
+            match block.release() {
+                Ok(()) => released += 1,
+                Err(err) => result = Err(err),
+            }
+        }
+        result.map(|_| released)
     }
 
     pub fn emulated_block_rw_sectors(&mut self, vmid: usize, req: BlockRequest) -> AxResult {
-        let block = self.emulated_blocks.get_mut(&vmid).ok_or(ax_err_type!(
-            InvalidInput,
-            format!("VM[]'s emulated block not exists")
-        ))?;
+        let dev = req.dev;
+        let block = self.emulated_blocks.get_mut(&(vmid, dev)).ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("VM[{vmid}]'s emulated block {dev} not exists")
+            )
+        })?;
         block.rw_sectors(req)
     }
 }
 
 impl EmulatedBlock {
-    fn rw_sectors(&mut self, _req: BlockRequest) -> AxResult {
-        // self.drive_file.
-        Ok(())
+    fn rw_sectors(&mut self, req: BlockRequest) -> AxResult {
+        match req.req_type {
+            BLOCK_REQ_FLUSH if self.cache_mode == DiskCacheMode::Unsafe => return Ok(()),
+            BLOCK_REQ_FLUSH => return self.backend.flush(),
+            BLOCK_REQ_GET_ID => {
+                // Serial is padded with zero, and not terminated if it has 20 Bytes.
+                let id = &mut self.cache[..DISK_SERIAL_MAX_LEN];
+                id.fill(0);
+                if let Some(serial) = &self.serial {
+                    id[..serial.len()].copy_from_slice(serial.as_bytes());
+                }
+                return Ok(());
+            }
+            BLOCK_REQ_WRITE | BLOCK_REQ_DISCARD if self.base.read_only != 0 => {
+                return ax_err!(
+                    PermissionDenied,
+                    format!("write to read-only emulated block {}", self.name)
+                )
+            }
+            _ => {}
+        }
+
+        if req
+            .sector
+            .checked_add(req.count)
+            .is_none_or(|end| end > self.base.block_num)
+        {
+            return ax_err!(
+                InvalidInput,
+                format!(
+                    "block request [{}, +{}) out of range, block_num {}",
+                    req.sector, req.count, self.base.block_num
+                )
+            );
+        }
+        if req.req_type == BLOCK_REQ_DISCARD {
+            return self.backend.discard(req.sector as u64, req.count as u64);
+        }
+        if req.count > self.base.dma_block_max {
+            return ax_err!(
+                InvalidInput,
+                format!(
+                    "block request of {} sectors exceeds dma_block_max {}",
+                    req.count, self.base.dma_block_max
+                )
+            );
+        }
+
+        let buf = &mut self.cache[..req.count * SECTOR_SIZE];
+        let sector = req.sector as u64;
+        match req.req_type {
+            BLOCK_REQ_READ => self.backend.read_sectors(sector, buf),
+            BLOCK_REQ_WRITE => {
+                self.backend.write_sectors(sector, buf)?;
+                if self.cache_mode == DiskCacheMode::Writethrough {
+                    self.backend.flush()?;
+                }
+                Ok(())
+            }
+            other => ax_err!(
+                Unsupported,
+                format!("unsupported block request type {other}")
+            ),
+        }
     }
-}
 
-pub fn open_file(path: &PathBuf, direct: bool) -> AxResult<File> {
-    let mut options = OpenOptions::new();
-    // READ/WRITE by default, may add READ_ONLY in the future?
-    options.read(true).write(true);
-    if direct {
-        options.custom_flags(libc::O_DIRECT);
-    }
-    let file = options.open(path).map_err(|err| {
-        ax_err_type!(
-            InvalidInput,
-            format!(
-                "failed to open the file for block {:?}. Error:{:?}\nos err :{}",
-                path,
-                err,
-                std::io::Error::last_os_error(),
+    /// Flush the disk image and unmap the cache.
+    fn release(self) -> AxResult {
+        let EmulatedBlock {
+            base,
+            name,
+            mut backend,
+            snapshot,
+            cache,
+            ..
+        } = self;
+
+        // The cache is unmapped here whether the flush succeeds or not.
+        drop(cache);
+
+        // Snapshot overlay is discarded by closing it.
+        if snapshot {
+            info!(
+                "{} discarded snapshot of emulated block {} {} of VM [{}]",
+                "AxDaemon".bold().green(),
+                base.dev_index,
+                name,
+                base.vmid
+            );
+        }
+
+        backend.flush().map_err(|err| {
+            ax_err_type!(
+                Io,
+                format!(
+                    "failed to sync emulated block {} {} of VM [{}], {err:?}",
+                    base.dev_index, name, base.vmid
+                )
             )
-        )
-    })?;
+        })?;
 
-    Ok(file)
+        info!(
+            "{} released emulated block {} {} of VM [{}], cache of {} Bytes unmapped",
+            "AxDaemon".bold().green(),
+            base.dev_index,
+            name,
+            base.vmid,
+            base.cache_size
+        );
+        Ok(())
+    }
 }
 
 fn setup_emulated_block_rw_cache(
     base: &mut EmulatedBlockCfgMmio,
-    drive_file_size: u64,
-) -> AxResult {
+    disk_size: u64,
+) -> AxResult<MmapMut> {
     let cache_size = HUGE_TLB_MAX;
 
     // // system("mkdir -p /mnt/huge");
@@ -175,9 +381,9 @@ fn setup_emulated_block_rw_cache(
 
     check_cache_address(&mut mmap)?;
 
-    base.block_num = (drive_file_size as f64 / BLOCK_SIZE as f64).ceil() as usize;
+    base.block_num = (disk_size as f64 / SECTOR_SIZE as f64).ceil() as usize;
     base.cache_size = cache_size;
-    base.dma_block_max = cache_size / BLOCK_SIZE;
+    base.dma_block_max = cache_size / SECTOR_SIZE;
     base.cache_gva = mmap.as_ptr() as usize;
     base.cache_gpa = get_physical_addr(&mmap)?;
     // This field was set by hypervisor
@@ -189,7 +395,7 @@ fn setup_emulated_block_rw_cache(
     );
     // Notify hypervisor through ioctl & hvc.
 
-    Ok(())
+    Ok(mmap)
 }
 
 /// Check if cache address allocated by mmap is valid.
@@ -282,3 +488,162 @@ fn get_physical_addr(mmap: &MmapMut) -> AxResult<usize> {
 fn pagemap_err_to_ax_err(e: pagemap::PageMapError) -> axerrno::AxError {
     ax_err_type!(BadState, format!("PageMapError {e:?}"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axdisk::test_utils::{disk_cfg, pattern, TempDir};
+
+    /// Sectors of the cache in tests, i.e. `dma_block_max`.
+    const CACHE_SECTORS: usize = 8;
+
+    /// Emulated block on raw disk image `path`, with an anonymous cache instead of one
+    /// shared with hypervisor.
+    fn test_block(path: &std::path::Path, read_only: bool) -> EmulatedBlock {
+        let cfg = DiskCfg {
+            format: DiskFormat::Raw,
+            read_only,
+            ..disk_cfg(path)
+        };
+        let backend = block::open_block_backend(&cfg).unwrap();
+        let cache_size = CACHE_SECTORS * SECTOR_SIZE;
+        let base = EmulatedBlockCfgMmio {
+            read_only: read_only as usize,
+            block_num: backend.size().div_ceil(SECTOR_SIZE as u64) as usize,
+            dma_block_max: CACHE_SECTORS,
+            cache_size,
+            ..Default::default()
+        };
+        EmulatedBlock {
+            base,
+            name: cfg.to_string(),
+            backend,
+            snapshot: false,
+            serial: None,
+            cache_mode: cfg.cache,
+            cache: MmapOptions::new().len(cache_size).map_anon().unwrap(),
+        }
+    }
+
+    fn request(req_type: usize, sector: usize, count: usize) -> BlockRequest {
+        BlockRequest::new(0, req_type, sector, count)
+    }
+
+    #[test]
+    fn read_boundary_sectors() {
+        let dir = TempDir::new();
+        let data = pattern(1, 16 * SECTOR_SIZE);
+        let path = dir.file("disk.img", &data);
+        let mut block = test_block(&path, false);
+
+        block.rw_sectors(request(BLOCK_REQ_READ, 0, 1)).unwrap();
+        assert_eq!(&block.cache[..SECTOR_SIZE], &data[..SECTOR_SIZE]);
+
+        block.rw_sectors(request(BLOCK_REQ_READ, 15, 1)).unwrap();
+        assert_eq!(&block.cache[..SECTOR_SIZE], &data[15 * SECTOR_SIZE..]);
+
+        for (sector, count) in [(15, 2), (16, 1), (usize::MAX, 2)] {
+            let result = block.rw_sectors(request(BLOCK_REQ_READ, sector, count));
+            assert_eq!(result, Err(AxError::InvalidInput), "[{sector}, +{count})");
+            assert_eq!(BlockReqStatus::from(&result), BlockReqStatus::IoErr);
+        }
+    }
+
+    #[test]
+    fn write_boundary_sectors() {
+        let dir = TempDir::new();
+        let path = dir.file("disk.img", &[0; 16 * SECTOR_SIZE]);
+        let mut block = test_block(&path, false);
+
+        let last = pattern(2, SECTOR_SIZE);
+        block.cache[..SECTOR_SIZE].copy_from_slice(&last);
+        block.rw_sectors(request(BLOCK_REQ_WRITE, 15, 1)).unwrap();
+        assert_eq!(
+            block.rw_sectors(request(BLOCK_REQ_WRITE, 16, 1)),
+            Err(AxError::InvalidInput)
+        );
+
+        let file = std::fs::read(&path).unwrap();
+        assert_eq!(file.len(), 16 * SECTOR_SIZE, "disk image must not grow");
+        assert_eq!(&file[15 * SECTOR_SIZE..], &last[..]);
+        assert!(file[..15 * SECTOR_SIZE].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn multi_block_transfer() {
+        let dir = TempDir::new();
+        let path = dir.file("disk.img", &[0; 32 * SECTOR_SIZE]);
+        let mut block = test_block(&path, false);
+
+        let data = pattern(3, CACHE_SECTORS * SECTOR_SIZE);
+        block.cache.copy_from_slice(&data);
+        block
+            .rw_sectors(request(BLOCK_REQ_WRITE, 5, CACHE_SECTORS))
+            .unwrap();
+        block.cache.fill(0);
+        block
+            .rw_sectors(request(BLOCK_REQ_READ, 5, CACHE_SECTORS))
+            .unwrap();
+        assert_eq!(&block.cache[..], &data[..]);
+
+        let file = std::fs::read(&path).unwrap();
+        let written = 5 * SECTOR_SIZE..(5 + CACHE_SECTORS) * SECTOR_SIZE;
+        assert_eq!(&file[written.clone()], &data[..]);
+        assert!(file[..written.start].iter().all(|&b| b == 0));
+        assert!(file[written.end..].iter().all(|&b| b == 0));
+
+        assert_eq!(
+            block.rw_sectors(request(BLOCK_REQ_READ, 0, CACHE_SECTORS + 1)),
+            Err(AxError::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn partial_last_sector_reads_zero() {
+        let dir = TempDir::new();
+        let data = pattern(4, 2 * SECTOR_SIZE + 100);
+        let path = dir.file("disk.img", &data);
+        let mut block = test_block(&path, false);
+        assert_eq!(block.base.block_num, 3);
+
+        block.cache.fill(0xff);
+        block.rw_sectors(request(BLOCK_REQ_READ, 1, 2)).unwrap();
+        assert_eq!(&block.cache[..SECTOR_SIZE + 100], &data[SECTOR_SIZE..]);
+        assert!(block.cache[SECTOR_SIZE + 100..2 * SECTOR_SIZE]
+            .iter()
+            .all(|&b| b == 0));
+    }
+
+    #[test]
+    fn read_only_and_unsupported_requests() {
+        let dir = TempDir::new();
+        let data = pattern(5, 4 * SECTOR_SIZE);
+        let path = dir.file("disk.img", &data);
+        let mut block = test_block(&path, true);
+
+        let result = block.rw_sectors(request(BLOCK_REQ_WRITE, 0, 1));
+        assert_eq!(result, Err(AxError::PermissionDenied));
+        assert_eq!(BlockReqStatus::from(&result), BlockReqStatus::IoErr);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        let result = block.rw_sectors(request(2, 0, 1));
+        assert_eq!(BlockReqStatus::from(&result), BlockReqStatus::Unsupported);
+    }
+
+    #[test]
+    fn image_paths_of_vm() {
+        let dir = TempDir::new();
+        let first = dir.file("first.img", &pattern(1, 16 * SECTOR_SIZE));
+        let second = dir.file("second.img", &pattern(2, 16 * SECTOR_SIZE));
+        let mut vdevs = EmulatedBlockBackends::default();
+        vdevs
+            .emulated_blocks
+            .insert((1, 0), test_block(&first, false));
+        vdevs
+            .emulated_blocks
+            .insert((2, 0), test_block(&second, true));
+        assert_eq!(vdevs.image_paths(1), [first]);
+        assert_eq!(vdevs.image_paths(2), [second]);
+        assert!(vdevs.image_paths(3).is_empty());
+    }
+}
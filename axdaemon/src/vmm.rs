@@ -1,14 +1,13 @@
 use std::collections::BTreeMap;
-use std::path::PathBuf;
-use std::sync::Mutex;
+use std::path::{Path, PathBuf};
 
 use colored::Colorize;
 use tokio::sync::oneshot;
 
-use axdaemon_request::{DaemonReply, DaemonRequest};
-use axerrno::{ax_err, ax_err_type, AxError, AxResult};
+use axdaemon_request::{DaemonReply, DaemonRequest, DiskCfg};
+use axerrno::{ax_err, AxError, AxResult};
 
-use crate::vdev::EmulatedBlockBackends;
+use crate::vdev::{BlockRequest, EmulatedBlockBackends};
 
 /// Events related to VM management, e.g. VM register, boot, shutdown, remove.
 /// See crate `axdaemon_request` for details.
@@ -20,71 +19,277 @@ pub struct VMMEventWrapper {
     pub reply_tx: oneshot::Sender<Option<DaemonReply>>,
 }
 
+/// Lifecycle state of a VM in axdaemon.
+///
+/// Legal transitions:
+/// * `Registered` -> `Prepared` -> `Running` -> `Stopped`, through `BootVM`, `VMBooted`
+///   and `ShutdownVM`.
+/// * `Prepared` -> `Stopped`, through `ShutdownVM` if hypervisor fails to boot VM after its
+///   devices are set up.
+/// * `Registered` -> `Stopped`, through `ShutdownVM` of a VM never booted.
+/// * `Stopped` -> `Prepared`, when a stopped VM is booted again.
+/// * `Registered` / `Stopped` -> `Removed`, through `UnregisterVM`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmState {
+    /// VM is registered with its disks, no resource is allocated yet.
+    Registered,
+    /// Emulated devices of VM are set up, VM is being booted by hypervisor.
+    Prepared,
+    /// VM is booted by hypervisor, as confirmed by axcli.
+    Running,
+    /// VM is shut down, its emulated devices are released.
+    Stopped,
+    /// VM is removed from axdaemon.
+    Removed,
+}
+
+impl VmState {
+    /// Whether VM in this state is allowed to transit to `next`.
+    pub fn can_transit_to(self, next: VmState) -> bool {
+        use VmState::*;
+        matches!(
+            (self, next),
+            (Registered, Prepared)
+                | (Registered, Stopped)
+                | (Prepared, Running)
+                | (Prepared, Stopped)
+                | (Running, Stopped)
+                | (Stopped, Prepared)
+                | (Registered, Removed)
+                | (Stopped, Removed)
+        )
+    }
+
+    /// The action making VM transit to this state, used in error messages.
+    fn action(self) -> &'static str {
+        match self {
+            VmState::Registered => "register",
+            VmState::Prepared => "set up",
+            VmState::Running => "boot",
+            VmState::Stopped => "shut down",
+            VmState::Removed => "remove",
+        }
+    }
+}
+
+impl core::fmt::Display for VmState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let state = match self {
+            VmState::Registered => "registered",
+            VmState::Prepared => "prepared",
+            VmState::Running => "running",
+            VmState::Stopped => "stopped",
+            VmState::Removed => "removed",
+        };
+        f.pad(state)
+    }
+}
+
+/// Information of a VM managed by axdaemon.
+#[derive(Debug)]
+struct VmRecord {
+    /// Indexed by disk index.
+    disks: Vec<DiskCfg>,
+    state: VmState,
+}
+
 #[derive(Debug, Default)]
 pub struct VMM {
-    vm_disk_image_paths: Mutex<BTreeMap<usize, PathBuf>>,
+    vms: BTreeMap<usize, VmRecord>,
     vdevs: EmulatedBlockBackends,
 }
 
 impl VMM {
     pub fn new() -> Self {
-        Self {
-            vm_disk_image_paths: Mutex::new(BTreeMap::new()),
-            ..Default::default()
+        Self::default()
+    }
+
+    /// Handle `DaemonRequest` from axcli.
+    ///
+    /// Illegal state transitions are rejected with a descriptive `DaemonReply::Result(Err(_))`.
+    pub fn handle_daemon_request(&mut self, request: DaemonRequest) -> AxResult<DaemonReply> {
+        let result = match request {
+            DaemonRequest::RegisterVM { vmid, disks } => self.register_vm(vmid, disks),
+            DaemonRequest::BootVM { vmid } => self.boot_vm(vmid),
+            DaemonRequest::VMBooted { vmid } => self.transit(vmid, VmState::Running),
+            DaemonRequest::ShutdownVM { vmid } => self.shutdown_vm(vmid),
+            DaemonRequest::UnregisterVM { vmid } => self.unregister_vm(vmid),
+            DaemonRequest::ListVM => return Ok(DaemonReply::VMList(self.list_vm_disks())),
+            DaemonRequest::CheckDisksUnused { paths } => self.check_disks_unused(&paths),
+        };
+        Ok(DaemonReply::Result(result))
+    }
+}
+
+impl VMM {
+    /// Get current state of VM, `None` if VM is not registered.
+    pub fn vm_state(&self, vmid: usize) -> Option<VmState> {
+        self.vms.get(&vmid).map(|vm| vm.state)
+    }
+
+    /// Check whether VM is allowed to transit to `next`, return the current state.
+    fn check_transition(&self, vmid: usize, next: VmState) -> Result<VmState, String> {
+        let state = self
+            .vm_state(vmid)
+            .ok_or_else(|| format!("VM [{vmid}] is not registered in AxDaemon"))?;
+        if !state.can_transit_to(next) {
+            return Err(format!(
+                "cannot {} VM [{vmid}] which is {state}",
+                next.action()
+            ));
         }
+        Ok(state)
     }
 
-    pub fn handle_daemon_request(&mut self, request: DaemonRequest) -> AxResult {
-        match request {
-            axdaemon_request::DaemonRequest::RegisterVM {
-                vmid,
-                disk_image_path,
-            } => self.add_vm_disk_images(vmid, disk_image_path)?,
-            axdaemon_request::DaemonRequest::BootVM { vmid } => self.setup_vm(vmid)?,
+    /// Transit VM to `next` state, or remove its record if `next` is `VmState::Removed`.
+    fn transit(&mut self, vmid: usize, next: VmState) -> Result<(), String> {
+        let prev = self.check_transition(vmid, next)?;
+        if next == VmState::Removed {
+            self.vms.remove(&vmid);
+        } else if let Some(vm) = self.vms.get_mut(&vmid) {
+            vm.state = next;
         }
+        debug!("VM [{vmid}] {prev} -> {next}");
         Ok(())
     }
-}
 
-impl VMM {
-    fn add_vm_disk_images(&self, vmid: usize, image_path: PathBuf) -> AxResult {
-        if self
-            .vm_disk_image_paths
-            .lock()
-            .map_err(|err| ax_err_type!(BadState, format!("failed to get lock {err:?}")))?
-            .contains_key(&vmid)
-        {
-            return ax_err!(
-                InvalidInput,
-                format!("VM [{vmid}] has already been registered in AxDaemon")
-            );
+    fn register_vm(&mut self, vmid: usize, disks: Vec<DiskCfg>) -> Result<(), String> {
+        if let Some(state) = self.vm_state(vmid) {
+            return Err(format!(
+                "VM [{vmid}] has already been registered in AxDaemon, it is {state}"
+            ));
+        }
+        if disks.is_empty() {
+            return Err(format!("VM [{vmid}] has no disk to register"));
         }
 
         info!(
-            "{} register VM {} disk_path {:?}",
+            "{} register VM {} with {} disks",
             "AxDaemon".bold().green(),
             vmid,
-            image_path
+            disks.len()
         );
+        for (dev_index, disk) in disks.iter().enumerate() {
+            info!("  * disk {dev_index} {disk}");
+        }
+
+        self.vms.insert(
+            vmid,
+            VmRecord {
+                disks,
+                state: VmState::Registered,
+            },
+        );
+        Ok(())
+    }
+
+    /// Remove a stopped or never booted VM, so that its id could be registered again.
+    fn unregister_vm(&mut self, vmid: usize) -> Result<(), String> {
+        self.transit(vmid, VmState::Removed)?;
+        info!("{} unregister VM [{}]", "AxDaemon".bold().green(), vmid);
+        Ok(())
+    }
+
+    fn list_vm_disks(&self) -> BTreeMap<usize, Vec<DiskCfg>> {
+        self.vms
+            .iter()
+            .map(|(vmid, vm)| (*vmid, vm.disks.clone()))
+            .collect()
+    }
 
-        self.vm_disk_image_paths
-            .lock()
-            .unwrap()
-            .insert(vmid, image_path);
+    /// Disk images open for VMs, backing files included, must not be changed behind them.
+    fn check_disks_unused(&self, paths: &[PathBuf]) -> Result<(), String> {
+        let canonical = |path: &Path| std::fs::canonicalize(path).unwrap_or(path.to_path_buf());
+        for (vmid, vm) in self.vms.iter() {
+            if !matches!(vm.state, VmState::Prepared | VmState::Running) {
+                continue;
+            }
+            let images: Vec<_> = self
+                .vdevs
+                .image_paths(*vmid)
+                .iter()
+                .map(|path| canonical(path))
+                .collect();
+            if let Some(path) = paths.iter().find(|path| images.contains(&canonical(path))) {
+                return Err(format!(
+                    "{path:?} is used by VM [{vmid}] which is {}",
+                    vm.state
+                ));
+            }
+        }
         Ok(())
     }
 
-    fn get_vm_disk_image(&self, vmid: usize) -> Option<PathBuf> {
-        self.vm_disk_image_paths.lock().unwrap().get(&vmid).cloned()
+    /// Handle block request from guest VM through its emulated block.
+    pub fn handle_block_request(&mut self, vmid: usize, req: BlockRequest) -> AxResult {
+        match self.vm_state(vmid) {
+            Some(VmState::Prepared | VmState::Running) => {}
+            Some(state) => {
+                return ax_err!(
+                    BadState,
+                    format!("block request from VM [{vmid}] which is {state}")
+                )
+            }
+            None => {
+                return ax_err!(
+                    NotFound,
+                    format!("block request from unregistered VM [{vmid}]")
+                )
+            }
+        }
+        self.vdevs.emulated_block_rw_sectors(vmid, req)
     }
 
-    pub fn setup_vm(&mut self, vmid: usize) -> AxResult {
+    /// Release resources of all VMs before axdaemon exits.
+    ///
+    /// Return the number of emulated blocks released.
+    pub fn shutdown(&mut self) -> AxResult<usize> {
+        for vm in self.vms.values_mut() {
+            if matches!(vm.state, VmState::Prepared | VmState::Running) {
+                vm.state = VmState::Stopped;
+            }
+        }
+        self.vdevs.release_all()
+    }
+
+    /// Set up emulated devices of VM then mark it prepared, it is marked running once
+    /// axcli confirms it is booted, see `VmState`.
+    fn boot_vm(&mut self, vmid: usize) -> Result<(), String> {
+        self.check_transition(vmid, VmState::Prepared)?;
+        self.setup_vm(vmid)
+            .map_err(|err| format!("failed to set up VM [{vmid}]: {err}"))?;
+        self.transit(vmid, VmState::Prepared)
+    }
+
+    /// Tear down emulated blocks and their caches of VM, then mark it stopped.
+    fn shutdown_vm(&mut self, vmid: usize) -> Result<(), String> {
+        self.check_transition(vmid, VmState::Stopped)?;
+        info!("{} shut down VM [{}]", "AxDaemon".bold().green(), vmid);
+
+        // Emulated blocks are gone even if they fail to be flushed, so VM is stopped anyway.
+        let result = self
+            .vdevs
+            .remove_emulated_blocks(vmid)
+            .map(|_| ())
+            .map_err(|err| format!("failed to release emulated blocks of VM [{vmid}]: {err}"));
+        self.transit(vmid, VmState::Stopped)?;
+        result
+    }
+
+    fn setup_vm(&mut self, vmid: usize) -> AxResult {
         info!("{} set up VM [{}]", "AxDaemon".bold().green(), vmid);
 
-        let disk_image_path = self.get_vm_disk_image(vmid).ok_or(AxError::BadState)?;
+        let vm = self.vms.get(&vmid).ok_or(AxError::NotFound)?;
 
-        self.vdevs
-            .setup_emulated_block(vmid, disk_image_path, true)?;
+        for (dev_index, disk) in vm.disks.iter().enumerate() {
+            if let Err(err) = self.vdevs.setup_emulated_block(vmid, dev_index, disk) {
+                // Do not leave disks set up before the failed one behind.
+                if let Err(release_err) = self.vdevs.remove_emulated_blocks(vmid) {
+                    warn!("failed to release emulated blocks of VM [{vmid}], {release_err:?}");
+                }
+                return Err(err);
+            }
+        }
 
         info!(
             "{} set up VM [{}] success, it is ready for booting...",
@@ -94,3 +299,177 @@ impl VMM {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use axdaemon_request::DiskFormat;
+    use axdisk::test_utils::disk_cfg;
+
+    use super::*;
+
+    const ALL_STATES: [VmState; 5] = [
+        VmState::Registered,
+        VmState::Prepared,
+        VmState::Running,
+        VmState::Stopped,
+        VmState::Removed,
+    ];
+
+    fn disk(path: &str) -> DiskCfg {
+        DiskCfg {
+            format: DiskFormat::Raw,
+            ..disk_cfg(Path::new(path))
+        }
+    }
+
+    /// Handle `request`, return the error replied if any.
+    fn request(vmm: &mut VMM, request: DaemonRequest) -> Result<(), String> {
+        match vmm.handle_daemon_request(request) {
+            Ok(DaemonReply::Result(result)) => result,
+            other => panic!("unexpected reply {other:?}"),
+        }
+    }
+
+    fn registered_vmm(vmid: usize) -> VMM {
+        let mut vmm = VMM::new();
+        let disks = vec![disk("/nonexistent/disk.img")];
+        request(&mut vmm, DaemonRequest::RegisterVM { vmid, disks }).unwrap();
+        assert_eq!(vmm.vm_state(vmid), Some(VmState::Registered));
+        vmm
+    }
+
+    #[test]
+    fn legal_transitions() {
+        use VmState::*;
+        let legal = [
+            (Registered, Prepared),
+            (Registered, Stopped),
+            (Prepared, Running),
+            (Prepared, Stopped),
+            (Running, Stopped),
+            (Stopped, Prepared),
+            (Registered, Removed),
+            (Stopped, Removed),
+        ];
+        for from in ALL_STATES {
+            for to in ALL_STATES {
+                assert_eq!(
+                    from.can_transit_to(to),
+                    legal.contains(&(from, to)),
+                    "{from} -> {to}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn transit_through_lifecycle() {
+        use VmState::*;
+        let mut vmm = registered_vmm(1);
+        for next in [Prepared, Running, Stopped, Prepared, Stopped, Removed] {
+            let state = vmm.vm_state(1).unwrap();
+            // Illegal transitions leave VM untouched.
+            for illegal in ALL_STATES.into_iter().filter(|s| !state.can_transit_to(*s)) {
+                let err = vmm.transit(1, illegal).unwrap_err();
+                assert_eq!(
+                    err,
+                    format!("cannot {} VM [1] which is {state}", illegal.action())
+                );
+                assert_eq!(vmm.vm_state(1), Some(state));
+            }
+            vmm.transit(1, next).unwrap();
+        }
+        assert_eq!(vmm.vm_state(1), None);
+        assert!(vmm.transit(1, Prepared).is_err());
+    }
+
+    #[test]
+    fn register_and_unregister() {
+        let mut vmm = registered_vmm(1);
+
+        let disks = vec![disk("/nonexistent/other.img")];
+        let err = request(&mut vmm, DaemonRequest::RegisterVM { vmid: 1, disks }).unwrap_err();
+        assert!(err.contains("already been registered"), "{err}");
+        let disks = Vec::new();
+        assert!(request(&mut vmm, DaemonRequest::RegisterVM { vmid: 2, disks }).is_err());
+
+        let vm_disks = vmm.list_vm_disks();
+        assert_eq!(vm_disks.len(), 1);
+        assert_eq!(vm_disks[&1], vec![disk("/nonexistent/disk.img")]);
+
+        request(&mut vmm, DaemonRequest::UnregisterVM { vmid: 1 }).unwrap();
+        assert_eq!(vmm.vm_state(1), None);
+        assert!(vmm.list_vm_disks().is_empty());
+        assert!(request(&mut vmm, DaemonRequest::UnregisterVM { vmid: 1 }).is_err());
+    }
+
+    #[test]
+    fn shutdown_vm_never_booted() {
+        let mut vmm = registered_vmm(1);
+        request(&mut vmm, DaemonRequest::ShutdownVM { vmid: 1 }).unwrap();
+        assert_eq!(vmm.vm_state(1), Some(VmState::Stopped));
+        let err = request(&mut vmm, DaemonRequest::ShutdownVM { vmid: 1 }).unwrap_err();
+        assert_eq!(err, "cannot shut down VM [1] which is stopped");
+        request(&mut vmm, DaemonRequest::UnregisterVM { vmid: 1 }).unwrap();
+    }
+
+    #[test]
+    fn boot_requests() {
+        let mut vmm = registered_vmm(1);
+
+        // VM is neither prepared nor running if its devices fail to be set up.
+        let err = request(&mut vmm, DaemonRequest::BootVM { vmid: 1 }).unwrap_err();
+        assert!(err.starts_with("failed to set up VM [1]"), "{err}");
+        assert_eq!(vmm.vm_state(1), Some(VmState::Registered));
+
+        let err = request(&mut vmm, DaemonRequest::VMBooted { vmid: 1 }).unwrap_err();
+        assert_eq!(err, "cannot boot VM [1] which is registered");
+        assert!(request(&mut vmm, DaemonRequest::BootVM { vmid: 2 }).is_err());
+
+        // Devices of a prepared VM are released if hypervisor fails to boot it.
+        vmm.transit(1, VmState::Prepared).unwrap();
+        request(&mut vmm, DaemonRequest::ShutdownVM { vmid: 1 }).unwrap();
+        assert_eq!(vmm.vm_state(1), Some(VmState::Stopped));
+
+        vmm.transit(1, VmState::Prepared).unwrap();
+        request(&mut vmm, DaemonRequest::VMBooted { vmid: 1 }).unwrap();
+        assert_eq!(vmm.vm_state(1), Some(VmState::Running));
+        let err = request(&mut vmm, DaemonRequest::BootVM { vmid: 1 }).unwrap_err();
+        assert_eq!(err, "cannot set up VM [1] which is running");
+        let err = request(&mut vmm, DaemonRequest::UnregisterVM { vmid: 1 }).unwrap_err();
+        assert_eq!(err, "cannot remove VM [1] which is running");
+
+        request(&mut vmm, DaemonRequest::ShutdownVM { vmid: 1 }).unwrap();
+        assert_eq!(vmm.vm_state(1), Some(VmState::Stopped));
+    }
+
+    #[test]
+    fn block_requests_need_prepared_vm() {
+        let mut vmm = registered_vmm(1);
+        let req = || BlockRequest::new(0, 0, 0, 1);
+        assert_eq!(vmm.handle_block_request(1, req()), Err(AxError::BadState));
+        assert_eq!(vmm.handle_block_request(2, req()), Err(AxError::NotFound));
+
+        // VM has no emulated block as it is not set up really.
+        vmm.transit(1, VmState::Prepared).unwrap();
+        assert_eq!(
+            vmm.handle_block_request(1, req()),
+            Err(AxError::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn disks_not_open_are_unused() {
+        let mut vmm = registered_vmm(1);
+        let check = || DaemonRequest::CheckDisksUnused {
+            paths: vec![PathBuf::from("/nonexistent/disk.img")],
+        };
+        request(&mut vmm, check()).unwrap();
+
+        // Only images really open for VM count, it has none as it is not set up really.
+        vmm.transit(1, VmState::Prepared).unwrap();
+        request(&mut vmm, check()).unwrap();
+    }
+}
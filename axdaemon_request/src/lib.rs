@@ -1,19 +1,259 @@
-use std::path::PathBuf;
+use std::collections::BTreeMap;
 use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
 
 pub const ARCEOS_DAEMON_PORT_DEFAULT: u16 = 2334;
 
+/// Default Unix socket of axdaemon, TCP is only used when explicitly enabled.
+pub const AXDAEMON_SOCKET_PATH_DEFAULT: &str = "/run/axdaemon.sock";
+
 pub const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 
+/// "AXDM", the first field of `DaemonHello`.
+pub const PROTOCOL_MAGIC: u32 = 0x4d44_5841;
+
+/// Version of the wire format, bump it whenever `DaemonRequest` or `DaemonReply` changes.
+pub const PROTOCOL_VERSION: u32 = 8;
+
+/// Optional features supported by this side of the connection.
+pub const PROTOCOL_FEATURES: &[&str] = &[
+    "vm-list",
+    "vm-lifecycle",
+    "multi-disk",
+    "disk-snapshot",
+    "disk-qcow2",
+    "disk-discard",
+    "disk-ram",
+    "disk-overlay",
+];
+
+/// Max length of disk serial, same as `VIRTIO_BLK_ID_BYTES` in Virtio spec.
+pub const DISK_SERIAL_MAX_LEN: usize = 20;
+
+/// Max size of a single frame, a larger length prefix is treated as corrupted.
+pub const MAX_FRAME_SIZE: usize = 1 << 20;
+
+/// First frame exchanged on every connection, sent by axcli then replied by axdaemon.
+///
+/// Each frame is a bincode message prefixed with its length as an u64 in little endian.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DaemonHello {
+    pub magic: u32,
+    pub version: u32,
+    pub features: Vec<String>,
+}
+
+impl Default for DaemonHello {
+    fn default() -> Self {
+        Self {
+            magic: PROTOCOL_MAGIC,
+            version: PROTOCOL_VERSION,
+            features: PROTOCOL_FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+}
+
+impl DaemonHello {
+    /// Check whether the peer sending this hello speaks the same protocol.
+    pub fn check_compatible(&self) -> Result<(), String> {
+        if self.magic != PROTOCOL_MAGIC {
+            return Err(format!(
+                "bad protocol magic {:#x}, peer is not axcli/axdaemon or is too old",
+                self.magic
+            ));
+        }
+        if self.version != PROTOCOL_VERSION {
+            return Err(format!(
+                "incompatible protocol version {}, expect version {}",
+                self.version, PROTOCOL_VERSION
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// When data written by guest reaches the disk image.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskCacheMode {
+    /// Synced when guest flushes the disk.
+    #[default]
+    Writeback,
+    /// Synced after every write.
+    Writethrough,
+    /// Never synced on guest requests, only when the disk is released.
+    Unsafe,
+}
+
+impl core::fmt::Display for DiskCacheMode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mode = match self {
+            Self::Writeback => "writeback",
+            Self::Writethrough => "writethrough",
+            Self::Unsafe => "unsafe",
+        };
+        f.pad(mode)
+    }
+}
+
+/// Format of disk image.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskFormat {
+    /// Detected by magic of disk image, raw if no magic is found.
+    #[default]
+    Auto,
+    Raw,
+    Qcow2,
+    /// Copy-on-write overlay on top of a base disk image, created by `axcli disk create`.
+    Overlay,
+    /// Scratch disk of `DiskCfg::size` in memory of axdaemon without disk image, its data
+    /// is lost when VM shuts down.
+    Ram,
+}
+
+/// Magic at the beginning of qcow2 images.
+pub const QCOW2_MAGIC: &[u8; 4] = b"QFI\xfb";
+
+/// Magic at the beginning of overlays, see `DiskFormat::Overlay`.
+pub const OVERLAY_MAGIC: &[u8; 8] = b"AXOVRLAY";
+
+impl DiskFormat {
+    /// Format of disk image starting with `header`, raw if it has no known magic.
+    pub fn detect(header: &[u8]) -> Self {
+        if header.starts_with(QCOW2_MAGIC) {
+            Self::Qcow2
+        } else if header.starts_with(OVERLAY_MAGIC) {
+            Self::Overlay
+        } else {
+            Self::Raw
+        }
+    }
+}
+
+impl core::fmt::Display for DiskFormat {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let format = match self {
+            Self::Auto => "auto",
+            Self::Raw => "raw",
+            Self::Qcow2 => "qcow2",
+            Self::Overlay => "overlay",
+            Self::Ram => "ram",
+        };
+        f.pad(format)
+    }
+}
+
+/// An emulated disk of VM, identified by its index in `RegisterVM::disks`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DiskCfg {
+    /// Path of disk image, resolved by axcli, empty for RAM disks.
+    pub path: PathBuf,
+    /// Format of disk image.
+    pub format: DiskFormat,
+    /// Writes from guest fail with I/O error.
+    pub read_only: bool,
+    /// Writes from guest go to a temporary overlay discarded when VM shuts down, the disk
+    /// image itself is opened read-only and may be shared by several VMs.
+    pub snapshot: bool,
+    /// Open disk image with `O_DIRECT`, only for raw disk images.
+    pub direct: bool,
+    /// Returned to guest through `VIRTIO_BLK_T_GET_ID`, at most `DISK_SERIAL_MAX_LEN` Bytes.
+    pub serial: Option<String>,
+    pub cache: DiskCacheMode,
+    /// Punch holes in raw disk image when guest discards sectors, keeping it sparse.
+    pub discard: bool,
+    /// Size of RAM disk in Bytes, disks with image take the size of it.
+    pub size: Option<u64>,
+}
+
+impl core::fmt::Display for DiskCfg {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match (self.format, self.size) {
+            (DiskFormat::Ram, Some(size)) => write!(f, "ram:{size:#x}"),
+            _ => write!(f, "{}", self.path.display()),
+        }
+    }
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub enum DaemonRequest {
-    RegisterVM { vmid: usize, disk_image_path: PathBuf },
-    BootVM { vmid: usize },
+    RegisterVM {
+        vmid: usize,
+        disks: Vec<DiskCfg>,
+    },
+    /// Set up emulated devices of a VM before it is booted by hypervisor.
+    BootVM {
+        vmid: usize,
+    },
+    /// Mark a VM running after it is booted by hypervisor. If booting fails, its emulated
+    /// devices are torn down by `ShutdownVM` instead.
+    VMBooted {
+        vmid: usize,
+    },
+    /// Tear down emulated devices of a VM after it is shut down by hypervisor.
+    ShutdownVM {
+        vmid: usize,
+    },
+    /// Remove a stopped VM from axdaemon after it is destroyed by hypervisor.
+    UnregisterVM {
+        vmid: usize,
+    },
+    ListVM,
+    /// Fail if any of disk images `paths` is open for a prepared or running VM, including
+    /// backing files of its disks. Sent by axcli before it changes an overlay or its base.
+    CheckDisksUnused {
+        paths: Vec<PathBuf>,
+    },
+}
+
+impl DaemonRequest {
+    /// Features in `PROTOCOL_FEATURES` axdaemon must support to handle this request.
+    pub fn required_features(&self) -> Vec<&'static str> {
+        match self {
+            Self::RegisterVM { disks, .. } => {
+                let mut features = Vec::new();
+                if disks.len() > 1 {
+                    features.push("multi-disk");
+                }
+                for disk in disks {
+                    let feature = match disk.format {
+                        DiskFormat::Qcow2 => "disk-qcow2",
+                        DiskFormat::Overlay => "disk-overlay",
+                        DiskFormat::Ram => "disk-ram",
+                        DiskFormat::Auto | DiskFormat::Raw => continue,
+                    };
+                    features.push(feature);
+                }
+                if disks.iter().any(|disk| disk.snapshot) {
+                    features.push("disk-snapshot");
+                }
+                if disks.iter().any(|disk| disk.discard) {
+                    features.push("disk-discard");
+                }
+                features.sort_unstable();
+                features.dedup();
+                features
+            }
+            Self::VMBooted { .. } | Self::ShutdownVM { .. } | Self::UnregisterVM { .. } => {
+                vec!["vm-lifecycle"]
+            }
+            Self::ListVM => vec!["vm-list"],
+            Self::CheckDisksUnused { .. } => vec!["disk-overlay"],
+            Self::BootVM { .. } => Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[must_use]
 pub enum DaemonReply {
     Result(Result<(), String>),
+    /// Disks of VMs registered in axdaemon, indexed by VM id.
+    VMList(BTreeMap<usize, Vec<DiskCfg>>),
     Empty,
-}
\ No newline at end of file
+}
@@ -0,0 +1,669 @@
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use axdaemon_request::{DiskCfg, DiskFormat};
+use axerrno::{ax_err, ax_err_type, AxResult};
+
+use crate::overlay::Overlay;
+use crate::qcow2::Qcow2;
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Max length of backing file chain, guards against loops.
+const MAX_BACKING_DEPTH: usize = 16;
+
+/// Storage of an emulated block, addressed in sectors of `SECTOR_SIZE` Bytes.
+///
+/// `buf` of reads and writes is always a multiple of `SECTOR_SIZE`.
+pub trait BlockBackend: core::fmt::Debug {
+    /// Size of disk seen by guest in Bytes.
+    fn size(&self) -> u64;
+
+    /// Fill `buf` with sectors starting at `sector`.
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> AxResult;
+
+    /// Write sectors starting at `sector` from `buf`.
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> AxResult;
+
+    /// Make sectors written so far durable.
+    fn flush(&mut self) -> AxResult;
+
+    /// Discard `count` sectors starting at `sector`, guest must not rely on their data
+    /// afterwards.
+    fn discard(&mut self, sector: u64, count: u64) -> AxResult;
+
+    /// Disk images read by this backend, its own image first, then its backing files.
+    fn image_paths(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+}
+
+/// Open the disk image of `cfg` with the backend of its format.
+///
+/// Disk image is never written in snapshot mode, guest writes go to a `SnapshotOverlay`.
+pub fn open_block_backend(cfg: &DiskCfg) -> AxResult<Box<dyn BlockBackend>> {
+    if cfg.format == DiskFormat::Ram {
+        let size = cfg
+            .size
+            .ok_or_else(|| ax_err_type!(InvalidInput, "size of RAM disk is not set"))?;
+        return Ok(Box::new(RamDisk::new(size)?));
+    }
+    let read_only = cfg.read_only || cfg.snapshot;
+    let backend = if cfg.discard && !cfg.snapshot {
+        open_sparse_image(cfg, read_only)?
+    } else {
+        open_image(&cfg.path, cfg.format, cfg.direct, read_only, 0)?
+    };
+    if cfg.snapshot {
+        Ok(Box::new(SnapshotOverlay::new(backend)?))
+    } else {
+        Ok(backend)
+    }
+}
+
+/// Open disk image at `path`, `depth` is the position in a backing file chain.
+pub fn open_image(
+    path: &Path,
+    format: DiskFormat,
+    direct: bool,
+    read_only: bool,
+    depth: usize,
+) -> AxResult<Box<dyn BlockBackend>> {
+    if depth > MAX_BACKING_DEPTH {
+        return ax_err!(
+            InvalidData,
+            format!("backing file chain of {path:?} is longer than {MAX_BACKING_DEPTH}")
+        );
+    }
+    let format = match format {
+        DiskFormat::Auto => detect_format(path)?,
+        format => format,
+    };
+    match format {
+        DiskFormat::Qcow2 => {
+            // `direct` is on by default, not worth a warning.
+            if direct {
+                debug!("O_DIRECT is ignored for qcow2 image {:?}", path);
+            }
+            let file = open_file(path, false, read_only)?;
+            Ok(Box::new(Qcow2::open(file, path, read_only, depth)?))
+        }
+        DiskFormat::Overlay => {
+            if direct {
+                debug!("O_DIRECT is ignored for overlay {:?}", path);
+            }
+            Ok(Box::new(Overlay::open(path, read_only, depth)?))
+        }
+        _ => Ok(Box::new(DriveFile::open(path, direct, read_only)?)),
+    }
+}
+
+/// Open raw disk image of `cfg` as `SparseFile`.
+fn open_sparse_image(cfg: &DiskCfg, read_only: bool) -> AxResult<Box<dyn BlockBackend>> {
+    let format = match cfg.format {
+        DiskFormat::Auto => detect_format(&cfg.path)?,
+        format => format,
+    };
+    if format != DiskFormat::Raw {
+        return ax_err!(
+            Unsupported,
+            format!("discard is not supported for {format} image {:?}", cfg.path)
+        );
+    }
+    let file = DriveFile::open(&cfg.path, cfg.direct, read_only)?;
+    Ok(Box::new(SparseFile { file }))
+}
+
+/// Qcow2 or overlay if the image starts with their magic, raw otherwise.
+fn detect_format(path: &Path) -> AxResult<DiskFormat> {
+    // Opened without O_DIRECT, which fails on unaligned reads.
+    let mut file = open_file(path, false, true)?;
+    let mut magic = [0; 8];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(DiskFormat::detect(&magic)),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(DiskFormat::Raw),
+        Err(err) => ax_err!(Io, format!("failed to read {path:?}, {err:?}")),
+    }
+}
+
+/// Represent a single raw drive backend file.
+#[derive(Debug)]
+pub struct DriveFile {
+    /// The opened file.
+    file: File,
+    /// File path.
+    path: PathBuf,
+    /// Whether the file is opened with `O_DIRECT`.
+    direct: bool,
+    size: u64,
+}
+
+impl DriveFile {
+    pub fn open(path: &Path, direct: bool, read_only: bool) -> AxResult<Self> {
+        let file = open_file(path, direct, read_only)?;
+        let size = file_size(&file, path)?;
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+            direct,
+            size,
+        })
+    }
+
+    /// O_DIRECT requires buffer address, file offset and length aligned to block size.
+    fn check_direct_buf(&self, buf: &[u8]) -> AxResult {
+        if self.direct && !(buf.as_ptr() as usize).is_multiple_of(SECTOR_SIZE) {
+            return ax_err!(
+                BadAddress,
+                format!(
+                    "buffer {:#x} is not aligned for O_DIRECT",
+                    buf.as_ptr() as usize
+                )
+            );
+        }
+        Ok(())
+    }
+}
+
+impl BlockBackend for DriveFile {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> AxResult {
+        self.check_direct_buf(buf)?;
+        read_exact_at(&self.file, &self.path, buf, sector * SECTOR_SIZE as u64)
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> AxResult {
+        self.check_direct_buf(buf)?;
+        write_all_at(&self.file, &self.path, buf, sector * SECTOR_SIZE as u64)
+    }
+
+    fn flush(&mut self) -> AxResult {
+        self.file
+            .sync_data()
+            .map_err(|err| ax_err_type!(Io, format!("failed to flush {:?}, {err:?}", self.path)))
+    }
+
+    fn discard(&mut self, _sector: u64, _count: u64) -> AxResult {
+        ax_err!(
+            Unsupported,
+            format!("discard is not enabled for {:?}", self.path)
+        )
+    }
+
+    fn image_paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+}
+
+/// Raw drive file which deallocates discarded sectors, so that it only takes space for
+/// sectors in use.
+#[derive(Debug)]
+struct SparseFile {
+    file: DriveFile,
+}
+
+impl BlockBackend for SparseFile {
+    fn size(&self) -> u64 {
+        self.file.size()
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> AxResult {
+        self.file.read_sectors(sector, buf)
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> AxResult {
+        self.file.write_sectors(sector, buf)
+    }
+
+    fn flush(&mut self) -> AxResult {
+        self.file.flush()
+    }
+
+    fn discard(&mut self, sector: u64, count: u64) -> AxResult {
+        punch_hole(&self.file.file, &self.file.path, sector, count)
+    }
+
+    fn image_paths(&self) -> Vec<PathBuf> {
+        self.file.image_paths()
+    }
+}
+
+/// Disk in memory, for scratch volumes.
+#[derive(Debug)]
+struct RamDisk {
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    /// A zeroed disk of `size` Bytes, which must be a multiple of `SECTOR_SIZE`.
+    fn new(size: u64) -> AxResult<Self> {
+        if size == 0 || !size.is_multiple_of(SECTOR_SIZE as u64) {
+            return ax_err!(
+                InvalidInput,
+                format!("RAM disk size {size:#x} is not a positive multiple of {SECTOR_SIZE}")
+            );
+        }
+        let no_memory = || ax_err_type!(NoMemory, format!("no memory for RAM disk of {size:#x}"));
+        let size = usize::try_from(size).map_err(|_| no_memory())?;
+        let mut data = Vec::new();
+        data.try_reserve_exact(size).map_err(|_| no_memory())?;
+        data.resize(size, 0);
+        Ok(Self { data })
+    }
+
+    fn range(&self, sector: u64, len: usize) -> AxResult<core::ops::Range<usize>> {
+        let start = usize::try_from(sector)
+            .ok()
+            .and_then(|sector| sector.checked_mul(SECTOR_SIZE));
+        match start.and_then(|start| Some(start..start.checked_add(len)?)) {
+            Some(range) if range.end <= self.data.len() => Ok(range),
+            _ => ax_err!(
+                InvalidInput,
+                format!("sectors [{sector}, +{len} Bytes) out of RAM disk")
+            ),
+        }
+    }
+}
+
+impl BlockBackend for RamDisk {
+    fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> AxResult {
+        let range = self.range(sector, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> AxResult {
+        let range = self.range(sector, buf.len())?;
+        self.data[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> AxResult {
+        Ok(())
+    }
+
+    fn discard(&mut self, sector: u64, count: u64) -> AxResult {
+        let len = usize::try_from(count)
+            .ok()
+            .and_then(|count| count.checked_mul(SECTOR_SIZE))
+            .ok_or_else(|| {
+                ax_err_type!(
+                    InvalidInput,
+                    format!("{count} sectors at {sector} out of RAM disk")
+                )
+            })?;
+        let range = self.range(sector, len)?;
+        self.data[range].fill(0);
+        Ok(())
+    }
+}
+
+/// Temporary file holding sectors written by guest, discarded when it is closed.
+///
+/// Sectors never written are read from `base`, which is never written.
+#[derive(Debug)]
+struct SnapshotOverlay {
+    base: Box<dyn BlockBackend>,
+    /// Unnamed file created with `O_TMPFILE`, of the same size as `base`.
+    file: File,
+    path: PathBuf,
+    /// Bitmap of sectors in `file`.
+    dirty: Vec<u64>,
+}
+
+impl SnapshotOverlay {
+    fn new(base: Box<dyn BlockBackend>) -> AxResult<Self> {
+        let dir = std::env::temp_dir();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_TMPFILE)
+            .open(&dir)
+            .map_err(|err| {
+                ax_err_type!(
+                    Io,
+                    format!("failed to create snapshot overlay in {dir:?}, {err:?}")
+                )
+            })?;
+        // Sparse, only sectors written take space.
+        let size = base.size();
+        file.set_len(size).map_err(|err| {
+            ax_err_type!(Io, format!("failed to resize snapshot overlay {err:?}"))
+        })?;
+        let sectors = size.div_ceil(SECTOR_SIZE as u64) as usize;
+        Ok(Self {
+            base,
+            file,
+            path: dir,
+            dirty: vec![0; sectors.div_ceil(64)],
+        })
+    }
+
+    fn is_dirty(&self, sector: u64) -> bool {
+        let sector = sector as usize;
+        self.dirty[sector / 64] & (1 << (sector % 64)) != 0
+    }
+
+    fn mark_dirty(&mut self, sector: u64, count: usize, dirty: bool) {
+        for sector in sector as usize..sector as usize + count {
+            if dirty {
+                self.dirty[sector / 64] |= 1 << (sector % 64);
+            } else {
+                self.dirty[sector / 64] &= !(1 << (sector % 64));
+            }
+        }
+    }
+}
+
+impl BlockBackend for SnapshotOverlay {
+    fn size(&self) -> u64 {
+        self.base.size()
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> AxResult {
+        // Read runs of sectors from the same file.
+        let count = (buf.len() / SECTOR_SIZE) as u64;
+        let mut start = 0;
+        while start < count {
+            let dirty = self.is_dirty(sector + start);
+            let end = (start + 1..count)
+                .find(|&i| self.is_dirty(sector + i) != dirty)
+                .unwrap_or(count);
+            let run = &mut buf[start as usize * SECTOR_SIZE..end as usize * SECTOR_SIZE];
+            if dirty {
+                let offset = (sector + start) * SECTOR_SIZE as u64;
+                read_exact_at(&self.file, &self.path, run, offset)?;
+            } else {
+                self.base.read_sectors(sector + start, run)?;
+            }
+            start = end;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> AxResult {
+        write_all_at(&self.file, &self.path, buf, sector * SECTOR_SIZE as u64)?;
+        self.mark_dirty(sector, buf.len() / SECTOR_SIZE, true);
+        Ok(())
+    }
+
+    /// Nothing written by guest is kept in snapshot mode.
+    fn flush(&mut self) -> AxResult {
+        Ok(())
+    }
+
+    /// Discarded sectors read from `base` again.
+    fn discard(&mut self, sector: u64, count: u64) -> AxResult {
+        punch_hole(&self.file, &self.path, sector, count)?;
+        self.mark_dirty(sector, count as usize, false);
+        Ok(())
+    }
+
+    fn image_paths(&self) -> Vec<PathBuf> {
+        self.base.image_paths()
+    }
+}
+
+/// Fill `buf` with data of `backend` starting at `offset`, zero beyond the end of it.
+pub fn read_padded(backend: &mut dyn BlockBackend, offset: u64, buf: &mut [u8]) -> AxResult {
+    let size = backend.size().next_multiple_of(SECTOR_SIZE as u64);
+    let len = size.saturating_sub(offset).min(buf.len() as u64) as usize;
+    let (data, zero) = buf.split_at_mut(len);
+    if !data.is_empty() {
+        backend.read_sectors(offset / SECTOR_SIZE as u64, data)?;
+    }
+    zero.fill(0);
+    Ok(())
+}
+
+/// Split `buf` at multiples of `unit`, the first piece starts at `offset`.
+pub fn split_at_boundaries(buf: &[u8], offset: u64, unit: u64) -> Vec<&[u8]> {
+    let mut pieces = Vec::new();
+    let mut rest = buf;
+    let mut offset = offset;
+    while !rest.is_empty() {
+        let len = (unit - (offset & (unit - 1))).min(rest.len() as u64);
+        let (piece, tail) = rest.split_at(len as usize);
+        pieces.push(piece);
+        rest = tail;
+        offset += len;
+    }
+    pieces
+}
+
+/// Fill `buf` with data of `file` starting at `offset`, `path` is for error messages.
+///
+/// The last sector may be partially backed by the file, the part beyond the end of file
+/// is filled with zero.
+pub fn read_exact_at(file: &File, path: &Path, buf: &mut [u8], offset: u64) -> AxResult {
+    let mut done = 0;
+    while done < buf.len() {
+        match file.read_at(&mut buf[done..], offset + done as u64) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                return ax_err!(
+                    Io,
+                    format!("failed to read {:?} at {offset:#x}, {err:?}", path)
+                )
+            }
+        }
+    }
+
+    if done < buf.len() {
+        if offset + (done as u64) < file_size(file, path)? || buf.len() - done >= SECTOR_SIZE {
+            return ax_err!(
+                UnexpectedEof,
+                format!(
+                    "short read on {:?} at {offset:#x}, {done} of {} Bytes",
+                    path,
+                    buf.len()
+                )
+            );
+        }
+        buf[done..].fill(0);
+    }
+    Ok(())
+}
+
+/// Write the whole `buf` to `file` starting at `offset`, `path` is for error messages.
+pub fn write_all_at(file: &File, path: &Path, buf: &[u8], offset: u64) -> AxResult {
+    let mut done = 0;
+    while done < buf.len() {
+        match file.write_at(&buf[done..], offset + done as u64) {
+            Ok(0) => {
+                return ax_err!(
+                    WriteZero,
+                    format!(
+                        "short write on {:?} at {offset:#x}, {done} of {} Bytes",
+                        path,
+                        buf.len()
+                    )
+                )
+            }
+            Ok(n) => done += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                return ax_err!(
+                    Io,
+                    format!("failed to write {:?} at {offset:#x}, {err:?}", path)
+                )
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Deallocate `count` sectors of `file` starting at `sector`, which read as zero afterwards.
+pub fn punch_hole(file: &File, path: &Path, sector: u64, count: u64) -> AxResult {
+    let offset = (sector * SECTOR_SIZE as u64) as libc::off_t;
+    let len = (count * SECTOR_SIZE as u64) as libc::off_t;
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    // SAFETY: `file` is an open file descriptor.
+    if unsafe { libc::fallocate(file.as_raw_fd(), mode, offset, len) } != 0 {
+        return ax_err!(
+            Io,
+            format!(
+                "failed to punch hole in {:?} at {offset:#x}, {}",
+                path,
+                std::io::Error::last_os_error()
+            )
+        );
+    }
+    Ok(())
+}
+
+pub fn file_size(file: &File, path: &Path) -> AxResult<u64> {
+    Ok(file
+        .metadata()
+        .map_err(|err| ax_err_type!(Io, format!("failed to get metadata of {path:?}, {err:?}")))?
+        .len())
+}
+
+pub fn open_file(path: &Path, direct: bool, read_only: bool) -> AxResult<File> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(!read_only);
+    if direct {
+        options.custom_flags(libc::O_DIRECT);
+    }
+    let file = options.open(path).map_err(|err| {
+        ax_err_type!(
+            InvalidInput,
+            format!(
+                "failed to open the file for block {:?}. Error:{:?}\nos err :{}",
+                path,
+                err,
+                std::io::Error::last_os_error(),
+            )
+        )
+    })?;
+
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        assert_same, disk_cfg, fixture, oracle_of, pattern, ram_disk_cfg, round_trip, TempDir,
+    };
+    use axerrno::AxError;
+
+    const SIZE: usize = 4 << 20;
+
+    #[test]
+    fn ram_disk() {
+        let mut disk = open_block_backend(&ram_disk_cfg(SIZE as u64)).unwrap();
+        assert_eq!(disk.size(), SIZE as u64);
+        let mut buf = vec![0xff; 2 * SECTOR_SIZE];
+        disk.read_sectors(8, &mut buf).unwrap();
+        assert_eq!(buf, [0; 2 * SECTOR_SIZE]);
+
+        disk.write_sectors(8, &pattern(1, 2 * SECTOR_SIZE)).unwrap();
+        disk.read_sectors(8, &mut buf).unwrap();
+        assert_eq!(buf, pattern(1, 2 * SECTOR_SIZE));
+        disk.discard(9, 1).unwrap();
+        disk.read_sectors(8, &mut buf).unwrap();
+        assert_eq!(buf[..SECTOR_SIZE], pattern(1, SECTOR_SIZE));
+        assert_eq!(buf[SECTOR_SIZE..], [0; SECTOR_SIZE]);
+
+        let last = (SIZE / SECTOR_SIZE) as u64 - 1;
+        assert_eq!(
+            disk.read_sectors(last, &mut buf),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(disk.write_sectors(last, &buf), Err(AxError::InvalidInput));
+        assert_eq!(disk.discard(last, 2), Err(AxError::InvalidInput));
+        // Offsets in Bytes overflow.
+        assert_eq!(
+            disk.read_sectors(u64::MAX, &mut buf),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(
+            disk.write_sectors(u64::MAX, &buf),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(disk.discard(u64::MAX, 1), Err(AxError::InvalidInput));
+        assert_eq!(disk.discard(0, u64::MAX), Err(AxError::InvalidInput));
+    }
+
+    #[test]
+    fn ram_disk_size() {
+        let open = |size| {
+            open_block_backend(&DiskCfg {
+                size,
+                ..ram_disk_cfg(0)
+            })
+        };
+        assert_eq!(open(None).unwrap_err(), AxError::InvalidInput);
+        assert_eq!(open(Some(0)).unwrap_err(), AxError::InvalidInput);
+        assert_eq!(open(Some(1000)).unwrap_err(), AxError::InvalidInput);
+        assert_eq!(open(Some(1 << 62)).unwrap_err(), AxError::NoMemory);
+    }
+
+    #[test]
+    fn raw_and_sparse_round_trip() {
+        let dir = TempDir::new();
+        let path = dir.file("disk.img", &pattern(3, SIZE));
+        for discard in [false, true] {
+            let cfg = DiskCfg {
+                format: DiskFormat::Raw,
+                discard,
+                ..disk_cfg(&path)
+            };
+            let mut disk = open_block_backend(&cfg).unwrap();
+            let mut oracle = oracle_of(disk.as_mut());
+            round_trip(disk.as_mut(), oracle.as_mut());
+            let discarded = disk.discard(64, 16);
+            if discard {
+                discarded.unwrap();
+                oracle.discard(64, 16).unwrap();
+            } else {
+                assert_eq!(discarded, Err(AxError::Unsupported));
+            }
+            assert_same(disk.as_mut(), oracle.as_mut());
+        }
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let dir = TempDir::new();
+        let raw = dir.file("disk.img", &pattern(3, SIZE));
+        let qcow2 = fixture(&dir, "data.qcow2");
+        for (path, format) in [(raw, DiskFormat::Raw), (qcow2, DiskFormat::Qcow2)] {
+            let image = std::fs::read(&path).unwrap();
+            let cfg = DiskCfg {
+                format,
+                snapshot: true,
+                ..disk_cfg(&path)
+            };
+            let mut disk = open_block_backend(&cfg).unwrap();
+            let mut base = oracle_of(disk.as_mut());
+            let mut oracle = oracle_of(disk.as_mut());
+            round_trip(disk.as_mut(), oracle.as_mut());
+
+            // Discarded sectors read from the image again.
+            disk.discard(100, 32).unwrap();
+            let mut buf = vec![0; 32 * SECTOR_SIZE];
+            base.read_sectors(100, &mut buf).unwrap();
+            oracle.write_sectors(100, &buf).unwrap();
+            assert_same(disk.as_mut(), oracle.as_mut());
+            drop(disk);
+            assert!(
+                std::fs::read(&path).unwrap() == image,
+                "{path:?} is written"
+            );
+        }
+    }
+}
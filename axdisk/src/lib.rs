@@ -0,0 +1,13 @@
+//! Disk images of guest VMs: raw, qcow2 and copy-on-write overlays.
+//!
+//! Guest I/O goes through `block::BlockBackend` in axdaemon, overlays are created and
+//! updated by axcli with permissions of its user, see `overlay`.
+
+#[macro_use]
+extern crate log;
+
+pub mod block;
+pub mod overlay;
+pub mod qcow2;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
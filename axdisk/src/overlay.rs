@@ -0,0 +1,711 @@
+//! Copy-on-write overlays, so that many VMs boot from one base image with their own deltas.
+//!
+//! Chunks never written by guest are read from the base image, which is only written by
+//! `commit`. Overlays are created, committed and rebased by axcli with permissions of its
+//! user, axdaemon only opens them for guests.
+//!
+//! Layout of an overlay file, integers are little-endian:
+//! * Two header slots at `0` and `HEADER_SLOT_SIZE`, the valid one with the larger
+//!   generation is current. Header is updated by writing the other slot, a crash in the
+//!   middle leaves the current one intact.
+//! * Bitmap at `BITMAP_OFFSET`, a bit is set if the chunk is in overlay. It is written
+//!   only after the chunks it marks are synced.
+//! * Chunks at `data_offset` plus their offset in the disk.
+
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use axdaemon_request::{DiskFormat, OVERLAY_MAGIC};
+use axerrno::{ax_err, ax_err_type, AxResult};
+
+use crate::block::{self, BlockBackend, SECTOR_SIZE};
+
+const OVERLAY_VERSION: u32 = 1;
+
+const HEADER_SLOT_SIZE: usize = 4096;
+const BITMAP_OFFSET: u64 = 2 * HEADER_SLOT_SIZE as u64;
+/// Chunks of 64 KiB, the unit copied from base on first write.
+const CHUNK_BITS: u32 = 16;
+/// Same limit as of qcow2 L1 table, so that a crafted header cannot exhaust memory. It is
+/// a disk of 16 TiB with chunks of 64 KiB.
+const MAX_BITMAP_SIZE: u64 = 32 << 20;
+
+/// Offsets of fields in a header slot, see `OverlayHeader::encode`.
+const BASE_FORMAT_OFFSET: usize = 44;
+const BASE_PATH_OFFSET: usize = 48;
+const CHECKSUM_OFFSET: usize = HEADER_SLOT_SIZE - 8;
+const BASE_PATH_MAX_LEN: usize = CHECKSUM_OFFSET - BASE_PATH_OFFSET;
+
+#[derive(Debug, Clone)]
+struct OverlayHeader {
+    /// Increased by every header update.
+    generation: u64,
+    chunk_bits: u32,
+    /// Disk size in Bytes, size of base image when overlay is created.
+    size: u64,
+    data_offset: u64,
+    /// Path of base image, relative to the directory of overlay if not absolute.
+    base: PathBuf,
+    /// Never probed, so that guest data in a raw base is not taken for a header.
+    base_format: DiskFormat,
+}
+
+impl OverlayHeader {
+    fn encode(&self) -> AxResult<Vec<u8>> {
+        let base = self.base.as_os_str().as_bytes();
+        if base.len() > BASE_PATH_MAX_LEN {
+            return ax_err!(
+                InvalidInput,
+                format!(
+                    "path of base image {:?} exceeds {BASE_PATH_MAX_LEN} Bytes",
+                    self.base
+                )
+            );
+        }
+        let mut slot = vec![0; HEADER_SLOT_SIZE];
+        slot[0..8].copy_from_slice(OVERLAY_MAGIC);
+        slot[8..12].copy_from_slice(&OVERLAY_VERSION.to_le_bytes());
+        slot[12..16].copy_from_slice(&self.chunk_bits.to_le_bytes());
+        slot[16..24].copy_from_slice(&self.generation.to_le_bytes());
+        slot[24..32].copy_from_slice(&self.size.to_le_bytes());
+        slot[32..40].copy_from_slice(&self.data_offset.to_le_bytes());
+        slot[40..44].copy_from_slice(&(base.len() as u32).to_le_bytes());
+        slot[BASE_FORMAT_OFFSET..BASE_FORMAT_OFFSET + 4]
+            .copy_from_slice(&encode_format(self.base_format)?.to_le_bytes());
+        slot[BASE_PATH_OFFSET..BASE_PATH_OFFSET + base.len()].copy_from_slice(base);
+        let checksum = checksum(&slot[..CHECKSUM_OFFSET]);
+        slot[CHECKSUM_OFFSET..].copy_from_slice(&checksum.to_le_bytes());
+        Ok(slot)
+    }
+
+    /// `None` if the slot is empty or torn by a crash.
+    fn decode(slot: &[u8], path: &Path) -> AxResult<Option<Self>> {
+        if &slot[0..8] != OVERLAY_MAGIC
+            || checksum(&slot[..CHECKSUM_OFFSET]) != le_u64(slot, CHECKSUM_OFFSET)
+        {
+            return Ok(None);
+        }
+        let version = le_u32(slot, 8);
+        if version != OVERLAY_VERSION {
+            return ax_err!(
+                Unsupported,
+                format!("overlay {path:?} has unsupported version {version}")
+            );
+        }
+        let chunk_bits = le_u32(slot, 12);
+        let base_len = le_u32(slot, 40) as usize;
+        if !(9..=24).contains(&chunk_bits) || base_len > BASE_PATH_MAX_LEN {
+            return ax_err!(InvalidData, format!("overlay {path:?} has invalid header"));
+        }
+        let (size, data_offset) = (le_u64(slot, 24), le_u64(slot, 32));
+        let bitmap_size = bitmap_size(size, chunk_bits);
+        if bitmap_size > MAX_BITMAP_SIZE
+            || data_offset % (1 << chunk_bits) != 0
+            || data_offset < BITMAP_OFFSET + bitmap_size
+            || data_offset.checked_add(size).is_none()
+        {
+            return ax_err!(
+                InvalidData,
+                format!(
+                    "overlay {path:?} has invalid size {size:#x} or data offset {data_offset:#x}"
+                )
+            );
+        }
+        let base = &slot[BASE_PATH_OFFSET..BASE_PATH_OFFSET + base_len];
+        let base_format = decode_format(le_u32(slot, BASE_FORMAT_OFFSET)).ok_or_else(|| {
+            ax_err_type!(
+                InvalidData,
+                format!("overlay {path:?} has base image of unknown format")
+            )
+        })?;
+        Ok(Some(Self {
+            generation: le_u64(slot, 16),
+            chunk_bits,
+            size,
+            data_offset,
+            base: PathBuf::from(OsStr::from_bytes(base)),
+            base_format,
+        }))
+    }
+}
+
+/// Overlay file without its base image, enough for metadata updates.
+#[derive(Debug)]
+struct OverlayFile {
+    file: File,
+    path: PathBuf,
+    header: OverlayHeader,
+    /// Slot of current header.
+    slot: usize,
+    bitmap: Vec<u64>,
+    /// Bitmap in memory has bits not written to file yet.
+    bitmap_dirty: bool,
+}
+
+impl OverlayFile {
+    fn open(path: &Path, read_only: bool) -> AxResult<Self> {
+        let file = block::open_file(path, false, read_only)?;
+        let mut slots = vec![0; 2 * HEADER_SLOT_SIZE];
+        block::read_exact_at(&file, path, &mut slots, 0)?;
+        let (slot, header) = slots
+            .chunks_exact(HEADER_SLOT_SIZE)
+            .enumerate()
+            .filter_map(|(i, slot)| {
+                OverlayHeader::decode(slot, path)
+                    .map(|header| header.map(|header| (i, header)))
+                    .transpose()
+            })
+            .collect::<AxResult<Vec<_>>>()?
+            .into_iter()
+            .max_by_key(|(_, header)| header.generation)
+            .ok_or_else(|| {
+                ax_err_type!(InvalidData, format!("overlay {path:?} has no valid header"))
+            })?;
+
+        let mut raw = vec![0; bitmap_size(header.size, header.chunk_bits) as usize];
+        block::read_exact_at(&file, path, &mut raw, BITMAP_OFFSET)?;
+        let bitmap = raw
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+            header,
+            slot,
+            bitmap,
+            bitmap_dirty: false,
+        })
+    }
+
+    fn chunk_size(&self) -> u64 {
+        1 << self.header.chunk_bits
+    }
+
+    fn chunks(&self) -> u64 {
+        self.header.size.div_ceil(self.chunk_size())
+    }
+
+    fn is_allocated(&self, chunk: u64) -> bool {
+        self.bitmap[(chunk / 64) as usize] & (1 << (chunk % 64)) != 0
+    }
+
+    fn set_allocated(&mut self, chunk: u64) {
+        self.bitmap[(chunk / 64) as usize] |= 1 << (chunk % 64);
+        self.bitmap_dirty = true;
+    }
+
+    /// Length of chunk within the disk, the last chunk may be shorter.
+    fn chunk_len(&self, chunk: u64) -> usize {
+        let end = self.header.size.next_multiple_of(SECTOR_SIZE as u64);
+        (end - chunk * self.chunk_size()).min(self.chunk_size()) as usize
+    }
+
+    fn base_path(&self) -> PathBuf {
+        resolve_base(&self.path, &self.header.base)
+    }
+
+    /// Offset in file of chunk data at `offset` in the disk.
+    fn data_pos(&self, offset: u64) -> AxResult<u64> {
+        self.header.data_offset.checked_add(offset).ok_or_else(|| {
+            ax_err_type!(
+                InvalidInput,
+                format!("offset {offset:#x} is beyond overlay {:?}", self.path)
+            )
+        })
+    }
+
+    fn read_data(&self, offset: u64, buf: &mut [u8]) -> AxResult {
+        block::read_exact_at(&self.file, &self.path, buf, self.data_pos(offset)?)
+    }
+
+    fn write_data(&self, offset: u64, buf: &[u8]) -> AxResult {
+        block::write_all_at(&self.file, &self.path, buf, self.data_pos(offset)?)
+    }
+
+    fn sync(&self) -> AxResult {
+        self.file
+            .sync_data()
+            .map_err(|err| ax_err_type!(Io, format!("failed to flush {:?}, {err:?}", self.path)))
+    }
+
+    /// Sync chunks, then the bitmap marking them.
+    fn flush(&mut self) -> AxResult {
+        self.sync()?;
+        if !self.bitmap_dirty {
+            return Ok(());
+        }
+        let raw: Vec<u8> = self
+            .bitmap
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        block::write_all_at(&self.file, &self.path, &raw, BITMAP_OFFSET)?;
+        self.sync()?;
+        self.bitmap_dirty = false;
+        Ok(())
+    }
+
+    /// Write `header` to the other slot, it becomes current once synced.
+    fn write_header(&mut self, mut header: OverlayHeader) -> AxResult {
+        header.generation = self.header.generation + 1;
+        let slot = 1 - self.slot;
+        let raw = header.encode()?;
+        block::write_all_at(
+            &self.file,
+            &self.path,
+            &raw,
+            (slot * HEADER_SLOT_SIZE) as u64,
+        )?;
+        self.sync()?;
+        self.header = header;
+        self.slot = slot;
+        Ok(())
+    }
+}
+
+/// Overlay with its base image as a block backend.
+#[derive(Debug)]
+pub struct Overlay {
+    overlay: OverlayFile,
+    base: Box<dyn BlockBackend>,
+    read_only: bool,
+}
+
+impl Overlay {
+    /// Open overlay and its base image, `depth` is the position in a backing file chain.
+    pub fn open(path: &Path, read_only: bool, depth: usize) -> AxResult<Self> {
+        let overlay = OverlayFile::open(path, read_only)?;
+        let base = block::open_image(
+            &overlay.base_path(),
+            overlay.header.base_format,
+            false,
+            true,
+            depth + 1,
+        )?;
+        Ok(Self {
+            overlay,
+            base,
+            read_only,
+        })
+    }
+}
+
+impl BlockBackend for Overlay {
+    fn size(&self) -> u64 {
+        self.overlay.header.size
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> AxResult {
+        let chunk_size = self.overlay.chunk_size();
+        let mut offset = sector * SECTOR_SIZE as u64;
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let len = (chunk_size - (offset & (chunk_size - 1))).min(buf.len() as u64);
+            let (piece, rest) = buf.split_at_mut(len as usize);
+            if self.overlay.is_allocated(offset / chunk_size) {
+                self.overlay.read_data(offset, piece)?;
+            } else {
+                block::read_padded(self.base.as_mut(), offset, piece)?;
+            }
+            offset += len;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> AxResult {
+        if self.read_only {
+            return ax_err!(
+                PermissionDenied,
+                format!("write to read-only overlay {:?}", self.overlay.path)
+            );
+        }
+        let chunk_size = self.overlay.chunk_size();
+        let mut offset = sector * SECTOR_SIZE as u64;
+        for piece in block::split_at_boundaries(buf, offset, chunk_size) {
+            let chunk = offset / chunk_size;
+            if self.overlay.is_allocated(chunk) {
+                self.overlay.write_data(offset, piece)?;
+            } else {
+                // Copy the rest of chunk from base on first write.
+                let start = chunk * chunk_size;
+                let mut data = vec![0; self.overlay.chunk_len(chunk)];
+                if piece.len() != data.len() {
+                    block::read_padded(self.base.as_mut(), start, &mut data)?;
+                }
+                let in_chunk = (offset - start) as usize;
+                data[in_chunk..in_chunk + piece.len()].copy_from_slice(piece);
+                self.overlay.write_data(start, &data)?;
+                self.overlay.set_allocated(chunk);
+            }
+            offset += piece.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> AxResult {
+        self.overlay.flush()
+    }
+
+    fn discard(&mut self, _sector: u64, _count: u64) -> AxResult {
+        ax_err!(
+            Unsupported,
+            format!(
+                "discard is not supported for overlay {:?}",
+                self.overlay.path
+            )
+        )
+    }
+
+    fn image_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.overlay.path.clone()];
+        paths.extend(self.base.image_paths());
+        paths
+    }
+}
+
+/// Create an empty overlay at `path` on top of `base` of `base_format`, `path` must not
+/// exist.
+pub fn create(path: &Path, base: &Path, base_format: DiskFormat) -> AxResult {
+    encode_format(base_format)?;
+    let size = block::open_image(&resolve_base(path, base), base_format, false, true, 1)?.size();
+    let chunk_size = 1u64 << CHUNK_BITS;
+    let bitmap_size = bitmap_size(size, CHUNK_BITS);
+    if bitmap_size > MAX_BITMAP_SIZE {
+        return ax_err!(
+            InvalidInput,
+            format!("base image {base:?} of {size:#x} Bytes is too large for an overlay")
+        );
+    }
+    let header = OverlayHeader {
+        generation: 1,
+        chunk_bits: CHUNK_BITS,
+        size,
+        data_offset: (BITMAP_OFFSET + bitmap_size.next_multiple_of(4096))
+            .next_multiple_of(chunk_size),
+        base: base.to_path_buf(),
+        base_format,
+    };
+    let raw = header.encode()?;
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|err| ax_err_type!(Io, format!("failed to create overlay {path:?}, {err:?}")))?;
+    let result = (|| {
+        // Bitmap is a hole of zero.
+        file.set_len(header.data_offset)
+            .map_err(|err| ax_err_type!(Io, format!("failed to resize {path:?}, {err:?}")))?;
+        block::write_all_at(&file, path, &raw, 0)?;
+        file.sync_all()
+            .map_err(|err| ax_err_type!(Io, format!("failed to sync {path:?}, {err:?}")))?;
+        sync_parent_dir(path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+    result
+}
+
+/// Base image of overlay at `path`.
+pub fn base_of(path: &Path) -> AxResult<PathBuf> {
+    OverlayFile::open(path, true).map(|overlay| overlay.base_path())
+}
+
+/// Merge chunks in overlay at `path` into its base image, then empty the overlay.
+///
+/// Chunks are synced to base before the overlay is emptied, so a crash in the middle
+/// leaves the same data in both, and `commit` could be run again.
+///
+/// Return the number of chunks merged.
+pub fn commit(path: &Path) -> AxResult<u64> {
+    let mut overlay = OverlayFile::open(path, false)?;
+    let base_path = overlay.base_path();
+    let mut base = block::open_image(&base_path, overlay.header.base_format, false, false, 1)?;
+    if base.size() < overlay.header.size {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "base image {base_path:?} of {:#x} Bytes is smaller than overlay {path:?}",
+                base.size()
+            )
+        );
+    }
+
+    let mut merged = 0;
+    let mut data = vec![0; overlay.chunk_size() as usize];
+    for chunk in 0..overlay.chunks() {
+        if !overlay.is_allocated(chunk) {
+            continue;
+        }
+        let data = &mut data[..overlay.chunk_len(chunk)];
+        let offset = chunk * overlay.chunk_size();
+        overlay.read_data(offset, data)?;
+        base.write_sectors(offset / SECTOR_SIZE as u64, data)?;
+        merged += 1;
+    }
+    base.flush()?;
+
+    overlay.bitmap.fill(0);
+    overlay.bitmap_dirty = true;
+    overlay.flush()?;
+
+    // Chunks are not referenced any more, free their space.
+    let data_offset = overlay.header.data_offset;
+    let file_size = block::file_size(&overlay.file, path)?;
+    if file_size > data_offset {
+        let sectors = (file_size - data_offset).div_ceil(SECTOR_SIZE as u64);
+        if let Err(err) = block::punch_hole(
+            &overlay.file,
+            path,
+            data_offset / SECTOR_SIZE as u64,
+            sectors,
+        ) {
+            warn!("failed to free space of committed overlay {path:?}, {err:?}");
+        }
+    }
+    Ok(merged)
+}
+
+/// Switch base image of overlay at `path` to `new_base` of `new_format`.
+///
+/// If `safe`, chunks not in overlay but differing between old and new base are copied
+/// into overlay from old base first, then the header is switched. A crash before that
+/// leaves the overlay on old base with the same data.
+///
+/// Return the number of chunks copied.
+pub fn rebase(path: &Path, new_base: &Path, new_format: DiskFormat, safe: bool) -> AxResult<u64> {
+    encode_format(new_format)?;
+    let mut overlay = OverlayFile::open(path, false)?;
+    let mut new = block::open_image(&resolve_base(path, new_base), new_format, false, true, 1)?;
+
+    let mut copied = 0;
+    if safe {
+        let mut old = block::open_image(
+            &overlay.base_path(),
+            overlay.header.base_format,
+            false,
+            true,
+            1,
+        )?;
+        let mut old_data = vec![0; overlay.chunk_size() as usize];
+        let mut new_data = vec![0; overlay.chunk_size() as usize];
+        for chunk in 0..overlay.chunks() {
+            if overlay.is_allocated(chunk) {
+                continue;
+            }
+            let len = overlay.chunk_len(chunk);
+            let offset = chunk * overlay.chunk_size();
+            block::read_padded(old.as_mut(), offset, &mut old_data[..len])?;
+            block::read_padded(new.as_mut(), offset, &mut new_data[..len])?;
+            if old_data[..len] != new_data[..len] {
+                overlay.write_data(offset, &old_data[..len])?;
+                overlay.set_allocated(chunk);
+                copied += 1;
+            }
+        }
+        overlay.flush()?;
+    }
+
+    let mut header = overlay.header.clone();
+    header.base = new_base.to_path_buf();
+    header.base_format = new_format;
+    overlay.write_header(header)?;
+    Ok(copied)
+}
+
+/// Size in Bytes of the bitmap of a disk of `size` Bytes.
+fn bitmap_size(size: u64, chunk_bits: u32) -> u64 {
+    size.div_ceil(1 << chunk_bits).div_ceil(64) * 8
+}
+
+/// Format of base image as stored in header.
+fn encode_format(format: DiskFormat) -> AxResult<u32> {
+    match format {
+        DiskFormat::Raw => Ok(1),
+        DiskFormat::Qcow2 => Ok(2),
+        DiskFormat::Overlay => Ok(3),
+        _ => ax_err!(
+            InvalidInput,
+            format!("base image of overlay cannot be {format}")
+        ),
+    }
+}
+
+fn decode_format(format: u32) -> Option<DiskFormat> {
+    match format {
+        1 => Some(DiskFormat::Raw),
+        2 => Some(DiskFormat::Qcow2),
+        3 => Some(DiskFormat::Overlay),
+        _ => None,
+    }
+}
+
+fn resolve_base(path: &Path, base: &Path) -> PathBuf {
+    path.parent().unwrap_or(Path::new(".")).join(base)
+}
+
+/// Make a newly created file in the directory durable.
+fn sync_parent_dir(path: &Path) -> AxResult {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|err| ax_err_type!(Io, format!("failed to sync directory {dir:?}, {err:?}")))
+}
+
+/// FNV-1a, detects header slots torn by a crash.
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        assert_same, disk_cfg, fixture, oracle_of, pattern, round_trip, TempDir,
+    };
+    use axdaemon_request::DiskCfg;
+
+    const SIZE: usize = 4 << 20;
+
+    fn open(path: &Path) -> Box<dyn BlockBackend> {
+        let cfg = DiskCfg {
+            format: DiskFormat::Overlay,
+            ..disk_cfg(path)
+        };
+        block::open_block_backend(&cfg).unwrap()
+    }
+
+    fn open_raw(path: &Path) -> Box<dyn BlockBackend> {
+        let cfg = DiskCfg {
+            format: DiskFormat::Raw,
+            ..disk_cfg(path)
+        };
+        block::open_block_backend(&cfg).unwrap()
+    }
+
+    #[test]
+    fn round_trip_against_ram_disk() {
+        let dir = TempDir::new();
+        dir.file("base.img", &pattern(5, SIZE));
+        fixture(&dir, "data.qcow2");
+        for (base, format) in [
+            ("base.img", DiskFormat::Raw),
+            ("data.qcow2", DiskFormat::Qcow2),
+        ] {
+            let path = dir.path().join(format!("{base}.overlay"));
+            create(&path, Path::new(base), format).unwrap();
+            let base_image = std::fs::read(dir.path().join(base)).unwrap();
+
+            let mut disk = open(&path);
+            let mut oracle = oracle_of(disk.as_mut());
+            round_trip(disk.as_mut(), oracle.as_mut());
+            drop(disk);
+            assert_same(open(&path).as_mut(), oracle.as_mut());
+            assert!(std::fs::read(dir.path().join(base)).unwrap() == base_image);
+        }
+    }
+
+    #[test]
+    fn base_format_is_not_probed() {
+        let dir = TempDir::new();
+        // Raw base whose guest data looks like a qcow2 image.
+        let qcow2 = fixture(&dir, "data.qcow2");
+        let path = dir.path().join("disk.overlay");
+        create(&path, &qcow2, DiskFormat::Raw).unwrap();
+        let mut disk = open(&path);
+        assert_eq!(disk.size(), std::fs::metadata(&qcow2).unwrap().len());
+        assert_same(disk.as_mut(), open_raw(&qcow2).as_mut());
+        assert_eq!(disk.image_paths(), [path.clone(), qcow2.clone()]);
+
+        assert_eq!(
+            create(&dir.path().join("auto.overlay"), &qcow2, DiskFormat::Auto),
+            Err(axerrno::AxError::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn commit_into_base() {
+        let dir = TempDir::new();
+        let base = dir.file("base.img", &pattern(5, SIZE));
+        let path = dir.path().join("disk.overlay");
+        create(&path, Path::new("base.img"), DiskFormat::Raw).unwrap();
+        let mut disk = open(&path);
+        let mut oracle = oracle_of(disk.as_mut());
+        round_trip(disk.as_mut(), oracle.as_mut());
+        drop(disk);
+
+        assert!(commit(&path).unwrap() > 0);
+        assert_same(open_raw(&base).as_mut(), oracle.as_mut());
+        assert_same(open(&path).as_mut(), oracle.as_mut());
+        // Nothing is left to merge.
+        assert_eq!(commit(&path).unwrap(), 0);
+    }
+
+    #[test]
+    fn rejects_corrupted_header() {
+        let dir = TempDir::new();
+        dir.file("base.img", &pattern(5, SIZE));
+        let path = dir.path().join("disk.overlay");
+        let corruptions: [fn(&mut OverlayHeader); 3] = [
+            // Bitmap of 32 TiB.
+            |header| header.size = u64::MAX,
+            // Chunks over the bitmap, or not aligned.
+            |header| header.data_offset = BITMAP_OFFSET,
+            |header| header.data_offset += SECTOR_SIZE as u64,
+        ];
+        for (i, corrupt) in corruptions.into_iter().enumerate() {
+            let _ = std::fs::remove_file(&path);
+            create(&path, Path::new("base.img"), DiskFormat::Raw).unwrap();
+            let mut overlay = OverlayFile::open(&path, false).unwrap();
+            let mut header = overlay.header.clone();
+            corrupt(&mut header);
+            overlay.write_header(header).unwrap();
+            drop(overlay);
+            let err = OverlayFile::open(&path, true).unwrap_err();
+            assert_eq!(err, axerrno::AxError::InvalidData, "corruption {i}");
+        }
+    }
+
+    #[test]
+    fn rebase_keeps_guest_data() {
+        let dir = TempDir::new();
+        dir.file("old.img", &pattern(5, SIZE));
+        let new = dir.file("new.img", &pattern(6, SIZE));
+        let path = dir.path().join("disk.overlay");
+        create(&path, Path::new("old.img"), DiskFormat::Raw).unwrap();
+        let mut disk = open(&path);
+        let mut oracle = oracle_of(disk.as_mut());
+        round_trip(disk.as_mut(), oracle.as_mut());
+        drop(disk);
+
+        rebase(&path, Path::new("new.img"), DiskFormat::Raw, true).unwrap();
+        assert_eq!(base_of(&path).unwrap(), new);
+        assert_same(open(&path).as_mut(), oracle.as_mut());
+
+        // Unsafe rebase only switches the base, chunks never written read from it.
+        let path = dir.path().join("unsafe.overlay");
+        create(&path, Path::new("old.img"), DiskFormat::Raw).unwrap();
+        rebase(&path, Path::new("new.img"), DiskFormat::Raw, false).unwrap();
+        assert_same(open(&path).as_mut(), open_raw(&new).as_mut());
+    }
+}
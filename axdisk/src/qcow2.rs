@@ -0,0 +1,801 @@
+//! Qcow2 disk images, see `docs/interop/qcow2.txt` in QEMU.
+//!
+//! Only images without encryption, compression or incompatible features are supported,
+//! internal snapshots are allowed if the image is opened read-only.
+//!
+//! A new cluster is referenced only after its refcount and content are synced, so a crash
+//! leaks clusters at worst. Backing file is raw unless the backing format header extension
+//! tells otherwise, its format is never probed.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use axdaemon_request::{DiskFormat, QCOW2_MAGIC};
+use axerrno::{ax_err, ax_err_type, AxResult};
+
+use crate::block::{self, BlockBackend, SECTOR_SIZE};
+
+/// Size of version 2 header, version 3 header is at least 104 Bytes.
+const HEADER_V2_SIZE: usize = 72;
+const HEADER_V3_SIZE: usize = 104;
+
+/// Bits 9-55 of L1, L2 and refcount table entries.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// Refcount of the cluster is exactly one, it may be written in place.
+const OFLAG_COPIED: u64 = 1 << 63;
+const OFLAG_COMPRESSED: u64 = 1 << 62;
+/// Cluster reads as zero, version 3 only.
+const OFLAG_ZERO: u64 = 1;
+
+/// Only 16-bit refcounts, the default of `qemu-img`, are supported for writes.
+const REFCOUNT_ORDER: u32 = 4;
+
+/// Offset of `autoclear_features` in header.
+const AUTOCLEAR_FEATURES_OFFSET: u64 = 88;
+
+/// Same limits as QEMU, so that a crafted header cannot exhaust memory.
+const MAX_L1_SIZE: usize = (32 << 20) / 8;
+const MAX_REFCOUNT_TABLE_SIZE: usize = 8 << 20;
+const MAX_BACKING_FILE_NAME_LEN: usize = 1023;
+
+const HEADER_EXT_END: u32 = 0;
+const HEADER_EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+/// Where a guest cluster is stored.
+enum Cluster {
+    /// At this offset of image.
+    Data(u64),
+    /// Reads as zero, `Some` if a cluster is still allocated at this offset.
+    Zero(Option<u64>),
+    /// Not allocated, reads from backing file or as zero.
+    Unallocated,
+}
+
+#[derive(Debug)]
+pub struct Qcow2 {
+    file: File,
+    path: PathBuf,
+    read_only: bool,
+    version: u32,
+    /// Virtual disk size in Bytes.
+    size: u64,
+    cluster_bits: u32,
+    l1_table_offset: u64,
+    /// L1 table cached in memory, L2 tables are read on demand.
+    l1_table: Vec<u64>,
+    refcount_table_offset: u64,
+    /// Only loaded if writable.
+    refcount_table: Vec<u64>,
+    /// New clusters are appended at the end of image.
+    next_cluster: u64,
+    backing: Option<Box<dyn BlockBackend>>,
+}
+
+impl Qcow2 {
+    /// Open qcow2 image, `depth` is the position of the image in a backing file chain.
+    pub fn open(file: File, path: &Path, read_only: bool, depth: usize) -> AxResult<Self> {
+        let invalid =
+            |msg: String| ax_err_type!(InvalidData, format!("qcow2 image {path:?} {msg}"));
+
+        let file_size = block::file_size(&file, path)?;
+        let mut header = [0; HEADER_V3_SIZE];
+        block::read_exact_at(&file, path, &mut header[..HEADER_V2_SIZE], 0)?;
+        if &header[..4] != QCOW2_MAGIC {
+            return Err(invalid("has no qcow2 magic".into()));
+        }
+        let version = be_u32(&header, 4);
+        match version {
+            2 => {}
+            3 => block::read_exact_at(&file, path, &mut header[HEADER_V2_SIZE..], 72)?,
+            _ => return Err(invalid(format!("has unsupported version {version}"))),
+        }
+
+        let backing_file_offset = be_u64(&header, 8);
+        let backing_file_size = be_u32(&header, 16) as usize;
+        let cluster_bits = be_u32(&header, 20);
+        let size = be_u64(&header, 24);
+        let crypt_method = be_u32(&header, 32);
+        let l1_size = be_u32(&header, 36) as usize;
+        let l1_table_offset = be_u64(&header, 40);
+        let refcount_table_offset = be_u64(&header, 48);
+        let refcount_table_clusters = be_u32(&header, 56) as usize;
+        let nb_snapshots = be_u32(&header, 60);
+        let (incompatible_features, autoclear_features, refcount_order, header_len) =
+            if version == 3 {
+                (
+                    be_u64(&header, 72),
+                    be_u64(&header, 88),
+                    be_u32(&header, 96),
+                    be_u32(&header, 100) as usize,
+                )
+            } else {
+                (0, 0, REFCOUNT_ORDER, HEADER_V2_SIZE)
+            };
+
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(invalid(format!("has invalid cluster_bits {cluster_bits}")));
+        }
+        if header_len < HEADER_V3_SIZE && version == 3 {
+            return Err(invalid(format!(
+                "has header of {header_len} Bytes too short"
+            )));
+        }
+        if crypt_method != 0 {
+            return Err(invalid("is encrypted".into()));
+        }
+        if incompatible_features != 0 {
+            return Err(invalid(format!(
+                "has unsupported incompatible features {incompatible_features:#x}"
+            )));
+        }
+        if !read_only && refcount_order != REFCOUNT_ORDER {
+            return Err(invalid(format!(
+                "has {}-bit refcounts, only 16-bit ones are writable",
+                1 << refcount_order
+            )));
+        }
+        if !read_only && nb_snapshots != 0 {
+            return Err(invalid(format!(
+                "has {nb_snapshots} internal snapshots, open it read-only or in snapshot mode"
+            )));
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+        let l1_coverage = cluster_size * (cluster_size / 8);
+        if (l1_size as u64) < size.div_ceil(l1_coverage) {
+            return Err(invalid(format!(
+                "has L1 table of {l1_size} entries too small"
+            )));
+        }
+        if l1_size > MAX_L1_SIZE {
+            return Err(invalid(format!(
+                "has L1 table of {l1_size} entries, more than {MAX_L1_SIZE}"
+            )));
+        }
+        let refcount_table_size = refcount_table_clusters
+            .checked_mul(cluster_size as usize)
+            .filter(|&len| len <= MAX_REFCOUNT_TABLE_SIZE)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "has refcount table of {refcount_table_clusters} clusters, \
+                    more than {MAX_REFCOUNT_TABLE_SIZE:#x} Bytes"
+                ))
+            })?;
+        let within_file = |offset: u64, len: usize| {
+            offset
+                .checked_add(len as u64)
+                .is_some_and(|end| end <= file_size)
+        };
+        if !within_file(l1_table_offset, l1_size * 8) {
+            return Err(invalid("has L1 table beyond the end of file".into()));
+        }
+        if !within_file(refcount_table_offset, refcount_table_size) {
+            return Err(invalid("has refcount table beyond the end of file".into()));
+        }
+        if backing_file_size > MAX_BACKING_FILE_NAME_LEN
+            || backing_file_offset != 0 && !within_file(backing_file_offset, backing_file_size)
+        {
+            return Err(invalid(format!(
+                "has invalid backing file name of {backing_file_size} Bytes"
+            )));
+        }
+
+        // Same as QEMU, backing file name written by older tools may follow the header
+        // directly, where extensions would be.
+        let extensions_end = match backing_file_offset {
+            0 => cluster_size,
+            offset => offset.min(cluster_size),
+        };
+        let backing_format =
+            read_backing_format(&file, path, header_len, extensions_end.min(file_size))?;
+        let l1_table = read_table(&file, path, l1_table_offset, l1_size)?;
+        let refcount_table = if read_only {
+            Vec::new()
+        } else {
+            read_table(&file, path, refcount_table_offset, refcount_table_size / 8)?
+        };
+
+        let mut image = Self {
+            next_cluster: file_size.next_multiple_of(cluster_size),
+            file,
+            path: path.to_path_buf(),
+            read_only,
+            version,
+            size,
+            cluster_bits,
+            l1_table_offset,
+            l1_table,
+            refcount_table_offset,
+            refcount_table,
+            backing: None,
+        };
+
+        if backing_file_offset != 0 {
+            let mut name = vec![0; backing_file_size];
+            block::read_exact_at(&image.file, path, &mut name, backing_file_offset)?;
+            let name = String::from_utf8(name)
+                .map_err(|_| invalid("has backing file name not in UTF-8".into()))?;
+            // Relative to the directory of this image, same as QEMU.
+            let backing_path = path.parent().unwrap_or(Path::new(".")).join(name);
+            let backing = block::open_image(
+                &backing_path,
+                backing_format.unwrap_or(DiskFormat::Raw),
+                false,
+                true,
+                depth + 1,
+            )?;
+            image.backing = Some(backing);
+        }
+
+        // Features we do not know about, e.g. persistent bitmaps, become stale once written.
+        if !read_only && autoclear_features != 0 {
+            image.write_u64(AUTOCLEAR_FEATURES_OFFSET, 0)?;
+        }
+
+        Ok(image)
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Position of guest `offset` in L1 and L2 table.
+    fn table_index(&self, offset: u64) -> (usize, u64) {
+        let l2_bits = self.cluster_bits - 3;
+        let l1_index = (offset >> (self.cluster_bits + l2_bits)) as usize;
+        let l2_index = (offset >> self.cluster_bits) & ((1 << l2_bits) - 1);
+        (l1_index, l2_index)
+    }
+
+    /// Offset of L2 table entry of guest `offset`, `None` if L2 table is not allocated.
+    fn l2_entry_offset(&self, offset: u64) -> Option<u64> {
+        let (l1_index, l2_index) = self.table_index(offset);
+        let l2_table = self.l1_table.get(l1_index).copied().unwrap_or(0) & OFFSET_MASK;
+        (l2_table != 0).then_some(l2_table + l2_index * 8)
+    }
+
+    fn lookup(&self, offset: u64) -> AxResult<Cluster> {
+        let Some(entry_offset) = self.l2_entry_offset(offset) else {
+            return Ok(Cluster::Unallocated);
+        };
+        let entry = self.read_u64(entry_offset)?;
+        if entry & OFLAG_COMPRESSED != 0 {
+            return ax_err!(
+                Unsupported,
+                format!(
+                    "compressed cluster at {offset:#x} of qcow2 image {:?}",
+                    self.path
+                )
+            );
+        }
+        let host = entry & OFFSET_MASK;
+        if self.version >= 3 && entry & OFLAG_ZERO != 0 {
+            return Ok(Cluster::Zero((host != 0).then_some(host)));
+        }
+        if host == 0 {
+            return Ok(Cluster::Unallocated);
+        }
+        if !self.read_only && entry & OFLAG_COPIED == 0 {
+            return ax_err!(
+                Unsupported,
+                format!(
+                    "shared cluster at {offset:#x} of qcow2 image {:?}",
+                    self.path
+                )
+            );
+        }
+        Ok(Cluster::Data(host))
+    }
+
+    /// Read guest data at `offset` from backing file, zero beyond the end of it.
+    fn read_backing(&mut self, offset: u64, buf: &mut [u8]) -> AxResult {
+        match &mut self.backing {
+            Some(backing) => block::read_padded(backing.as_mut(), offset, buf),
+            None => {
+                buf.fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    /// Allocate L2 table of guest `offset` if missing, return offset of its L2 entry.
+    fn alloc_l2_entry(&mut self, offset: u64) -> AxResult<u64> {
+        if let Some(entry_offset) = self.l2_entry_offset(offset) {
+            return Ok(entry_offset);
+        }
+        let (l1_index, _) = self.table_index(offset);
+        if l1_index >= self.l1_table.len() {
+            return ax_err!(
+                InvalidInput,
+                format!("offset {offset:#x} beyond L1 table of {:?}", self.path)
+            );
+        }
+        let l2_table = self.alloc_cluster()?;
+        let zero = vec![0; self.cluster_size() as usize];
+        block::write_all_at(&self.file, &self.path, &zero, l2_table)?;
+        self.barrier()?;
+        let l1_entry = l2_table | OFLAG_COPIED;
+        self.write_u64(self.l1_table_offset + l1_index as u64 * 8, l1_entry)?;
+        self.l1_table[l1_index] = l1_entry;
+        Ok(self.l2_entry_offset(offset).unwrap())
+    }
+
+    /// Allocate a cluster at the end of image with refcount one.
+    ///
+    /// Callers sync the image by `barrier` before the cluster is referenced, so a crash in
+    /// between leaks the cluster instead of corrupting the image.
+    fn alloc_cluster(&mut self) -> AxResult<u64> {
+        let cluster = self.next_cluster;
+        self.next_cluster += self.cluster_size();
+        self.set_refcount(cluster, 1)?;
+        Ok(cluster)
+    }
+
+    fn set_refcount(&mut self, cluster: u64, refcount: u16) -> AxResult {
+        let cluster_size = self.cluster_size();
+        let entries_per_block = cluster_size / 2;
+        let cluster_index = cluster >> self.cluster_bits;
+        let table_index = (cluster_index / entries_per_block) as usize;
+        let block_index = cluster_index % entries_per_block;
+
+        if table_index >= self.refcount_table.len() {
+            return ax_err!(
+                StorageFull,
+                format!(
+                    "refcount table of qcow2 image {:?} is full, resize it with qemu-img",
+                    self.path
+                )
+            );
+        }
+
+        let mut refcount_block = self.refcount_table[table_index] & OFFSET_MASK;
+        if refcount_block == 0 {
+            refcount_block = self.next_cluster;
+            self.next_cluster += cluster_size;
+            let block_cluster_index = refcount_block >> self.cluster_bits;
+            // The new refcount block may cover itself.
+            let covers_itself = block_cluster_index / entries_per_block == table_index as u64;
+            let mut data = vec![0; cluster_size as usize];
+            if covers_itself {
+                let index = (block_cluster_index % entries_per_block) as usize * 2;
+                data[index..index + 2].copy_from_slice(&1u16.to_be_bytes());
+            }
+            block::write_all_at(&self.file, &self.path, &data, refcount_block)?;
+            if !covers_itself {
+                self.set_refcount(refcount_block, 1)?;
+            }
+            self.barrier()?;
+            let table_entry = self.refcount_table_offset + table_index as u64 * 8;
+            self.write_u64(table_entry, refcount_block)?;
+            self.refcount_table[table_index] = refcount_block;
+        }
+
+        block::write_all_at(
+            &self.file,
+            &self.path,
+            &refcount.to_be_bytes(),
+            refcount_block + block_index * 2,
+        )
+    }
+
+    /// Write guest data within a single cluster.
+    fn write_cluster(&mut self, offset: u64, buf: &[u8]) -> AxResult {
+        let cluster_size = self.cluster_size();
+        let in_cluster = offset & (cluster_size - 1);
+
+        let (host, fill_from_backing) = match self.lookup(offset)? {
+            Cluster::Data(host) => {
+                return block::write_all_at(&self.file, &self.path, buf, host + in_cluster)
+            }
+            Cluster::Zero(host) => (host, false),
+            Cluster::Unallocated => (None, true),
+        };
+
+        // The rest of cluster keeps what guest read from it before.
+        let cluster_start = offset - in_cluster;
+        let mut data = vec![0; cluster_size as usize];
+        if fill_from_backing && buf.len() as u64 != cluster_size {
+            self.read_backing(cluster_start, &mut data)?;
+        }
+        data[in_cluster as usize..in_cluster as usize + buf.len()].copy_from_slice(buf);
+
+        // Data first, then the L2 entry referencing it.
+        let entry_offset = self.alloc_l2_entry(offset)?;
+        let host = match host {
+            Some(host) => host,
+            None => self.alloc_cluster()?,
+        };
+        block::write_all_at(&self.file, &self.path, &data, host)?;
+        self.barrier()?;
+        self.write_u64(entry_offset, host | OFLAG_COPIED)
+    }
+
+    /// Make writes so far durable before metadata referencing them is written.
+    fn barrier(&self) -> AxResult {
+        self.file
+            .sync_data()
+            .map_err(|err| ax_err_type!(Io, format!("failed to flush {:?}, {err:?}", self.path)))
+    }
+
+    fn read_u64(&self, offset: u64) -> AxResult<u64> {
+        let mut buf = [0; 8];
+        block::read_exact_at(&self.file, &self.path, &mut buf, offset)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn write_u64(&self, offset: u64, value: u64) -> AxResult {
+        block::write_all_at(&self.file, &self.path, &value.to_be_bytes(), offset)
+    }
+}
+
+impl BlockBackend for Qcow2 {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> AxResult {
+        let cluster_size = self.cluster_size();
+        let mut offset = sector * SECTOR_SIZE as u64;
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let in_cluster = offset & (cluster_size - 1);
+            let len = (cluster_size - in_cluster).min(buf.len() as u64) as usize;
+            let (chunk, rest) = buf.split_at_mut(len);
+            match self.lookup(offset)? {
+                Cluster::Data(host) => {
+                    block::read_exact_at(&self.file, &self.path, chunk, host + in_cluster)?
+                }
+                Cluster::Zero(_) => chunk.fill(0),
+                Cluster::Unallocated => self.read_backing(offset, chunk)?,
+            }
+            offset += len as u64;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> AxResult {
+        if self.read_only {
+            return ax_err!(
+                PermissionDenied,
+                format!("write to read-only qcow2 image {:?}", self.path)
+            );
+        }
+        let cluster_size = self.cluster_size();
+        let mut offset = sector * SECTOR_SIZE as u64;
+        for chunk in block::split_at_boundaries(buf, offset, cluster_size) {
+            self.write_cluster(offset, chunk)?;
+            offset += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> AxResult {
+        self.barrier()
+    }
+
+    fn discard(&mut self, _sector: u64, _count: u64) -> AxResult {
+        ax_err!(
+            Unsupported,
+            format!("discard is not supported for qcow2 image {:?}", self.path)
+        )
+    }
+
+    fn image_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.path.clone()];
+        if let Some(backing) = &self.backing {
+            paths.extend(backing.image_paths());
+        }
+        paths
+    }
+}
+
+/// Read a table of big-endian 64-bit entries.
+fn read_table(file: &File, path: &Path, offset: u64, entries: usize) -> AxResult<Vec<u64>> {
+    let mut table = Vec::new();
+    table.try_reserve_exact(entries).map_err(|_| {
+        ax_err_type!(
+            NoMemory,
+            format!("no memory for table of {entries} entries in {path:?}")
+        )
+    })?;
+    // Read in pieces, so that the table is not held twice in memory.
+    let mut buf = vec![0; entries.min(4096) * 8];
+    let mut offset = offset;
+    while table.len() < entries {
+        let len = (entries - table.len()).min(4096) * 8;
+        block::read_exact_at(file, path, &mut buf[..len], offset)?;
+        table.extend(
+            buf[..len]
+                .chunks_exact(8)
+                .map(|entry| u64::from_be_bytes(entry.try_into().unwrap())),
+        );
+        offset += len as u64;
+    }
+    Ok(table)
+}
+
+/// Backing file format from header extensions, which follow the header of `header_len`
+/// Bytes and end by an end extension or at `end`.
+fn read_backing_format(
+    file: &File,
+    path: &Path,
+    header_len: usize,
+    end: u64,
+) -> AxResult<Option<DiskFormat>> {
+    let invalid = || {
+        ax_err_type!(
+            InvalidData,
+            format!("qcow2 image {path:?} has invalid header extensions")
+        )
+    };
+    let mut buf = vec![0; end as usize];
+    block::read_exact_at(file, path, &mut buf, 0)?;
+    let mut offset = header_len;
+    let mut format = None;
+    while offset < buf.len() {
+        let ext = buf.get(offset..offset + 8).ok_or_else(invalid)?;
+        let (ext_type, len) = (be_u32(ext, 0), be_u32(ext, 4) as usize);
+        if ext_type == HEADER_EXT_END {
+            return Ok(format);
+        }
+        let data = buf.get(offset + 8..offset + 8 + len).ok_or_else(invalid)?;
+        if ext_type == HEADER_EXT_BACKING_FORMAT {
+            format = Some(match data {
+                b"raw" => DiskFormat::Raw,
+                b"qcow2" => DiskFormat::Qcow2,
+                _ => {
+                    return ax_err!(
+                        Unsupported,
+                        format!(
+                            "qcow2 image {path:?} has backing file of unsupported format {:?}",
+                            String::from_utf8_lossy(data)
+                        )
+                    )
+                }
+            });
+        }
+        offset += 8 + len.next_multiple_of(8);
+    }
+    Ok(format)
+}
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        assert_same, disk_cfg, fixture, oracle_of, pattern, round_trip, TempDir,
+    };
+    use axdaemon_request::DiskCfg;
+
+    /// Default cluster size of `qemu-img`, used by the fixtures, see `testdata/README.md`.
+    const CLUSTER: usize = 64 << 10;
+    const SIZE: usize = 4 << 20;
+
+    fn open(path: &Path, format: DiskFormat) -> AxResult<Box<dyn BlockBackend>> {
+        block::open_block_backend(&DiskCfg {
+            format,
+            ..disk_cfg(path)
+        })
+    }
+
+    /// `len` Bytes of `disk` at `offset`.
+    fn read(disk: &mut dyn BlockBackend, offset: usize, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        disk.read_sectors((offset / SECTOR_SIZE) as u64, &mut buf)
+            .unwrap();
+        buf
+    }
+
+    fn read_file(path: &Path, offset: usize, len: usize) -> Vec<u8> {
+        std::fs::read(path).unwrap()[offset..][..len].to_vec()
+    }
+
+    /// Write through `path` with a RAM disk as oracle, then check it again after reopening.
+    fn check_round_trip(path: &Path) {
+        let mut disk = open(path, DiskFormat::Auto).unwrap();
+        let mut oracle = oracle_of(disk.as_mut());
+        round_trip(disk.as_mut(), oracle.as_mut());
+        drop(disk);
+        let mut disk = open(path, DiskFormat::Qcow2).unwrap();
+        assert_same(disk.as_mut(), oracle.as_mut());
+        check_refcounts(path);
+    }
+
+    /// Check that each cluster in use has refcount one and others have refcount zero.
+    fn check_refcounts(path: &Path) {
+        let image = std::fs::read(path).unwrap();
+        let be_u64_at = |offset: u64| be_u64(&image, offset as usize);
+        let cluster_size = 1u64 << be_u32(&image, 20);
+        let l1_size = be_u32(&image, 36) as u64;
+        let (l1_offset, refcount_table_offset) = (be_u64_at(40), be_u64_at(48));
+        let refcount_table_clusters = be_u32(&image, 56) as u64;
+
+        let mut used = vec![0u16; image.len().div_ceil(cluster_size as usize)];
+        let mut use_range = |offset: u64, len: u64| {
+            for cluster in offset / cluster_size..(offset + len).div_ceil(cluster_size) {
+                used[cluster as usize] += 1;
+            }
+        };
+        use_range(0, 1);
+        use_range(l1_offset, l1_size * 8);
+        use_range(
+            refcount_table_offset,
+            refcount_table_clusters * cluster_size,
+        );
+        let mut refcount_blocks = Vec::new();
+        for i in 0..refcount_table_clusters * cluster_size / 8 {
+            let block = be_u64_at(refcount_table_offset + i * 8) & OFFSET_MASK;
+            if block != 0 {
+                use_range(block, cluster_size);
+            }
+            refcount_blocks.push(block);
+        }
+        for i in 0..l1_size {
+            let l2_table = be_u64_at(l1_offset + i * 8) & OFFSET_MASK;
+            if l2_table == 0 {
+                continue;
+            }
+            use_range(l2_table, cluster_size);
+            for j in 0..cluster_size / 8 {
+                let host = be_u64_at(l2_table + j * 8) & OFFSET_MASK;
+                if host != 0 {
+                    use_range(host, cluster_size);
+                }
+            }
+        }
+
+        let block_entries = cluster_size / 2;
+        for (cluster, &expected) in used.iter().enumerate() {
+            let cluster = cluster as u64;
+            let block = refcount_blocks[(cluster / block_entries) as usize];
+            let refcount = if block == 0 {
+                0
+            } else {
+                let offset = (block + cluster % block_entries * 2) as usize;
+                u16::from_be_bytes([image[offset], image[offset + 1]])
+            };
+            assert_eq!(refcount, expected, "refcount of cluster {cluster}");
+        }
+    }
+
+    #[test]
+    fn reads_existing_clusters() {
+        let dir = TempDir::new();
+        let path = fixture(&dir, "data.qcow2");
+        check_refcounts(&path);
+        let mut disk = open(&path, DiskFormat::Qcow2).unwrap();
+        assert_eq!(disk.size(), SIZE as u64);
+        // Cluster 1 has zero flag, cluster 3 too with a preallocated host cluster.
+        let mut expected = vec![0; SIZE];
+        expected[..CLUSTER].fill(0x11);
+        expected[132 << 10..136 << 10].fill(0x12);
+        expected[3 << 20..(3 << 20) + CLUSTER].fill(0x22);
+        assert!(read(disk.as_mut(), 0, SIZE) == expected);
+    }
+
+    #[test]
+    fn round_trip_against_ram_disk() {
+        let dir = TempDir::new();
+        for name in ["empty.qcow2", "data.qcow2", "v2.qcow2"] {
+            check_round_trip(&fixture(&dir, name));
+        }
+    }
+
+    #[test]
+    fn backing_file_of_declared_format() {
+        let dir = TempDir::new();
+        let base = dir.file("base.img", &pattern(7, SIZE));
+        let path = fixture(&dir, "backing-raw.qcow2");
+        let mut disk = open(&path, DiskFormat::Qcow2).unwrap();
+        let mut oracle = oracle_of(disk.as_mut());
+        assert_eq!(read(oracle.as_mut(), CLUSTER, CLUSTER), [0x44; CLUSTER]);
+        assert_eq!(
+            read(oracle.as_mut(), 2 * CLUSTER, CLUSTER),
+            read_file(&base, 2 * CLUSTER, CLUSTER)
+        );
+        round_trip(disk.as_mut(), oracle.as_mut());
+        drop(disk);
+        assert_eq!(std::fs::read(&base).unwrap(), pattern(7, SIZE));
+        let mut disk = open(&path, DiskFormat::Qcow2).unwrap();
+        assert_same(disk.as_mut(), oracle.as_mut());
+        check_refcounts(&path);
+
+        // Written partially over a qcow2 backing file, the rest of cluster is copied from it.
+        fixture(&dir, "data.qcow2");
+        let path = fixture(&dir, "backing-qcow2.qcow2");
+        check_refcounts(&path);
+        let mut disk = open(&path, DiskFormat::Qcow2).unwrap();
+        let mut expected = [0x11; CLUSTER];
+        expected[8 << 10..12 << 10].fill(0x55);
+        assert_eq!(read(disk.as_mut(), 0, CLUSTER), expected);
+        assert_eq!(read(disk.as_mut(), 3 << 20, CLUSTER), [0x22; CLUSTER]);
+        check_round_trip(&path);
+    }
+
+    #[test]
+    fn backing_file_defaults_to_raw() {
+        let dir = TempDir::new();
+        let backing = fixture(&dir, "data.qcow2");
+        let path = fixture(&dir, "backing-qcow2.qcow2");
+        // Unknown extensions are skipped, so the image has no backing format.
+        let mut image = std::fs::read(&path).unwrap();
+        image[112..116].copy_from_slice(&0x1234_5678u32.to_be_bytes());
+        std::fs::write(&path, image).unwrap();
+        let mut disk = open(&path, DiskFormat::Qcow2).unwrap();
+        // Qcow2 metadata of the backing file is guest data.
+        assert_eq!(
+            read(disk.as_mut(), CLUSTER, CLUSTER),
+            read_file(&backing, CLUSTER, CLUSTER)
+        );
+    }
+
+    #[test]
+    fn v2_backing_file_name_after_header() {
+        let dir = TempDir::new();
+        let base = dir.file("base.img", &pattern(7, SIZE));
+        let path = fixture(&dir, "v2-backing.qcow2");
+        let mut disk = open(&path, DiskFormat::Qcow2).unwrap();
+        assert_same(
+            disk.as_mut(),
+            open(&base, DiskFormat::Raw).unwrap().as_mut(),
+        );
+        drop(disk);
+
+        // Older tools write the name right after the header of 72 Bytes, no extension.
+        let mut image = std::fs::read(&path).unwrap();
+        image[8..20].copy_from_slice(&[0, 0, 0, 0, 0, 0, 0, 72, 0, 0, 0, 8]);
+        image[72..80].copy_from_slice(b"base.img");
+        std::fs::write(&path, image).unwrap();
+        let mut disk = open(&path, DiskFormat::Qcow2).unwrap();
+        assert_same(
+            disk.as_mut(),
+            open(&base, DiskFormat::Raw).unwrap().as_mut(),
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_backing_format() {
+        let dir = TempDir::new();
+        fixture(&dir, "data.qcow2");
+        let path = fixture(&dir, "backing-qcow2.qcow2");
+        let mut image = std::fs::read(&path).unwrap();
+        // Data of the backing format extension follows the header of 112 Bytes.
+        image[120..125].copy_from_slice(b"vmdk2");
+        std::fs::write(&path, image).unwrap();
+        let err = open(&path, DiskFormat::Qcow2).unwrap_err();
+        assert_eq!(err, axerrno::AxError::Unsupported);
+    }
+
+    #[test]
+    fn rejects_oversized_tables() {
+        let dir = TempDir::new();
+        let path = fixture(&dir, "empty.qcow2");
+        let original = std::fs::read(&path).unwrap();
+        let patches: [(usize, &[u8]); 5] = [
+            // L1 size beyond the limit and beyond the end of file.
+            (36, &u32::MAX.to_be_bytes()),
+            (36, &1024u32.to_be_bytes()),
+            // Refcount table clusters beyond the limit and beyond the end of file.
+            (56, &u32::MAX.to_be_bytes()),
+            (56, &16u32.to_be_bytes()),
+            // Backing file name longer than 1023 Bytes.
+            (8, &[0, 0, 0, 0, 0, 0, 0, 200, 0, 0, 4, 0]),
+        ];
+        for (offset, patch) in patches {
+            let mut image = original.clone();
+            image[offset..offset + patch.len()].copy_from_slice(patch);
+            std::fs::write(&path, image).unwrap();
+            let err = open(&path, DiskFormat::Qcow2).unwrap_err();
+            assert_eq!(err, axerrno::AxError::InvalidData, "patch at {offset}");
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Helpers shared by unit tests, also by those of axdaemon and axcli with feature `test-utils`.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use axdaemon_request::{DiskCacheMode, DiskCfg, DiskFormat};
+
+use crate::block::{self, BlockBackend, SECTOR_SIZE};
+
+/// Directory under `std::env::temp_dir()` removed with its content when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "axdisk-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Create file `name` in the directory with `data`, return its path.
+    pub fn file(&self, name: &str, data: &[u8]) -> PathBuf {
+        let path = self.0.join(name);
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+}
+
+impl Default for TempDir {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// `len` Bytes of a pattern derived from `seed`, so that different writes are told apart.
+pub fn pattern(seed: u8, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed) ^ (i >> 9) as u8)
+        .collect()
+}
+
+/// Copy of fixture `name` in `testdata`, which tests may write.
+pub fn fixture(dir: &TempDir, name: &str) -> PathBuf {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata")
+        .join(name);
+    dir.file(name, &std::fs::read(path).unwrap())
+}
+
+/// Writable disk at `path` of probed format, other fields are set with struct update syntax.
+pub fn disk_cfg(path: &Path) -> DiskCfg {
+    DiskCfg {
+        path: path.to_path_buf(),
+        format: DiskFormat::Auto,
+        read_only: false,
+        snapshot: false,
+        // tmpfs does not support O_DIRECT.
+        direct: false,
+        serial: None,
+        cache: DiskCacheMode::Writeback,
+        discard: false,
+        size: None,
+    }
+}
+
+/// RAM disk of `size` Bytes.
+pub fn ram_disk_cfg(size: u64) -> DiskCfg {
+    DiskCfg {
+        format: DiskFormat::Ram,
+        size: Some(size),
+        ..disk_cfg(Path::new(""))
+    }
+}
+
+/// RAM disk with the content of `disk`, to check it against.
+pub fn oracle_of(disk: &mut dyn BlockBackend) -> Box<dyn BlockBackend> {
+    let mut oracle = block::open_block_backend(&ram_disk_cfg(disk.size())).unwrap();
+    let mut buf = vec![0; 64 << 10];
+    for offset in (0..disk.size()).step_by(buf.len()) {
+        let len = buf.len().min((disk.size() - offset) as usize);
+        let sector = offset / SECTOR_SIZE as u64;
+        disk.read_sectors(sector, &mut buf[..len]).unwrap();
+        oracle.write_sectors(sector, &buf[..len]).unwrap();
+    }
+    oracle
+}
+
+/// Write the same data to `disk` and `oracle`, crossing cluster boundaries of qcow2 images
+/// with 64 KiB clusters, then check that they read the same.
+///
+/// Both are at least 4 MiB.
+pub fn round_trip(disk: &mut dyn BlockBackend, oracle: &mut dyn BlockBackend) {
+    let last_sector = disk.size() / SECTOR_SIZE as u64 - 1;
+    // (sector, sectors), written with distinct patterns, later writes overlap earlier ones.
+    let writes = [
+        (0, 1),
+        (7, 2),
+        (3, 16),
+        (128 - 16, 32),
+        (100, 600),
+        (8, 8),
+        (last_sector, 1),
+        (2 * 128 - 1, 2),
+    ];
+    for (seed, (sector, sectors)) in writes.into_iter().enumerate() {
+        let data = pattern(seed as u8 + 1, sectors * SECTOR_SIZE);
+        disk.write_sectors(sector, &data).unwrap();
+        oracle.write_sectors(sector, &data).unwrap();
+    }
+    disk.flush().unwrap();
+    assert_same(disk, oracle);
+}
+
+/// Check that `disk` and `oracle` read the same, with reads of various alignments.
+pub fn assert_same(disk: &mut dyn BlockBackend, oracle: &mut dyn BlockBackend) {
+    assert_eq!(disk.size(), oracle.size());
+    let sectors = disk.size() / SECTOR_SIZE as u64;
+    for read_sectors in [1, 3, 128] {
+        let mut expected = vec![0; read_sectors * SECTOR_SIZE];
+        let mut actual = vec![0; read_sectors * SECTOR_SIZE];
+        for sector in (0..sectors).step_by(read_sectors) {
+            let len = (read_sectors as u64).min(sectors - sector) as usize * SECTOR_SIZE;
+            oracle.read_sectors(sector, &mut expected[..len]).unwrap();
+            disk.read_sectors(sector, &mut actual[..len]).unwrap();
+            assert!(
+                expected[..len] == actual[..len],
+                "{read_sectors} sectors at {sector} differ"
+            );
+        }
+    }
+}